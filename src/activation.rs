@@ -0,0 +1,541 @@
+//! Determines when Night Kitchen timers will next activate
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use dbus::blocking::Connection;
+use slog::{debug, warn, Logger};
+
+use crate::calendar::CalendarSpec;
+use crate::config::WakePolicy;
+use crate::dbus::systemd_timer::OrgFreedesktopSystemd1Timer;
+use crate::dbus::{systemd_unit_at, systemd_unit_path};
+use crate::power_monitor::Deadline;
+use crate::time::{boottime_to_realtime, from_timestamp_usecs, monotonic_to_realtime};
+
+/// The timers that Night Kitchen wakes the system up for
+pub const TIMER_UNITS: &[&str] = &["night-kitchen-daily.timer", "night-kitchen-weekly.timer"];
+
+/// How far apart, in seconds, systemd's reported elapsation point and the one computed from `OnCalendar=` may be
+/// before a warning is logged
+const CALENDAR_TOLERANCE_SECS: i64 = 60;
+
+/// The window during which systemd may trigger a timer. It starts when the timer next elapses, and ends once the timer's
+/// `AccuracySec=` has passed. If systemd hasn't picked the `RandomizedDelaySec=` delay for that elapsation point yet, the
+/// window ends after the longest possible delay as well.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ActivationWindow {
+    /// When the timer next elapses, before any randomized delay. systemd picks a new delay whenever it recalculates the
+    /// timer, so skipped activations are identified by this instead of by `start`.
+    pub elapse: DateTime<Utc>,
+    /// When the timer next elapses, including the randomized delay if systemd has picked it
+    pub start: DateTime<Utc>,
+    /// The latest time at which systemd will trigger the timer
+    pub end: DateTime<Utc>,
+    /// The timer's `AccuracySec=`
+    pub accuracy: Duration,
+    /// The timer's `RandomizedDelaySec=`
+    pub randomized_delay: Duration,
+    /// Whether or not the timer has `Persistent=` set, so that it will be triggered on boot if it was missed
+    pub persistent: bool,
+}
+
+impl ActivationWindow {
+    /// Gets the time at which to wake the system for this timer, following `policy`.
+    pub fn wake_time(&self, policy: WakePolicy) -> DateTime<Utc> {
+        match policy.for_timer(self.persistent) {
+            WakePolicy::End => self.end,
+            _ => self.start,
+        }
+    }
+
+    /// Moves the window to a later activation of the same timer, which elapses at `elapse`. systemd hasn't picked a
+    /// randomized delay for that one yet, so the window ends after the longest possible delay.
+    pub fn moved_to(&self, elapse: DateTime<Utc>) -> ActivationWindow {
+        ActivationWindow {
+            elapse,
+            start: elapse,
+            end: elapse + self.randomized_delay + self.accuracy,
+            ..*self
+        }
+    }
+}
+
+/// How the system is about to go down, which decides which timer events can bring it back up
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Downtime {
+    /// The system is shutting down, so only events on the realtime clock can still elapse afterwards
+    Shutdown,
+    /// The system is suspending, which also keeps monotonic events on `CLOCK_BOOTTIME` counting
+    Suspend,
+}
+
+/// What a monotonic timer event is relative to. See `man:systemd.timer(5)` for details.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MonotonicBase {
+    /// `OnActiveSec=`, relative to when the timer was activated
+    Active,
+    /// `OnBootSec=`, relative to when the system booted
+    Boot,
+    /// `OnStartupSec=`, relative to when the service manager started
+    Startup,
+    /// `OnUnitActiveSec=`, relative to when the triggered unit was last activated
+    UnitActive,
+    /// `OnUnitInactiveSec=`, relative to when the triggered unit was last deactivated
+    UnitInactive,
+}
+
+impl FromStr for MonotonicBase {
+    type Err = anyhow::Error;
+
+    /// Parses a base from the names used by the `TimersMonotonic` D-Bus property
+    fn from_str(s: &str) -> Result<MonotonicBase> {
+        match s {
+            "OnActiveUSec" => Ok(MonotonicBase::Active),
+            "OnBootUSec" => Ok(MonotonicBase::Boot),
+            "OnStartupUSec" => Ok(MonotonicBase::Startup),
+            "OnUnitActiveUSec" => Ok(MonotonicBase::UnitActive),
+            "OnUnitInactiveUSec" => Ok(MonotonicBase::UnitInactive),
+            other => Err(anyhow!("Unknown monotonic timer base {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for MonotonicBase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let setting = match self {
+            MonotonicBase::Active => "OnActiveSec=",
+            MonotonicBase::Boot => "OnBootSec=",
+            MonotonicBase::Startup => "OnStartupSec=",
+            MonotonicBase::UnitActive => "OnUnitActiveSec=",
+            MonotonicBase::UnitInactive => "OnUnitInactiveSec=",
+        };
+        write!(f, "{}", setting)
+    }
+}
+
+/// Looks up the next activation window of the given timer unit, if it will activate once the system goes down the way
+/// `downtime` says. Elapsation points systemd reports at or before `now` are treated as stale. Each D-Bus call gives up
+/// once `deadline` expires.
+pub fn next_activation(
+    logger: &Logger,
+    conn: &Connection,
+    timer_unit: &str,
+    now: &DateTime<Utc>,
+    downtime: Downtime,
+    deadline: &Deadline,
+) -> Result<Option<ActivationWindow>> {
+    let unit_path = systemd_unit_path(conn, timer_unit, deadline.call_timeout())?;
+    let timer = || systemd_unit_at(conn, unit_path.clone(), deadline.call_timeout());
+
+    let accuracy_usecs = timer()
+        .accuracy_usec()
+        .context("Could not get timer accuracy")?;
+    let randomized_delay_usecs = timer()
+        .randomized_delay_usec()
+        .context("Could not get timer randomized delay")?;
+    let persistent = timer()
+        .persistent()
+        .context("Could not determine if timer is persistent")?;
+
+    // If either is 0, that means the timer doesn't include any events using the corresponding clock
+    let reported_realtime = match timer()
+        .next_elapse_usec_realtime()
+        .context("Could not get next CLOCK_REALTIME elapsation point")?
+    {
+        0 => None,
+        realtime_usecs => {
+            let next_realtime = from_timestamp_usecs(realtime_usecs);
+            debug!(&logger, "Next CLOCK_REALTIME elapsation point is {}", next_realtime; "unit" => timer_unit);
+            Some(next_realtime)
+        }
+    };
+
+    // Once shutdown has started, systemd may report a stale or missing elapsation point for calendar timers, so
+    // cross-check it against the timer's OnCalendar= expressions. systemd includes the randomized delay in the
+    // elapsation point it reports, but computed ones can still be pushed back by up to the whole delay. Each of these
+    // is the elapse without the delay, when the timer may start, and how much of the delay could still come on top.
+    let randomized_delay = Duration::microseconds(randomized_delay_usecs as i64);
+    let calendar = calendar_specs(logger, &timer(), timer_unit);
+    let computed_realtime = next_elapse_of(&calendar, now);
+    let next_realtime = match (reported_realtime, computed_realtime) {
+        (None, Some(computed)) => {
+            warn!(&logger, "systemd did not report a CLOCK_REALTIME elapsation point, using {} from OnCalendar=", computed; "unit" => timer_unit);
+            Some((computed, computed, randomized_delay))
+        }
+        (Some(reported), Some(computed)) if reported <= *now => {
+            warn!(&logger, "systemd reported stale CLOCK_REALTIME elapsation point {}, using {} from OnCalendar=", reported, computed; "unit" => timer_unit);
+            Some((computed, computed, randomized_delay))
+        }
+        (Some(reported), Some(computed)) => {
+            // The reported activation may have elapsed already, and only be waiting for its delay to pass
+            let tolerance = Duration::seconds(CALENDAR_TOLERANCE_SECS);
+            match next_elapse_of(&calendar, &(reported - randomized_delay - tolerance)) {
+                Some(elapse) if elapse <= reported + tolerance => {
+                    Some((elapse, reported, Duration::zero()))
+                }
+                _ => {
+                    warn!(&logger, "systemd reported CLOCK_REALTIME elapsation point {}, but OnCalendar= expressions elapse at {}", reported, computed; "unit" => timer_unit);
+                    Some((reported, reported, Duration::zero()))
+                }
+            }
+        }
+        (reported, None) => reported.map(|reported| (reported, reported, Duration::zero())),
+    };
+
+    // Monotonic events are all relative to something that happens during boot, so they can't be used to schedule a
+    // wakeup across a shutdown. systemd tracks those of WakeSystem= timers on CLOCK_BOOTTIME, which keeps counting while
+    // suspended, so it wakes the system from suspend for them. All others are on CLOCK_MONOTONIC, which doesn't.
+    let wake_system = timer()
+        .wake_system()
+        .context("Could not determine if timer wakes the system")?;
+    let mut next_boottime = None;
+    for (base, _, elapse_usecs) in timer()
+        .timers_monotonic()
+        .context("Could not get monotonic timer events")?
+    {
+        if elapse_usecs == 0 {
+            continue;
+        }
+        let base = match base.parse::<MonotonicBase>() {
+            Ok(base) => base,
+            Err(e) => {
+                warn!(&logger, "Ignoring unknown monotonic timer event"; "unit" => timer_unit, "error" => ?e);
+                continue;
+            }
+        };
+        if !wake_system {
+            let elapse = monotonic_to_realtime(from_timestamp_usecs(elapse_usecs));
+            debug!(&logger, "Ignoring {} event at {}, since it stops counting while the system is down", base, elapse; "unit" => timer_unit);
+            continue;
+        }
+
+        let elapse = boottime_to_realtime(from_timestamp_usecs(elapse_usecs));
+        match downtime {
+            Downtime::Shutdown => {
+                debug!(&logger, "Ignoring {} event at {}, since it restarts counting after shutdown", base, elapse; "unit" => timer_unit)
+            }
+            Downtime::Suspend if elapse <= *now => {
+                debug!(&logger, "Ignoring stale {} event at {}", base, elapse; "unit" => timer_unit)
+            }
+            Downtime::Suspend => {
+                debug!(&logger, "Next CLOCK_BOOTTIME elapsation point for {} is {}", base, elapse; "unit" => timer_unit);
+                next_boottime = match next_boottime {
+                    Some(next) if next <= elapse => Some(next),
+                    _ => Some(elapse),
+                };
+            }
+        }
+    }
+
+    // Like computed calendar elapses, monotonic ones don't include the randomized delay
+    let next = match (
+        next_realtime,
+        next_boottime.map(|elapse| (elapse, elapse, randomized_delay)),
+    ) {
+        (Some(realtime), Some(boottime)) if boottime.1 < realtime.1 => Some(boottime),
+        (realtime, boottime) => realtime.or(boottime),
+    };
+    let (elapse, start, remaining_delay) = match next {
+        Some(next) => next,
+        None => {
+            debug!(&logger, "Timer has no events that elapse while the system is down"; "unit" => timer_unit, "downtime" => ?downtime);
+            return Ok(None);
+        }
+    };
+
+    let accuracy = Duration::microseconds(accuracy_usecs as i64);
+    let window = ActivationWindow {
+        elapse,
+        start,
+        end: start + remaining_delay + accuracy,
+        accuracy,
+        randomized_delay,
+        persistent,
+    };
+    debug!(&logger, "Timer may activate between {} and {}", window.start, window.end; "unit" => timer_unit, "persistent" => persistent);
+
+    Ok(Some(window))
+}
+
+/// Works out when the given timer unit's `OnCalendar=` expressions next elapse after `after`. systemd only reports the
+/// next elapsation point, so this is how later activations are found. Returns `None` if the timer has no calendar
+/// expressions that elapse again. Each D-Bus call gives up once `deadline` expires.
+pub fn calendar_elapse_after(
+    logger: &Logger,
+    conn: &Connection,
+    timer_unit: &str,
+    after: &DateTime<Utc>,
+    deadline: &Deadline,
+) -> Result<Option<DateTime<Utc>>> {
+    let unit_path = systemd_unit_path(conn, timer_unit, deadline.call_timeout())?;
+    let timer = systemd_unit_at(conn, unit_path, deadline.call_timeout());
+    Ok(next_elapse_of(
+        &calendar_specs(logger, &timer, timer_unit),
+        after,
+    ))
+}
+
+/// Reads and parses the timer's `OnCalendar=` expressions, leaving out any that can't be evaluated
+fn calendar_specs<T: OrgFreedesktopSystemd1Timer>(
+    logger: &Logger,
+    timer: &T,
+    timer_unit: &str,
+) -> Vec<CalendarSpec> {
+    let calendar_timers = match timer.timers_calendar() {
+        Ok(calendar_timers) => calendar_timers,
+        Err(err) => {
+            warn!(&logger, "Could not get OnCalendar= expressions"; "unit" => timer_unit, "error" => ?err);
+            return Vec::new();
+        }
+    };
+
+    calendar_timers
+        .iter()
+        .filter_map(|(_, spec, _)| match spec.parse::<CalendarSpec>() {
+            Ok(calendar) => Some(calendar),
+            Err(err) => {
+                warn!(&logger, "Could not evaluate OnCalendar={}", spec; "unit" => timer_unit, "error" => ?err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Independently computes when any of `calendar` next elapses after `after`. Returns `None` if none of them elapse
+/// again.
+fn next_elapse_of(calendar: &[CalendarSpec], after: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+    calendar
+        .iter()
+        .filter_map(|spec| spec.next_elapse(after))
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dbus::testing::{FakeSystemd, FakeTimer, TestBus};
+    use slog::{o, Discard};
+
+    use super::*;
+
+    const UNIT: &str = "night-kitchen-daily.timer";
+
+    fn usecs(time: DateTime<Utc>) -> u64 {
+        (time.timestamp() * 1_000_000) as u64
+    }
+
+    #[test]
+    fn window_includes_accuracy() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+
+        let elapse = from_timestamp_usecs(usecs(Utc::now() + Duration::hours(2)));
+        systemd.add_timer(
+            UNIT,
+            FakeTimer {
+                next_elapse_usec_realtime: usecs(elapse),
+                accuracy_usec: 60_000_000,
+                randomized_delay_usec: 30_000_000,
+                persistent: true,
+                ..FakeTimer::default()
+            },
+        );
+
+        let window = next_activation(
+            &Logger::root(Discard, o!()),
+            &conn,
+            UNIT,
+            &Utc::now(),
+            Downtime::Shutdown,
+            &Deadline::none(),
+        )
+        .unwrap()
+        .unwrap();
+        // The reported elapsation point already includes the randomized delay
+        assert_eq!(window.start, elapse);
+        assert_eq!(window.end, elapse + Duration::seconds(60));
+        assert!(window.persistent);
+    }
+
+    #[test]
+    fn stale_elapse_uses_calendar() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+
+        let now = Utc::now();
+        systemd.add_timer(
+            UNIT,
+            FakeTimer {
+                next_elapse_usec_realtime: usecs(now - Duration::hours(1)),
+                timers_calendar: vec![(
+                    "OnCalendar".to_string(),
+                    "*-*-* 04:00:00 UTC".to_string(),
+                    0,
+                )],
+                accuracy_usec: 60_000_000,
+                randomized_delay_usec: 30_000_000,
+                ..FakeTimer::default()
+            },
+        );
+
+        let window = next_activation(
+            &Logger::root(Discard, o!()),
+            &conn,
+            UNIT,
+            &Utc::now(),
+            Downtime::Shutdown,
+            &Deadline::none(),
+        )
+        .unwrap()
+        .unwrap();
+        let expected = "*-*-* 04:00:00 UTC"
+            .parse::<CalendarSpec>()
+            .unwrap()
+            .next_elapse(&now)
+            .unwrap();
+        assert_eq!(window.start, expected);
+        assert_eq!(window.end, expected + Duration::seconds(90));
+    }
+
+    #[test]
+    fn elapse_leaves_out_randomized_delay() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+
+        let now = Utc::now();
+        let computed = "*-*-* 04:00:00 UTC"
+            .parse::<CalendarSpec>()
+            .unwrap()
+            .next_elapse(&now)
+            .unwrap();
+        let reported = computed + Duration::minutes(20);
+        systemd.add_timer(
+            UNIT,
+            FakeTimer {
+                next_elapse_usec_realtime: usecs(reported),
+                timers_calendar: vec![(
+                    "OnCalendar".to_string(),
+                    "*-*-* 04:00:00 UTC".to_string(),
+                    0,
+                )],
+                accuracy_usec: 60_000_000,
+                randomized_delay_usec: 1_800_000_000,
+                ..FakeTimer::default()
+            },
+        );
+
+        let window = next_activation(
+            &Logger::root(Discard, o!()),
+            &conn,
+            UNIT,
+            &now,
+            Downtime::Shutdown,
+            &Deadline::none(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(window.elapse, computed);
+        assert_eq!(window.start, reported);
+        assert_eq!(window.end, reported + Duration::seconds(60));
+
+        // Still the same activation once it has elapsed, while the delay runs
+        let delaying = next_activation(
+            &Logger::root(Discard, o!()),
+            &conn,
+            UNIT,
+            &(computed + Duration::minutes(10)),
+            Downtime::Shutdown,
+            &Deadline::none(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(delaying, window);
+
+        // systemd hasn't picked the delay for the next day's activation yet
+        let moved = window.moved_to(computed + Duration::days(1));
+        assert_eq!(moved.start, moved.elapse);
+        assert_eq!(
+            moved.end,
+            moved.elapse + Duration::minutes(30) + Duration::seconds(60)
+        );
+    }
+
+    #[test]
+    fn no_realtime_events() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+
+        systemd.add_timer(
+            UNIT,
+            FakeTimer {
+                timers_monotonic: vec![("OnBootUSec".to_string(), 900_000_000, 900_000_000)],
+                ..FakeTimer::default()
+            },
+        );
+
+        let window = next_activation(
+            &Logger::root(Discard, o!()),
+            &conn,
+            UNIT,
+            &Utc::now(),
+            Downtime::Shutdown,
+            &Deadline::none(),
+        )
+        .unwrap();
+        assert_eq!(window, None);
+    }
+
+    #[test]
+    fn suspend_includes_boottime_events() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+
+        let epoch = from_timestamp_usecs(0);
+        let boot_offset = boottime_to_realtime(epoch) - epoch;
+        let elapse = Utc::now() + Duration::hours(1);
+        systemd.add_timer(
+            UNIT,
+            FakeTimer {
+                wake_system: true,
+                accuracy_usec: 60_000_000,
+                timers_monotonic: vec![(
+                    "OnUnitActiveUSec".to_string(),
+                    3_600_000_000,
+                    usecs(elapse - boot_offset),
+                )],
+                ..FakeTimer::default()
+            },
+        );
+
+        let logger = Logger::root(Discard, o!());
+        let window = next_activation(
+            &logger,
+            &conn,
+            UNIT,
+            &Utc::now(),
+            Downtime::Suspend,
+            &Deadline::none(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!((window.start - elapse).num_seconds().abs() <= 1);
+        assert_eq!(window.end, window.start + Duration::seconds(60));
+
+        let window = next_activation(
+            &logger,
+            &conn,
+            UNIT,
+            &Utc::now(),
+            Downtime::Shutdown,
+            &Deadline::none(),
+        )
+        .unwrap();
+        assert_eq!(window, None);
+    }
+}
@@ -0,0 +1,42 @@
+use std::env;
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use slog::{o, Drain, Level, LevelFilter, Logger};
+use slog_term::{FullFormat, TermDecorator};
+
+mod run_now;
+mod skip;
+mod status;
+
+const USAGE: &str = "Usage:
+  night-kitchen status [--json] [--runs <count>]
+  night-kitchen run-now <target> [--then poweroff|suspend|none] [--json]
+  night-kitchen skip <timer> [<count>]
+  night-kitchen unskip [<timer>]
+  night-kitchen pause <time or time span>
+  night-kitchen unpause";
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let logger = cli_logger();
+
+    match args.first().map(String::as_str) {
+        Some("status") => status::run(&logger, &args[1..]),
+        Some("run-now") => run_now::run(&logger, &args[1..]),
+        Some("skip") => skip::skip(&args[1..]),
+        Some("unskip") => skip::unskip(&args[1..]),
+        Some("pause") => skip::pause(&args[1..]),
+        Some("unpause") => skip::unpause(&args[1..]),
+        _ => bail!(USAGE),
+    }
+}
+
+/// Creates a logger for anything worth warning about, which goes to stderr so that it doesn't mix with the output
+fn cli_logger() -> Logger {
+    let decorator = TermDecorator::new().stderr().build();
+    let drain = FullFormat::new(decorator).build();
+    let drain = LevelFilter::new(drain, Level::Warning).fuse();
+    let drain = Mutex::new(drain).fuse();
+    Logger::root(drain, o!())
+}
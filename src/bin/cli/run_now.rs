@@ -0,0 +1,180 @@
+//! `night-kitchen run-now`, which runs a task target through the runner by hand, in the same service its timer would
+//! start, and reports how each job went.
+use std::fmt::Write;
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use dbus::blocking::Connection;
+use slog::Logger;
+
+use night_kitchen::activation::TIMER_UNITS;
+use night_kitchen::dbus::start_unit;
+use night_kitchen::history::{History, PowerAction, RunCause, RunOutcome, RunRecord};
+use night_kitchen::run_request::RunRequest;
+
+use crate::status::{local, state_directory};
+
+const USAGE: &str = "Usage: night-kitchen run-now <target> [--then poweroff|suspend|none] [--json]";
+
+/// Runs `night-kitchen run-now` with the arguments following `run-now`
+pub fn run(logger: &Logger, args: &[String]) -> Result<()> {
+    let mut target = None;
+    let mut power_action = PowerAction::None;
+    let mut json = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--then" => {
+                power_action = match args.next() {
+                    Some(action) => action.parse()?,
+                    None => bail!("--then needs a power action"),
+                }
+            }
+            name if target.is_none() && !name.starts_with('-') => target = Some(target_unit(name)?),
+            other => bail!("Unknown argument: {}\n{}", other, USAGE),
+        }
+    }
+    let target = match target {
+        Some(target) => target,
+        None => bail!(USAGE),
+    };
+    let service = target.replace(".target", ".service");
+
+    let state_dir = state_directory();
+    let started = Utc::now();
+    RunRequest::new(target.as_str(), power_action)
+        .submit(&state_dir)
+        .context("Could not leave run request for the runner")?;
+
+    if !json {
+        println!("Running {} through {}...", target, service);
+    }
+    let mut conn = Connection::new_system().context("Could not connect to system D-Bus")?;
+    let service_result = start_unit(logger, &mut conn, &service)?
+        .pop()
+        .map(|outcome| outcome.result)
+        .unwrap_or_default();
+
+    let record = History::open(&state_dir)
+        .since(started)?
+        .into_iter()
+        .rev()
+        .find(|run| run.target == target && run.cause == RunCause::Manual);
+    let record = match record {
+        Some(record) => record,
+        None => bail!(
+            "{} finished with result {}, but the runner didn't record a run",
+            service,
+            service_result
+        ),
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&record).context("Could not serialize run")?
+        );
+    } else {
+        print!("{}", report(&record));
+    }
+    match record.outcome() {
+        RunOutcome::Success => (),
+        RunOutcome::Skipped => bail!("{} was skipped", target),
+        RunOutcome::Failure | RunOutcome::Timeout => bail!("{} failed", target),
+    }
+    Ok(())
+}
+
+/// Finds the Night Kitchen target `name` refers to, which can be the unit name or just `daily` or `weekly`
+fn target_unit(name: &str) -> Result<String> {
+    let unit = if name.ends_with(".target") {
+        name.to_string()
+    } else {
+        format!("night-kitchen-{}.target", name)
+    };
+    let targets: Vec<String> = TIMER_UNITS
+        .iter()
+        .map(|timer| timer.replace(".timer", ".target"))
+        .collect();
+    if !targets.contains(&unit) {
+        bail!(
+            "Unknown target {}, expected one of {}",
+            name,
+            targets.join(", ")
+        );
+    }
+    Ok(unit)
+}
+
+/// Describes how a run went, job by job
+fn report(record: &RunRecord) -> String {
+    let mut report = String::new();
+    let _ = writeln!(
+        report,
+        "{} {} at {} after {}s",
+        record.target,
+        match record.outcome() {
+            RunOutcome::Success => "succeeded",
+            RunOutcome::Skipped => "was skipped",
+            RunOutcome::Failure | RunOutcome::Timeout => "failed",
+        },
+        local(&record.finished),
+        (record.finished - record.started).num_seconds()
+    );
+    if let Some(error) = &record.error {
+        let _ = writeln!(report, "  Could not start: {}", error);
+    }
+    if let Some(reason) = &record.skipped {
+        let _ = writeln!(report, "  Skipped: {}", reason);
+    }
+    for unit in &record.units {
+        let _ = writeln!(report, "  {}: {}", unit.unit, unit.result);
+    }
+    let _ = match record.power_action {
+        PowerAction::PowerOff => writeln!(report, "Powering off"),
+        PowerAction::Suspend => writeln!(report, "Suspending"),
+        PowerAction::None => writeln!(report, "Leaving the system running"),
+    };
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use night_kitchen::history::UnitOutcome;
+
+    use super::*;
+
+    #[test]
+    fn reports_each_job() {
+        let started = Utc::now();
+        let record = RunRecord {
+            target: "night-kitchen-daily.target".to_string(),
+            timer: None,
+            cause: RunCause::Manual,
+            started,
+            finished: started + Duration::seconds(42),
+            units: vec![
+                UnitOutcome {
+                    unit: "backup.service".to_string(),
+                    result: "failed".to_string(),
+                },
+                UnitOutcome {
+                    unit: "night-kitchen-daily.target".to_string(),
+                    result: "done".to_string(),
+                },
+            ],
+            error: None,
+            skipped: None,
+            power_action: PowerAction::Suspend,
+        };
+
+        let report = report(&record);
+        assert!(report.starts_with("night-kitchen-daily.target failed at "));
+        assert!(report.contains("after 42s\n  backup.service: failed\n"));
+        assert!(report.ends_with("Suspending\n"));
+        assert_eq!(target_unit("daily").unwrap(), "night-kitchen-daily.target");
+        assert!(target_unit("night-kitchen-hourly.target").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! `night-kitchen skip`, `unskip`, `pause` and `unpause`, which stop Night Kitchen waking the system for a while. These
+//! go through the scheduler, so that it can work out which activations are skipped and update the wake alarm.
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use dbus::blocking::Connection;
+
+use night_kitchen::activation::TIMER_UNITS;
+use night_kitchen::config::parse_timespan;
+use night_kitchen::dbus::scheduler::IoGithubNightKitchenScheduler;
+use night_kitchen::dbus::scheduler_control;
+use night_kitchen::time::{from_timestamp_usecs, to_timestamp_usecs};
+
+use crate::status::local;
+
+/// Runs `night-kitchen skip <timer> [<count>]`
+pub fn skip(args: &[String]) -> Result<()> {
+    let (timer, count) = match args {
+        [timer] => (timer_unit(timer)?, 1),
+        [timer, count] => (
+            timer_unit(timer)?,
+            count
+                .parse()
+                .with_context(|| format!("Invalid activation count: {}", count))?,
+        ),
+        _ => bail!("Usage: night-kitchen skip <timer> [<count>]"),
+    };
+
+    let conn = connect()?;
+    let elapses = scheduler_control(&conn)
+        .skip_next(&timer, count)
+        .with_context(|| format!("Could not skip activations of {}", timer))?;
+    for elapse in elapses {
+        println!(
+            "Skipping {} at {}",
+            timer,
+            local(&from_timestamp_usecs(elapse))
+        );
+    }
+    Ok(())
+}
+
+/// Runs `night-kitchen unskip [<timer>]`
+pub fn unskip(args: &[String]) -> Result<()> {
+    let timer = match args {
+        [] => String::new(),
+        [timer] => timer_unit(timer)?,
+        _ => bail!("Usage: night-kitchen unskip [<timer>]"),
+    };
+
+    let conn = connect()?;
+    scheduler_control(&conn)
+        .clear_skips(&timer)
+        .context("Could not clear skipped activations")
+}
+
+/// Runs `night-kitchen pause <until>`
+pub fn pause(args: &[String]) -> Result<()> {
+    if args.is_empty() {
+        bail!("Usage: night-kitchen pause <time or time span>");
+    }
+    let until = parse_until(&args.join(" "), Utc::now())?;
+
+    let conn = connect()?;
+    scheduler_control(&conn)
+        .pause(to_timestamp_usecs(&until))
+        .context("Could not pause Night Kitchen")?;
+    println!("Paused until {}", local(&until));
+    Ok(())
+}
+
+/// Runs `night-kitchen unpause`
+pub fn unpause(args: &[String]) -> Result<()> {
+    if !args.is_empty() {
+        bail!("Usage: night-kitchen unpause");
+    }
+
+    let conn = connect()?;
+    scheduler_control(&conn)
+        .pause(0)
+        .context("Could not end pause")
+}
+
+fn connect() -> Result<Connection> {
+    Connection::new_system().context("Could not connect to system D-Bus")
+}
+
+/// Finds the Night Kitchen timer `name` refers to, which can be the unit name or just `daily` or `weekly`
+fn timer_unit(name: &str) -> Result<String> {
+    let unit = if name.ends_with(".timer") {
+        name.to_string()
+    } else {
+        format!("night-kitchen-{}.timer", name)
+    };
+    if !TIMER_UNITS.contains(&unit.as_str()) {
+        bail!(
+            "Unknown timer {}, expected one of {}",
+            name,
+            TIMER_UNITS.join(", ")
+        );
+    }
+    Ok(unit)
+}
+
+/// Parses when a pause should end, either as a local date and time such as `2020-03-10` or `2020-03-10 08:00`, or as a
+/// time span from `now` such as `3d`
+fn parse_until(s: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    if let Ok(span) = parse_timespan(s) {
+        return Ok(now + chrono::Duration::from_std(span)?);
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_hms(0, 0, 0))
+        });
+    match naive.map(|naive| Local.from_local_datetime(&naive).earliest()) {
+        Some(Some(time)) => Ok(time.with_timezone(&Utc)),
+        Some(None) => bail!("{} doesn't exist in the local timezone", s),
+        None => bail!("Invalid time: {}", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Timelike;
+
+    use super::*;
+
+    #[test]
+    fn parses_pause_end() {
+        let now = Utc.ymd(2020, 3, 1).and_hms(22, 0, 0);
+        assert_eq!(
+            parse_until("2d 12h", now).unwrap(),
+            now + chrono::Duration::hours(60)
+        );
+
+        let date = parse_until("2020-03-10", now)
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!(date.naive_local().date(), NaiveDate::from_ymd(2020, 3, 10));
+        assert_eq!(date.hour(), 0);
+        let time = parse_until("2020-03-10 08:30", now)
+            .unwrap()
+            .with_timezone(&Local);
+        assert_eq!((time.hour(), time.minute()), (8, 30));
+
+        assert!(parse_until("next tuesday", now).is_err());
+    }
+
+    #[test]
+    fn resolves_timer_names() {
+        assert_eq!(timer_unit("daily").unwrap(), "night-kitchen-daily.timer");
+        assert_eq!(
+            timer_unit("night-kitchen-weekly.timer").unwrap(),
+            "night-kitchen-weekly.timer"
+        );
+        assert!(timer_unit("hourly").is_err());
+    }
+}
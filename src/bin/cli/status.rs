@@ -0,0 +1,373 @@
+//! `night-kitchen status`, which shows when Night Kitchen will next wake the system and how its recent runs went
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local, Utc};
+use dbus::blocking::Connection;
+use serde::Serialize;
+use slog::Logger;
+
+use night_kitchen::activation::{next_activation, Downtime, TIMER_UNITS};
+use night_kitchen::config::SchedulerConfig;
+use night_kitchen::dbus::{login_manager, LoginManager};
+use night_kitchen::history::{History, PowerAction, RunCause, RunOutcome, RunRecord};
+use night_kitchen::power_monitor::Deadline;
+use night_kitchen::rtc::{self, Adjtime, Rtc};
+use night_kitchen::skips::Skips;
+use night_kitchen::SCHEDULER_INHIBITOR_SOURCE;
+
+/// How many past runs to show by default
+const DEFAULT_RUNS: usize = 5;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+
+/// Where systemd creates the runner's `StateDirectory=`, which holds the run history and skipped activations
+const SYSTEM_STATE_DIRECTORY: &str = "/var/lib/night-kitchen";
+
+/// Everything `night-kitchen status` reports
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub timers: Vec<TimerStatus>,
+    pub rtc_alarm: AlarmStatus,
+    pub inhibitor: InhibitorStatus,
+    /// When the current pause ends, if Night Kitchen is paused
+    pub paused_until: Option<DateTime<Utc>>,
+    /// When each skipped activation elapses, by timer unit
+    pub skipped: BTreeMap<String, Vec<DateTime<Utc>>>,
+    /// The most recent runs, oldest first
+    pub runs: Vec<RunRecord>,
+}
+
+/// When a Night Kitchen timer will next activate
+#[derive(Debug, Serialize)]
+pub struct TimerStatus {
+    pub unit: String,
+    /// When the timer next elapses, if it has any calendar events
+    pub next_elapse: Option<DateTime<Utc>>,
+    /// The latest time systemd will trigger the timer after it elapses
+    pub window_end: Option<DateTime<Utc>>,
+    /// Why the timer couldn't be looked up, if it couldn't
+    pub error: Option<String>,
+}
+
+/// The state of the RTC wake alarm
+#[derive(Debug, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum AlarmStatus {
+    Armed { time: DateTime<Utc> },
+    Disabled,
+    Unknown { error: String },
+}
+
+/// Whether the scheduler holds its inhibitor lock, which it needs to set the wake alarm at shutdown
+#[derive(Debug, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum InhibitorStatus {
+    Held { what: String, mode: String },
+    Released,
+    Unknown { error: String },
+}
+
+/// Runs `night-kitchen status` with the arguments following `status`
+pub fn run(logger: &Logger, args: &[String]) -> Result<()> {
+    let mut json = false;
+    let mut runs = DEFAULT_RUNS;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--runs" => {
+                runs = match args.next() {
+                    Some(count) => count
+                        .parse()
+                        .with_context(|| format!("Invalid run count: {}", count))?,
+                    None => bail!("--runs needs a count"),
+                }
+            }
+            other => bail!("Unknown argument: {}", other),
+        }
+    }
+
+    let config = SchedulerConfig::from_env().context("Invalid scheduler configuration")?;
+    let conn = Connection::new_system().context("Could not connect to system D-Bus")?;
+    let alarm = match rtc::open(config.rtc_interface) {
+        Ok(rtc) => alarm_status(&*rtc, Adjtime::read()),
+        Err(err) => AlarmStatus::Unknown {
+            error: format!("{:#}", err),
+        },
+    };
+    let status = collect(logger, &conn, alarm, &state_directory(), runs);
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&status).context("Could not serialize status")?
+        );
+    } else {
+        print!("{}", status);
+    }
+    Ok(())
+}
+
+/// Gathers the status of the timers, the scheduler's inhibitor lock, what's skipped and the last `runs` runs, using the
+/// state kept in `state_dir`. Anything that can't be looked up is reported as an error in the status rather than failing
+/// outright.
+pub fn collect(
+    logger: &Logger,
+    conn: &Connection,
+    rtc_alarm: AlarmStatus,
+    state_dir: &Path,
+    runs: usize,
+) -> Status {
+    let now = Utc::now();
+    let timers = TIMER_UNITS
+        .iter()
+        .map(|unit| {
+            let (window, error) = match next_activation(
+                logger,
+                conn,
+                unit,
+                &now,
+                Downtime::Shutdown,
+                &Deadline::none(),
+            ) {
+                Ok(window) => (window, None),
+                Err(err) => (None, Some(format!("{:#}", err))),
+            };
+            TimerStatus {
+                unit: unit.to_string(),
+                next_elapse: window.map(|window| window.start),
+                window_end: window.map(|window| window.end),
+                error,
+            }
+        })
+        .collect();
+
+    let inhibitor = match login_manager(conn).list_inhibitors() {
+        Ok(inhibitors) => inhibitors
+            .into_iter()
+            .find(|inhibitor| inhibitor.who == SCHEDULER_INHIBITOR_SOURCE)
+            .map(|inhibitor| InhibitorStatus::Held {
+                what: inhibitor.what,
+                mode: inhibitor.mode,
+            })
+            .unwrap_or(InhibitorStatus::Released),
+        Err(err) => InhibitorStatus::Unknown {
+            error: err.to_string(),
+        },
+    };
+
+    let mut skips = Skips::load(state_dir).unwrap_or_else(|err| {
+        slog::warn!(&logger, "Could not read skipped activations: {:?}", err);
+        Skips::default()
+    });
+    // Skipped activations that have elapsed are only cleared once their timer next runs
+    for elapses in skips.activations.values_mut() {
+        elapses.retain(|elapse| *elapse > now);
+    }
+    skips.activations.retain(|_, elapses| !elapses.is_empty());
+
+    let runs = History::open(state_dir).last(runs).unwrap_or_else(|err| {
+        slog::warn!(&logger, "Could not read run history: {:?}", err);
+        Vec::new()
+    });
+
+    Status {
+        timers,
+        rtc_alarm,
+        inhibitor,
+        paused_until: skips.paused_until.filter(|until| *until > now),
+        skipped: skips.activations,
+        runs,
+    }
+}
+
+/// Reads the RTC wake alarm, converting it from hardware clock time using `adjtime`
+pub fn alarm_status<R: Rtc + ?Sized>(rtc: &R, adjtime: Result<Adjtime>) -> AlarmStatus {
+    let alarm = rtc.alarm_configuration().and_then(|alarm| {
+        let adjtime = adjtime.context("Could not get hardware clock settings")?;
+        Ok((alarm, adjtime))
+    });
+    match alarm {
+        Ok((alarm, adjtime)) if alarm.enabled() => AlarmStatus::Armed {
+            time: adjtime.to_datetime(&alarm.time()),
+        },
+        Ok(_) => AlarmStatus::Disabled,
+        Err(err) => AlarmStatus::Unknown {
+            error: format!("{:#}", err),
+        },
+    }
+}
+
+/// Finds the state directory. Unlike the services, the CLI isn't run with `STATE_DIRECTORY` set, so this falls back to
+/// the directory systemd creates for them.
+pub fn state_directory() -> PathBuf {
+    env::var_os("STATE_DIRECTORY")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(SYSTEM_STATE_DIRECTORY))
+}
+
+/// Formats `time` in the local timezone
+pub fn local(time: &DateTime<Utc>) -> impl fmt::Display {
+    time.with_timezone(&Local).format(TIME_FORMAT)
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Timers:")?;
+        for timer in &self.timers {
+            match (&timer.error, timer.next_elapse, timer.window_end) {
+                (Some(error), _, _) => writeln!(f, "  {}: {}", timer.unit, error)?,
+                (None, Some(start), Some(end)) => writeln!(
+                    f,
+                    "  {}: activates between {} and {}",
+                    timer.unit,
+                    local(&start),
+                    local(&end)
+                )?,
+                _ => writeln!(f, "  {}: no calendar events", timer.unit)?,
+            }
+        }
+
+        match &self.rtc_alarm {
+            AlarmStatus::Armed { time } => writeln!(f, "RTC wake alarm: {}", local(time))?,
+            AlarmStatus::Disabled => writeln!(f, "RTC wake alarm: disabled")?,
+            AlarmStatus::Unknown { error } => writeln!(f, "RTC wake alarm: unknown ({})", error)?,
+        }
+
+        match &self.inhibitor {
+            InhibitorStatus::Held { what, mode } => {
+                writeln!(f, "Scheduler inhibitor: held ({}, {})", mode, what)?
+            }
+            InhibitorStatus::Released => writeln!(f, "Scheduler inhibitor: not held")?,
+            InhibitorStatus::Unknown { error } => {
+                writeln!(f, "Scheduler inhibitor: unknown ({})", error)?
+            }
+        }
+
+        if let Some(until) = &self.paused_until {
+            writeln!(f, "Paused until {}", local(until))?;
+        }
+        for (timer, elapses) in &self.skipped {
+            for elapse in elapses {
+                writeln!(f, "Skipping {} at {}", timer, local(elapse))?;
+            }
+        }
+
+        if self.runs.is_empty() {
+            return writeln!(f, "No recorded runs");
+        }
+        writeln!(f, "Last runs:")?;
+        for run in &self.runs {
+            let cause = match run.cause {
+                RunCause::Boot => "booted",
+                RunCause::Wake => "woke",
+                RunCause::None => "already up",
+                RunCause::Manual => "run by hand",
+            };
+            let action = match run.power_action {
+                PowerAction::PowerOff => "powered off",
+                PowerAction::Suspend => "suspended",
+                PowerAction::None => "left running",
+            };
+            writeln!(
+                f,
+                "  {}  {}  {} in {}s ({}, {})",
+                local(&run.started),
+                run.target,
+                match run.outcome() {
+                    RunOutcome::Success => "succeeded",
+                    RunOutcome::Skipped => "skipped",
+                    RunOutcome::Failure | RunOutcome::Timeout => "failed",
+                },
+                (run.finished - run.started).num_seconds(),
+                cause,
+                action
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+    use night_kitchen::dbus::testing::{FakeLogind, FakeSystemd, FakeTimer, TestBus};
+    use night_kitchen::power_monitor::PowerMonitor;
+    use night_kitchen::rtc::{FakeRtc, RtcWakeAlarm};
+    use night_kitchen::time::from_timestamp_usecs;
+    use slog::{o, Discard};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn collects_timers_and_inhibitor() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let _logind = FakeLogind::start(&bus).unwrap();
+        let next_elapse = (Utc::now() + Duration::hours(6)).timestamp() as u64 * 1_000_000;
+        systemd.add_timer(
+            "night-kitchen-daily.timer",
+            FakeTimer {
+                next_elapse_usec_realtime: next_elapse,
+                accuracy_usec: 3_600_000_000,
+                ..FakeTimer::default()
+            },
+        );
+        let conn = bus.connect().unwrap();
+        let logger = Logger::root(Discard, o!());
+        let state = TempDir::new().unwrap();
+
+        let status = collect(&logger, &conn, AlarmStatus::Disabled, state.path(), 5);
+        assert_eq!(
+            status.timers[0].next_elapse,
+            Some(from_timestamp_usecs(next_elapse))
+        );
+        assert_eq!(
+            status.timers[0].window_end,
+            Some(from_timestamp_usecs(next_elapse) + Duration::hours(1))
+        );
+        assert!(status.timers[1].error.is_some());
+        assert!(matches!(status.inhibitor, InhibitorStatus::Released));
+        assert!(status.skipped.is_empty());
+
+        let skipped = from_timestamp_usecs(next_elapse);
+        Skips::update(state.path(), |skips| {
+            skips.skip("night-kitchen-daily.timer", skipped);
+            skips.skip("night-kitchen-daily.timer", Utc::now() - Duration::days(1));
+            skips.paused_until = Some(Utc::now() - Duration::hours(1));
+            Ok(())
+        })
+        .unwrap();
+
+        let monitor = PowerMonitor::new(logger.clone(), SCHEDULER_INHIBITOR_SOURCE, "Testing");
+        PowerMonitor::register(&conn, monitor).unwrap();
+        let status = collect(&logger, &conn, AlarmStatus::Disabled, state.path(), 5);
+        assert!(matches!(
+            status.inhibitor,
+            InhibitorStatus::Held { ref mode, .. } if mode == "delay"
+        ));
+        assert_eq!(status.skipped["night-kitchen-daily.timer"], vec![skipped]);
+        assert_eq!(status.paused_until, None);
+    }
+
+    #[test]
+    fn converts_alarm_from_hardware_time() {
+        let time = Utc.ymd(2020, 3, 2).and_hms(3, 0, 0);
+        let rtc = FakeRtc::with_alarm(RtcWakeAlarm::new(true, &time.naive_utc()));
+        assert!(matches!(
+            alarm_status(&rtc, Ok(Adjtime::default())),
+            AlarmStatus::Armed { time: armed } if armed == time
+        ));
+
+        let rtc = FakeRtc::with_alarm(RtcWakeAlarm::new(false, &time.naive_utc()));
+        assert!(matches!(
+            alarm_status(&rtc, Ok(Adjtime::default())),
+            AlarmStatus::Disabled
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+//! The scheduler's main loop as a tokio event loop. Unix signals, logind's signals and control calls are all handled as
+//! soon as they arrive, with dbus-tokio driving the system bus connection instead of it being polled.
+//!
+//! The power monitor, the control interface and timer lookups use the blocking D-Bus bindings, since those are the only
+//! ones generated for logind and systemd. So the dbus-tokio connection receives the monitor's signals and the control
+//! calls, owns the scheduler's name and sends the replies, while each message is handled in turn on tokio's blocking
+//! threads. Those make their own calls over a second, blocking connection, which is only used for calls and is never
+//! processed. Both connections are replaced whenever the bus connection is lost.
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::RequestNameReply as NonblockRequestNameReply;
+use dbus::nonblock::SyncConnection;
+use dbus::Message;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::future::{self, AbortHandle};
+use futures::StreamExt;
+use slog::{error, warn, Logger};
+use tokio::runtime::Builder;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::task;
+use tokio::time::{delay_for, interval};
+
+use night_kitchen::config::SchedulerConfig;
+use night_kitchen::dbus::scheduler::SCHEDULER_NAME;
+use night_kitchen::platform::Platform;
+use night_kitchen::power_monitor::PowerMonitor;
+
+use crate::backoff::Backoff;
+use crate::control::{self, NameUnavailable};
+use crate::{clock_changed, power_monitor, refresh_metrics, AlarmState, METRICS_INTERVAL};
+
+/// Runs the scheduler until it receives SIGTERM. If the system bus connection is lost, it reconnects and registers
+/// the power monitor again. Fails if the scheduler's bus name is unavailable.
+pub fn run(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: Arc<dyn Platform>,
+    armed_alarm: Arc<Mutex<AlarmState>>,
+) -> Result<()> {
+    let monitor = power_monitor(logger, config, platform.clone(), armed_alarm.clone());
+    let mut runtime = Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .context("Could not start tokio runtime")?;
+    runtime.block_on(event_loop(logger, config, platform, armed_alarm, monitor))
+}
+
+async fn event_loop(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: Arc<dyn Platform>,
+    armed_alarm: Arc<Mutex<AlarmState>>,
+    monitor: Arc<PowerMonitor>,
+) -> Result<()> {
+    let mut signals = Signals {
+        sigterm: signal(SignalKind::terminate()).context("Could not add SIGTERM hook")?,
+        sighup: signal(SignalKind::hangup()).context("Could not add SIGHUP hook")?,
+    };
+
+    let mut backoff = Backoff::default();
+    loop {
+        let err = match serve(
+            logger,
+            config,
+            &platform,
+            &armed_alarm,
+            &monitor,
+            &mut signals,
+            &mut backoff,
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(err) if err.is::<NameUnavailable>() => return Err(err),
+            Err(err) => err,
+        };
+
+        let delay = backoff.next_delay();
+        warn!(&logger, "Lost connection to system D-Bus, reconnecting in {:?}", delay; "error" => ?err);
+        tokio::select! {
+            _ = signals.sigterm.recv() => return Ok(()),
+            _ = delay_for(delay) => (),
+        }
+    }
+}
+
+/// The Unix signals the scheduler handles
+struct Signals {
+    sigterm: Signal,
+    sighup: Signal,
+}
+
+/// Stops driving a D-Bus connection when dropped
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// A message received on the dbus-tokio connection
+enum Received {
+    /// One of the power monitor's signals
+    Signal(Message),
+    /// A method call to the control object
+    Call(Message),
+}
+
+/// Connects to the system bus and handles events until SIGTERM is received, which returns `Ok`, or the connection is
+/// lost.
+async fn serve(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: &Arc<dyn Platform>,
+    armed_alarm: &Arc<Mutex<AlarmState>>,
+    monitor: &Arc<PowerMonitor>,
+    signals: &mut Signals,
+    backoff: &mut Backoff,
+) -> Result<()> {
+    let (resource, conn) =
+        dbus_tokio::connection::new_system_sync().context("Could not connect to system D-Bus")?;
+    // The resource future drives the connection, and only completes if the connection is lost
+    let (resource, resource_handle) = future::abortable(resource);
+    let _resource_guard = AbortOnDrop(resource_handle);
+    let mut connection_lost = tokio::spawn(resource);
+    let calls = Arc::new(Mutex::new(
+        Connection::new_system().context("Could not connect to system D-Bus")?,
+    ));
+
+    let (received, mut messages) = mpsc::unbounded();
+    for (rule, match_str) in PowerMonitor::signal_matches() {
+        conn.add_match_no_cb(&match_str)
+            .await
+            .with_context(|| format!("Could not listen for {}", match_str))?;
+        forward(&conn, rule, received.clone(), Received::Signal);
+    }
+    {
+        let monitor = monitor.clone();
+        let calls = calls.clone();
+        task::spawn_blocking(move || monitor.start(&*lock_calls(&calls)?))
+            .await
+            .context("Power monitor task failed")??;
+    }
+    let reply = conn
+        .request_name(SCHEDULER_NAME, false, false, true)
+        .await
+        .map(blocking_reply);
+    control::claimed_name(reply)?;
+    forward(&conn, control::method_calls(), received, Received::Call);
+    backoff.reset();
+
+    // The first tick completes immediately, so metrics are written as soon as the scheduler connects
+    let mut metrics_refresh = interval(METRICS_INTERVAL);
+
+    loop {
+        tokio::select! {
+            lost = &mut connection_lost => {
+                let err = match lost {
+                    Ok(Ok(err)) => anyhow!("{}", err),
+                    Ok(Err(aborted)) => anyhow!(aborted),
+                    Err(err) => anyhow!(err),
+                };
+                return Err(err.context("Lost connection to system D-Bus"));
+            }
+            _ = signals.sigterm.recv() => return Ok(()),
+            Some(message) = messages.next() => {
+                let config = config.clone();
+                let platform = platform.clone();
+                let armed_alarm = armed_alarm.clone();
+                let monitor = monitor.clone();
+                let reply = with_connection(logger, &calls, move |logger, calls| match message {
+                    Received::Signal(signal) => {
+                        monitor.handle_signal(calls, &signal);
+                        Ok(None)
+                    }
+                    Received::Call(call) => Ok(Some(control::handle(
+                        logger,
+                        calls,
+                        &config,
+                        &*platform,
+                        &armed_alarm,
+                        &call,
+                    ))),
+                })
+                .await;
+                if let Some(Some(reply)) = reply {
+                    let _ = conn.send(reply);
+                }
+            }
+            _ = metrics_refresh.tick() => {
+                if config.metrics_directory.is_some() {
+                    let config = config.clone();
+                    let platform = platform.clone();
+                    with_connection(logger, &calls, move |logger, conn| {
+                        refresh_metrics(logger, conn, &config, &*platform);
+                        Ok(())
+                    })
+                    .await;
+                }
+            }
+            _ = signals.sighup.recv() => {
+                let config = config.clone();
+                let platform = platform.clone();
+                let armed_alarm = armed_alarm.clone();
+                with_connection(logger, &calls, move |logger, conn| {
+                    clock_changed(logger, conn, &config, &*platform, &armed_alarm)
+                })
+                .await;
+            }
+        }
+    }
+}
+
+/// Passes the messages matching `rule` that arrive on `conn` to the event loop, as `kind`
+fn forward(
+    conn: &SyncConnection,
+    rule: MatchRule<'static>,
+    received: UnboundedSender<Received>,
+    kind: fn(Message) -> Received,
+) {
+    conn.start_receive(
+        rule,
+        Box::new(move |message, _| received.unbounded_send(kind(message)).is_ok()),
+    );
+}
+
+/// dbus has identical replies to requesting a name for its blocking and non-blocking connections, but the control
+/// interface checks the blocking ones
+fn blocking_reply(reply: NonblockRequestNameReply) -> RequestNameReply {
+    match reply {
+        NonblockRequestNameReply::PrimaryOwner => RequestNameReply::PrimaryOwner,
+        NonblockRequestNameReply::InQueue => RequestNameReply::InQueue,
+        NonblockRequestNameReply::Exists => RequestNameReply::Exists,
+        NonblockRequestNameReply::AlreadyOwner => RequestNameReply::AlreadyOwner,
+    }
+}
+
+fn lock_calls(calls: &Mutex<Connection>) -> Result<std::sync::MutexGuard<'_, Connection>> {
+    calls
+        .lock()
+        .map_err(|_| anyhow!("Mutex containing D-Bus connection was poisoned"))
+}
+
+/// Calls `f` on a blocking thread with `conn`, since the power monitor, control interface and timer lookups use the
+/// blocking D-Bus bindings. Returns what `f` does, or `None` if it fails.
+async fn with_connection<T, F>(logger: &Logger, conn: &Arc<Mutex<Connection>>, f: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Logger, &Connection) -> Result<T> + Send + 'static,
+{
+    let result = {
+        let logger = logger.clone();
+        let conn = conn.clone();
+        task::spawn_blocking(move || f(&logger, &*lock_calls(&conn)?)).await
+    };
+
+    match result {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(e)) => {
+            error!(&logger, "Could not handle event: {:?}", e);
+            None
+        }
+        Err(e) => {
+            error!(&logger, "Event handling task failed: {:?}", e);
+            None
+        }
+    }
+}
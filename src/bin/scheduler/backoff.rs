@@ -0,0 +1,33 @@
+//! Exponential backoff for reconnecting to the system bus
+use std::time::Duration;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Tracks how long to wait between repeated connection attempts
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    /// Returns how long to wait before the next attempt. The delay doubles each time, up to a minute.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_DELAY);
+        delay
+    }
+
+    /// Starts over from the initial delay, once a connection has succeeded
+    pub fn reset(&mut self) {
+        self.next = INITIAL_DELAY;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            next: INITIAL_DELAY,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! The scheduler's main loop, which polls the D-Bus connection and checks for Unix signals in between
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use dbus::blocking::Connection;
+use dbus::channel::{MatchingReceiver, Sender};
+use signal_hook;
+use slog::{warn, Logger};
+
+use night_kitchen::config::SchedulerConfig;
+use night_kitchen::dbus::scheduler::SCHEDULER_NAME;
+use night_kitchen::platform::Platform;
+use night_kitchen::power_monitor::PowerMonitor;
+
+use crate::backoff::Backoff;
+use crate::control::{self, NameUnavailable};
+use crate::{clock_changed, power_monitor, refresh_metrics, AlarmState, METRICS_INTERVAL};
+
+/// How often to check for SIGTERM while waiting to reconnect
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs the scheduler until it receives SIGTERM. If the system bus connection is lost, it reconnects and registers
+/// the power monitor again. Fails if the scheduler's bus name is unavailable.
+pub fn run(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: Arc<dyn Platform>,
+    armed_alarm: Arc<Mutex<AlarmState>>,
+) -> Result<()> {
+    let monitor = power_monitor(logger, config, platform.clone(), armed_alarm.clone());
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGTERM, shutdown.clone())
+        .context("Could not add SIGTERM hook")?;
+    let sighup = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGHUP, sighup.clone())
+        .context("Could not add SIGHUP hook")?;
+
+    let mut backoff = Backoff::default();
+    while !shutdown.load(Ordering::SeqCst) {
+        let conn = match connect(logger, config, &platform, &armed_alarm, &monitor) {
+            Ok(conn) => conn,
+            Err(e) if e.is::<NameUnavailable>() => return Err(e),
+            Err(e) => {
+                let delay = backoff.next_delay();
+                warn!(&logger, "Could not start monitoring power events, retrying in {:?}", delay; "error" => ?e);
+                wait(&shutdown, delay);
+                continue;
+            }
+        };
+        backoff.reset();
+
+        match process(
+            logger,
+            &conn,
+            config,
+            &*platform,
+            &armed_alarm,
+            &shutdown,
+            &sighup,
+        ) {
+            Ok(()) => (),
+            Err(e) => {
+                warn!(&logger, "Lost connection to system D-Bus, reconnecting"; "error" => ?e)
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Connects to the system bus, and registers `monitor` and the control interface on the new connection. Their
+/// handlers run while the connection processes messages.
+fn connect(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: &Arc<dyn Platform>,
+    armed_alarm: &Arc<Mutex<AlarmState>>,
+    monitor: &Arc<PowerMonitor>,
+) -> Result<Connection> {
+    let conn = Connection::new_system().context("Could not connect to system D-Bus")?;
+    PowerMonitor::register(&conn, monitor.clone())?;
+
+    control::claimed_name(conn.request_name(SCHEDULER_NAME, false, false, true))?;
+    let logger = logger.clone();
+    let config = config.clone();
+    let platform = platform.clone();
+    let armed_alarm = armed_alarm.clone();
+    conn.start_receive(
+        control::method_calls(),
+        Box::new(move |call, conn| {
+            let _ = conn.send(control::handle(
+                &logger,
+                conn,
+                &config,
+                &*platform,
+                &armed_alarm,
+                &call,
+            ));
+            true
+        }),
+    );
+    Ok(conn)
+}
+
+/// Handles D-Bus messages and clock changes, and refreshes metrics, until SIGTERM is received or the connection fails.
+fn process(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+    shutdown: &AtomicBool,
+    sighup: &AtomicBool,
+) -> Result<()> {
+    let mut next_metrics_refresh = Instant::now();
+    while !shutdown.load(Ordering::SeqCst) {
+        if Instant::now() >= next_metrics_refresh {
+            refresh_metrics(logger, conn, config, platform);
+            next_metrics_refresh = Instant::now() + METRICS_INTERVAL;
+        }
+
+        conn.process(Duration::from_secs(1))?;
+
+        if sighup.swap(false, Ordering::SeqCst) {
+            clock_changed(logger, conn, config, platform, armed_alarm)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sleeps for `delay`, returning early if SIGTERM is received.
+fn wait(shutdown: &AtomicBool, delay: Duration) {
+    let deadline = Instant::now() + delay;
+    while !shutdown.load(Ordering::SeqCst) && Instant::now() < deadline {
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+}
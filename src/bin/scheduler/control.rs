@@ -0,0 +1,412 @@
+//! The scheduler's control interface on the system bus, which lets the runner and administrators ask it about wakeups
+//! and change them. The interface itself is described in `night_kitchen::dbus::scheduler`.
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use dbus::strings::ErrorName;
+use dbus::Message;
+use slog::{debug, Logger};
+
+use night_kitchen::config::SchedulerConfig;
+use night_kitchen::dbus::scheduler::{
+    INTROSPECTION_XML, SCHEDULER_INTERFACE, SCHEDULER_NAME, SCHEDULER_PATH,
+};
+use night_kitchen::platform::Platform;
+use night_kitchen::power_monitor::Deadline;
+use night_kitchen::skips::Skips;
+use night_kitchen::time::{from_timestamp_usecs, to_timestamp_usecs};
+
+use crate::{
+    clear_skips, disarm, lock_alarm, next_wake_time, pause, read_wake_alarm, rearm,
+    schedule_wakeup, skip_next, AlarmState,
+};
+
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE_INTERFACE: &str = "org.freedesktop.DBus.Introspectable";
+const ACCESS_DENIED_ERROR: &str = "org.freedesktop.DBus.Error.AccessDenied";
+
+/// Matches the method calls to the control object, which [`handle`](fn.handle.html) answers
+pub fn method_calls() -> MatchRule<'static> {
+    MatchRule::new_method_call().with_path(SCHEDULER_PATH)
+}
+
+/// The scheduler couldn't claim its bus name, because another process owns it or the D-Bus policy doesn't allow it. The
+/// runner finds the scheduler by its name, so this isn't worth retrying.
+#[derive(Debug)]
+pub struct NameUnavailable(String);
+
+impl fmt::Display for NameUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NameUnavailable {}
+
+/// Checks the reply to requesting the scheduler's bus name without queueing for it. Failures to own the name are
+/// [`NameUnavailable`](struct.NameUnavailable.html) errors.
+pub fn claimed_name(reply: Result<RequestNameReply, dbus::Error>) -> Result<()> {
+    match reply {
+        Ok(RequestNameReply::PrimaryOwner) | Ok(RequestNameReply::AlreadyOwner) => Ok(()),
+        Ok(reply) => Err(NameUnavailable(format!(
+            "{} is already owned by another process ({:?})",
+            SCHEDULER_NAME, reply
+        ))
+        .into()),
+        Err(e) if e.name() == Some(ACCESS_DENIED_ERROR) => Err(NameUnavailable(format!(
+            "Not allowed to own {}, is the D-Bus policy installed? {}",
+            SCHEDULER_NAME,
+            e.message().unwrap_or_default()
+        ))
+        .into()),
+        Err(e) => Err(e).with_context(|| format!("Could not claim {}", SCHEDULER_NAME)),
+    }
+}
+
+/// Answers a method call to the control object. Timers are looked up over `conn`, which has to be a blocking connection
+/// to the system bus.
+pub fn handle(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+    call: &Message,
+) -> Message {
+    let interface = call.interface();
+    let member = call.member();
+    debug!(&logger, "Handling control call"; "interface" => ?interface, "member" => ?member, "sender" => ?call.sender());
+
+    match (interface.as_deref(), member.as_deref()) {
+        (Some(SCHEDULER_INTERFACE), Some("GetNextWake")) => {
+            let next_wake = next_wake_time(logger, conn, config, platform, &Deadline::none());
+            call.method_return()
+                .append1(next_wake.as_ref().map_or(0, to_timestamp_usecs))
+        }
+        (Some(SCHEDULER_INTERFACE), Some("ArmNow")) => {
+            schedule_wakeup(
+                logger,
+                conn,
+                config,
+                platform,
+                armed_alarm,
+                &Deadline::none(),
+            );
+            match read_wake_alarm(platform) {
+                Ok(alarm) => call
+                    .method_return()
+                    .append1(alarm.as_ref().map_or(0, to_timestamp_usecs)),
+                Err(e) => failed(call, e),
+            }
+        }
+        (Some(SCHEDULER_INTERFACE), Some("Disarm")) => {
+            match disarm(logger, platform, armed_alarm) {
+                Ok(()) => call.method_return(),
+                Err(e) => failed(call, e),
+            }
+        }
+        (Some(SCHEDULER_INTERFACE), Some("SkipNext")) => match call.read2::<&str, u32>() {
+            Ok((timer, count)) => match skip_next(logger, conn, config, platform, timer, count) {
+                Ok(elapses) => {
+                    rearm(logger, conn, config, platform, armed_alarm);
+                    call.method_return()
+                        .append1(elapses.iter().map(to_timestamp_usecs).collect::<Vec<u64>>())
+                }
+                Err(e) => failed(call, e),
+            },
+            Err(_) => invalid_args(call),
+        },
+        (Some(SCHEDULER_INTERFACE), Some("ClearSkips")) => match call.read1::<&str>() {
+            Ok(timer) => {
+                let timer = if timer.is_empty() { None } else { Some(timer) };
+                match clear_skips(logger, config, timer) {
+                    Ok(()) => {
+                        rearm(logger, conn, config, platform, armed_alarm);
+                        call.method_return()
+                    }
+                    Err(e) => failed(call, e),
+                }
+            }
+            Err(_) => invalid_args(call),
+        },
+        (Some(SCHEDULER_INTERFACE), Some("Pause")) => match call.read1::<u64>() {
+            Ok(until) => {
+                let until = if until == 0 {
+                    None
+                } else {
+                    Some(from_timestamp_usecs(until))
+                };
+                match pause(logger, config, platform, until) {
+                    Ok(()) => {
+                        rearm(logger, conn, config, platform, armed_alarm);
+                        call.method_return()
+                    }
+                    Err(e) => failed(call, e),
+                }
+            }
+            Err(_) => invalid_args(call),
+        },
+        (Some(PROPERTIES_INTERFACE), Some("Get")) => match call.read2::<&str, &str>() {
+            Ok((SCHEDULER_INTERFACE, name)) => match properties(config, platform, armed_alarm) {
+                Ok(mut properties) => match properties.remove(name) {
+                    Some(value) => call.method_return().append1(value),
+                    None => error_reply(
+                        call,
+                        "org.freedesktop.DBus.Error.UnknownProperty",
+                        &format!("Unknown property {}", name),
+                    ),
+                },
+                Err(e) => failed(call, e),
+            },
+            _ => invalid_args(call),
+        },
+        (Some(PROPERTIES_INTERFACE), Some("GetAll")) => match call.read1::<&str>() {
+            Ok(SCHEDULER_INTERFACE) => match properties(config, platform, armed_alarm) {
+                Ok(properties) => call.method_return().append1(properties),
+                Err(e) => failed(call, e),
+            },
+            _ => invalid_args(call),
+        },
+        (Some(INTROSPECTABLE_INTERFACE), Some("Introspect")) => {
+            call.method_return().append1(INTROSPECTION_XML)
+        }
+        _ => error_reply(
+            call,
+            "org.freedesktop.DBus.Error.UnknownMethod",
+            "Unknown method",
+        ),
+    }
+}
+
+/// The current values of the control interface's properties
+fn properties(
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+) -> Result<HashMap<String, Variant<Box<dyn RefArg>>>> {
+    let last_resume = lock_alarm(armed_alarm)?.last_resume;
+    let (time, cause) = match last_resume {
+        Some((time, cause)) => (to_timestamp_usecs(&time), cause.to_string()),
+        None => (0, String::new()),
+    };
+    let now = platform.now();
+    let paused_until = match Skips::load(&config.state_directory)?.paused_until {
+        Some(until) if until > now => to_timestamp_usecs(&until),
+        _ => 0,
+    };
+
+    let mut properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+    properties.insert("LastResume".to_string(), Variant(Box::new(time)));
+    properties.insert("LastWakeCause".to_string(), Variant(Box::new(cause)));
+    properties.insert("PausedUntil".to_string(), Variant(Box::new(paused_until)));
+    Ok(properties)
+}
+
+fn error_reply<E: ToString + ?Sized>(call: &Message, name: &'static str, error: &E) -> Message {
+    let description = CString::new(error.to_string()).unwrap_or_default();
+    call.error(&ErrorName::from(name), &description)
+}
+
+/// Replies to `call` with a generic failure, describing `error`
+pub fn failed(call: &Message, error: anyhow::Error) -> Message {
+    error_reply(
+        call,
+        "org.freedesktop.DBus.Error.Failed",
+        &format!("{:#}", error),
+    )
+}
+
+fn invalid_args(call: &Message) -> Message {
+    error_reply(
+        call,
+        "org.freedesktop.DBus.Error.InvalidArgs",
+        "Invalid arguments",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+    use night_kitchen::calendar::CalendarSpec;
+    use night_kitchen::dbus::scheduler::IoGithubNightKitchenScheduler;
+    use night_kitchen::dbus::testing::{FakeSystemd, FakeTimer, TestBus};
+    use night_kitchen::platform::SimulatedPlatform;
+    use slog::{o, Discard};
+    use tempfile::TempDir;
+
+    use dbus::channel::{MatchingReceiver, Sender};
+
+    use super::*;
+    use crate::resumed;
+
+    /// Claims the scheduler's name on `conn` and answers control calls there, as the event loops do
+    fn serve(
+        logger: &Logger,
+        conn: &Connection,
+        config: &SchedulerConfig,
+        platform: Arc<dyn Platform>,
+        armed_alarm: Arc<Mutex<AlarmState>>,
+    ) -> Result<()> {
+        claimed_name(conn.request_name(SCHEDULER_NAME, false, false, true))?;
+        let logger = logger.clone();
+        let config = config.clone();
+        conn.start_receive(
+            method_calls(),
+            Box::new(move |call, conn| {
+                let _ = conn.send(handle(
+                    &logger,
+                    conn,
+                    &config,
+                    &*platform,
+                    &armed_alarm,
+                    &call,
+                ));
+                true
+            }),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn answers_control_calls() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let daily = to_timestamp_usecs(&(Utc::now() + chrono::Duration::hours(6)));
+        let weekly = to_timestamp_usecs(&(Utc::now() + chrono::Duration::hours(30)));
+        let daily_calendar = "*-*-* 02:00:00 UTC";
+        for (unit, next_elapse, calendar) in &[
+            ("night-kitchen-daily.timer", daily, daily_calendar),
+            (
+                "night-kitchen-weekly.timer",
+                weekly,
+                "Mon *-*-* 03:00:00 UTC",
+            ),
+        ] {
+            systemd.add_timer(
+                *unit,
+                FakeTimer {
+                    next_elapse_usec_realtime: *next_elapse,
+                    timers_calendar: vec![("OnCalendar".to_string(), calendar.to_string(), 0)],
+                    ..FakeTimer::default()
+                },
+            );
+        }
+
+        let logger = Logger::root(Discard, o!());
+        let platform = Arc::new(SimulatedPlatform::new(Utc::now(), Utc::now()));
+        let armed_alarm = Arc::new(Mutex::new(AlarmState::default()));
+        let state = TempDir::new().unwrap();
+        let config = SchedulerConfig {
+            state_directory: state.path().to_path_buf(),
+            ..SchedulerConfig::default()
+        };
+        let server = bus.connect().unwrap();
+        serve(
+            &logger,
+            &server,
+            &config,
+            platform.clone(),
+            armed_alarm.clone(),
+        )
+        .unwrap();
+
+        // Serve from a thread, since the client's calls block
+        let client = bus.connect().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let serving = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    server.process(Duration::from_millis(10)).unwrap();
+                }
+            })
+        };
+        let proxy = client.with_proxy(SCHEDULER_NAME, SCHEDULER_PATH, Duration::from_secs(2));
+
+        let daily_spec = daily_calendar.parse::<CalendarSpec>().unwrap();
+        let after_daily = daily_spec
+            .next_elapse(&from_timestamp_usecs(daily))
+            .unwrap();
+        assert_eq!(proxy.get_next_wake().unwrap(), daily);
+        assert_eq!(
+            proxy.skip_next("night-kitchen-daily.timer", 1).unwrap(),
+            vec![daily]
+        );
+        assert_eq!(
+            proxy.skip_next("night-kitchen-daily.timer", 1).unwrap(),
+            vec![to_timestamp_usecs(&after_daily)]
+        );
+        assert_eq!(proxy.get_next_wake().unwrap(), weekly);
+        assert!(proxy.skip_next("shadow.timer", 1).is_err());
+        assert!(proxy.skip_next("night-kitchen-daily.timer", 0).is_err());
+        proxy.clear_skips("").unwrap();
+        assert_eq!(proxy.get_next_wake().unwrap(), daily);
+
+        // The daily timer is the first to activate once the pause ends, since the weekly one is at 03:00
+        let until = daily_spec
+            .next_elapse(&(Utc::now() + chrono::Duration::days(20)))
+            .unwrap();
+        proxy.pause(to_timestamp_usecs(&until)).unwrap();
+        assert_eq!(proxy.paused_until().unwrap(), to_timestamp_usecs(&until));
+        assert_eq!(proxy.get_next_wake().unwrap(), to_timestamp_usecs(&until));
+        proxy.pause(0).unwrap();
+        assert_eq!(proxy.paused_until().unwrap(), 0);
+        assert!(proxy
+            .pause(to_timestamp_usecs(
+                &(Utc::now() - chrono::Duration::hours(1))
+            ))
+            .is_err());
+
+        assert_eq!(proxy.last_resume().unwrap(), 0);
+        assert_eq!(proxy.last_wake_cause().unwrap(), "");
+        resumed(&logger, &*platform, &armed_alarm).unwrap();
+        assert!(
+            from_timestamp_usecs(proxy.last_resume().unwrap())
+                > Utc::now() - chrono::Duration::minutes(1)
+        );
+        assert_eq!(proxy.last_wake_cause().unwrap(), "other");
+        let all: HashMap<String, Variant<Box<dyn RefArg>>> =
+            Properties::get_all(&proxy, SCHEDULER_INTERFACE).unwrap();
+        assert_eq!(all.len(), 3);
+
+        stop.store(true, Ordering::SeqCst);
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn fails_if_name_is_taken() {
+        let bus = TestBus::start().unwrap();
+        let other = bus.connect().unwrap();
+        other
+            .request_name(SCHEDULER_NAME, false, false, true)
+            .unwrap();
+
+        let state = TempDir::new().unwrap();
+        let config = SchedulerConfig {
+            state_directory: state.path().to_path_buf(),
+            ..SchedulerConfig::default()
+        };
+        let err = serve(
+            &Logger::root(Discard, o!()),
+            &bus.connect().unwrap(),
+            &config,
+            Arc::new(SimulatedPlatform::new(Utc::now(), Utc::now())),
+            Arc::new(Mutex::new(AlarmState::default())),
+        )
+        .unwrap_err();
+        assert!(err.is::<NameUnavailable>());
+    }
+}
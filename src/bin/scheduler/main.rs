@@ -1,163 +1,646 @@
-#[macro_use]
-extern crate nix;
-
-use std::fs::File;
-use std::io::Write;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
-use anyhow::{anyhow, Context, Result};
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use dbus::blocking::Connection;
-use signal_hook;
 use slog::{debug, error, info, warn, Logger};
 
-mod power_monitor;
-mod rtcwake;
-mod time;
+#[cfg(feature = "async")]
+mod async_loop;
+mod backoff;
+#[cfg(not(feature = "async"))]
+mod blocking_loop;
+mod control;
+#[cfg(test)]
+mod simulation;
+
+use night_kitchen::activation::{
+    calendar_elapse_after, next_activation, ActivationWindow, Downtime, TIMER_UNITS,
+};
+use night_kitchen::boot::ShutdownRecord;
+use night_kitchen::config::{CancelPolicy, SchedulerConfig};
+use night_kitchen::dbus::scheduler::WakeCause;
+use night_kitchen::metrics::{self, SCHEDULER_METRICS_FILE};
+use night_kitchen::platform::{Platform, SystemPlatform};
+use night_kitchen::power_monitor::{Deadline, PowerEvent, PowerMonitor, ShutdownKind};
+use night_kitchen::root_logger;
+use night_kitchen::rtc::{self, AlarmDecision, RtcWakeAlarm};
+use night_kitchen::skips::Skips;
+use night_kitchen::time::reload_timezone;
+
+#[cfg(feature = "async")]
+use crate::async_loop::run;
+#[cfg(not(feature = "async"))]
+use crate::blocking_loop::run;
+
+/// How much time to leave for setting the RTC alarm. Some RTCs take up to a second to accept a new alarm, and being
+/// interrupted partway through could leave it in an inconsistent state.
+const RTC_WRITE_TIME: Duration = Duration::from_secs(1);
 
-use night_kitchen::dbus::systemd_timer::OrgFreedesktopSystemd1Timer;
-use night_kitchen::dbus::systemd_unit;
-use night_kitchen::{resume_timestamp_file, root_logger};
+/// How often to refresh the scheduler's metrics, if they're enabled
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
 
-use crate::power_monitor::{PowerEvent, PowerMonitor};
-use crate::rtcwake::Rtc;
-use crate::time::{from_timestamp_usecs, monotonic_to_realtime};
+/// How early the system can resume before a timer is due and still count as woken by it, to allow for the RTC and
+/// system clock disagreeing slightly
+const WAKE_TOLERANCE: Duration = Duration::from_secs(60);
 
-const TIMER_UNITS: &[&str] = &["night-kitchen-daily.timer", "night-kitchen-weekly.timer"];
+// The "who" and "why" of the scheduler's inhibitor lock
+const INHIBITOR_SOURCE: &str = night_kitchen::SCHEDULER_INHIBITOR_SOURCE;
+const INHIBITOR_REASON: &str = "Scheduling next system wakeup";
 
 fn main() -> Result<()> {
     let logger = root_logger();
 
-    let mut conn = Connection::new_system().context("Could not connect to system D-Bus")?;
-
-    let monitor = PowerMonitor::new(
-        logger.clone(),
-        "Night Kitchen Scheduler",
-        "Scheduling next system wakeup",
-        move |conn, ev| {
-            match ev {
-                PowerEvent::PostSleep => {
-                    if let Err(err) = update_resume_timestamp(&logger) {
-                        error!(&logger, "Could not update resume timestamp: {:?}", err);
-                    }
+    let config = SchedulerConfig::from_env().context("Invalid scheduler configuration")?;
+    info!(&logger, "Loaded configuration"; "wake_ahead" => ?config.wake_ahead, "min_lead_time" => ?config.min_lead_time, "wake_policy" => %config.wake_policy, "cancel_policy" => %config.cancel_policy, "metrics_directory" => ?config.metrics_directory);
+
+    let platform: Arc<dyn Platform> = Arc::new(SystemPlatform::new(config.rtc_interface));
+    let armed_alarm = Arc::new(Mutex::new(AlarmState::default()));
+
+    run(&logger, &config, platform, armed_alarm)
+}
+
+/// What the scheduler knows about waking the system, which its event handlers and control interface share
+#[derive(Debug, Default)]
+struct AlarmState {
+    /// The hardware clock time of the RTC alarm this scheduler last set, if any
+    armed: Option<NaiveDateTime>,
+
+    /// The alarm configuration and `armed` from before the scheduler set the alarm for a pending shutdown, so that they
+    /// can be restored if the shutdown is cancelled
+    before_shutdown: Option<(RtcWakeAlarm, Option<NaiveDateTime>)>,
+
+    /// When the first Night Kitchen timer was due as the system went to sleep
+    due_at_sleep: Option<DateTime<Utc>>,
+
+    /// When the system last resumed from sleep, and why
+    last_resume: Option<(DateTime<Utc>, WakeCause)>,
+}
+
+fn lock_alarm(armed_alarm: &Mutex<AlarmState>) -> Result<MutexGuard<'_, AlarmState>> {
+    armed_alarm
+        .lock()
+        .map_err(|_| anyhow!("Mutex containing armed alarm was poisoned"))
+}
+
+/// Whether this scheduler has set an RTC alarm that it may need to re-arm
+fn is_armed(armed_alarm: &Mutex<AlarmState>) -> Result<bool> {
+    Ok(lock_alarm(armed_alarm)?.armed.is_some())
+}
+
+/// Creates the scheduler's power monitor, which sets the wake alarm before the system shuts down and tracks whether a
+/// Night Kitchen timer woke it from sleep. Both event loops, and the simulation tests, share it.
+fn power_monitor(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: Arc<dyn Platform>,
+    armed_alarm: Arc<Mutex<AlarmState>>,
+) -> Arc<PowerMonitor> {
+    let monitor = PowerMonitor::new(logger.clone(), INHIBITOR_SOURCE, INHIBITOR_REASON);
+    {
+        let logger = logger.clone();
+        let config = config.clone();
+        let platform = platform.clone();
+        let armed_alarm = armed_alarm.clone();
+        monitor.subscribe("wake alarm", 0, move |conn, ev, deadline| match ev {
+            PowerEvent::PreShutdown(kind) => {
+                prepare_for_shutdown(
+                    &logger,
+                    conn,
+                    &config,
+                    &*platform,
+                    &armed_alarm,
+                    kind,
+                    deadline,
+                );
+            }
+            PowerEvent::ShutdownCancelled => {
+                if let Err(err) = shutdown_cancelled(&logger, &config, &*platform, &armed_alarm) {
+                    error!(&logger, "Could not handle cancelled shutdown: {:?}", err);
                 }
-                PowerEvent::PreShutdown => {
-                    // Find the soonest activation time across all night kitchen timers
-                    let alarm_time = TIMER_UNITS
-                        .iter()
-                        .map(|unit| next_activation(&logger, conn, unit))
-                        .fold(None, |acc, time| match (acc, time) {
-                            (_, Err(e)) => {
-                                warn!(&logger, "Could not get timer activation time: {:?}", e);
-                                acc
-                            }
-                            (None, Ok(time)) => Some(time),
-                            (Some(prev_time), Ok(time)) => Some(prev_time.min(time)),
-                        });
-
-                    if let Some(alarm_time) = alarm_time {
-                        info!(&logger, "Next timer activation is at {}", alarm_time);
-                        match set_wake_alarm(&logger, &alarm_time) {
-                            Ok(_) => info!(&logger, "Scheduled wake alarm"),
-                            Err(e) => error!(&logger, "Could not set wake alarm: {:?}", e),
-                        }
-                    }
+            }
+            _ => (),
+        });
+    }
+    {
+        let logger = logger.clone();
+        monitor.subscribe("wake cause", 0, move |conn, ev, deadline| {
+            let result = match ev {
+                PowerEvent::PreSleep => {
+                    prepare_for_sleep(&logger, conn, &*platform, &armed_alarm, deadline)
                 }
-                _ => (),
+                PowerEvent::PostSleep => resumed(&logger, &*platform, &armed_alarm),
+                _ => Ok(()),
             };
-        },
-    );
+            if let Err(err) = result {
+                error!(&logger, "Could not track wake cause: {:?}", err);
+            }
+        });
+    }
+    monitor
+}
 
-    PowerMonitor::register(&conn, monitor)?;
+/// Re-arms the wake alarm, if this scheduler set one, after the system clock or timezone changed.
+/// night-kitchen-rearm.service reloads the scheduler with SIGHUP whenever that happens.
+fn clock_changed(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+) -> Result<()> {
+    reload_timezone();
+    if is_armed(armed_alarm)? {
+        info!(&logger, "Clock or timezone changed, re-arming wake alarm");
+        schedule_wakeup(
+            logger,
+            conn,
+            config,
+            platform,
+            armed_alarm,
+            &Deadline::none(),
+        );
+    } else {
+        debug!(
+            &logger,
+            "Clock or timezone changed, but no wake alarm is armed"
+        );
+    }
+    Ok(())
+}
 
-    let shutdown = Arc::new(AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::SIGTERM, shutdown.clone())
-        .context("Could not add SIGTERM hook")?;
+/// Gets ready for the system to shut down. Unless the system is about to come straight back up, this sets the RTC wake
+/// alarm. Either way, the kind of shutdown is recorded for the runner.
+fn prepare_for_shutdown(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+    kind: ShutdownKind,
+    deadline: &Deadline,
+) {
+    if kind.is_reboot() {
+        info!(&logger, "System is rebooting, not setting wake alarm"; "kind" => %kind);
+    } else {
+        if config.cancel_policy == CancelPolicy::Revert {
+            if let Err(err) = remember_alarm(platform, armed_alarm) {
+                warn!(
+                    &logger,
+                    "Could not save wake alarm in case shutdown is cancelled: {:?}", err
+                );
+            }
+        }
+        schedule_wakeup(logger, conn, config, platform, armed_alarm, deadline);
+    }
 
-    while !shutdown.load(Ordering::SeqCst) {
-        conn.process(Duration::from_secs(1))?;
+    if let Err(err) = record_shutdown(logger, config, platform, kind) {
+        error!(&logger, "Could not record shutdown: {:?}", err);
     }
+}
 
+/// Saves the current RTC wake alarm, so that it can be restored if the pending shutdown is cancelled
+fn remember_alarm(platform: &dyn Platform, armed_alarm: &Mutex<AlarmState>) -> Result<()> {
+    let alarm = platform.rtc()?.alarm_configuration()?;
+    let mut state = lock_alarm(armed_alarm)?;
+    state.before_shutdown = Some((alarm, state.armed));
     Ok(())
 }
 
-fn set_wake_alarm(logger: &Logger, alarm_time: &DateTime<Utc>) -> Result<()> {
-    info!(&logger, "Setting RTC alarm for {}", alarm_time);
-    let rtc = Rtc::new()?;
-    let clock_mode = Rtc::read_clock_mode().context("Could not get hardware clock mode")?;
+/// Handles a pending shutdown being cancelled, by keeping or reverting the wake alarm set for it according to the
+/// configured `CancelPolicy`.
+fn shutdown_cancelled(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+) -> Result<()> {
+    let mut state = lock_alarm(armed_alarm)?;
+    let before_shutdown = state.before_shutdown.take();
+    if config.cancel_policy == CancelPolicy::Keep {
+        info!(&logger, "Shutdown cancelled, keeping wake alarm");
+        return Ok(());
+    }
 
-    let mut alarm_config = rtc.alarm_configuration()?;
-    if alarm_config.enabled() {
-        let current_alarm = clock_mode.to_datetime(&alarm_config.time());
-        if &current_alarm < alarm_time {
-            debug!(
+    let (previous, previously_armed, armed) = match (before_shutdown, state.armed) {
+        (Some((previous, previously_armed)), Some(armed)) => (previous, previously_armed, armed),
+        _ => {
+            info!(
                 &logger,
-                "Will not override earlier alarm at {}", current_alarm
+                "Shutdown cancelled, but no wake alarm was set for it"
             );
-        } else {
-            debug!(&logger, "Overriding later alarm at {}", current_alarm);
-            alarm_config.set_time(&clock_mode.to_hardware(alarm_time));
+            return Ok(());
         }
+    };
+    let rtc = platform.rtc()?;
+    if rtc::restore_wake_alarm(&*rtc, &previous, armed)? {
+        state.armed = previously_armed;
+        info!(&logger, "Shutdown cancelled, restored {}", previous);
     } else {
-        debug!(&logger, "No previous alarm set");
-        alarm_config.set_enabled(true);
-        alarm_config.set_time(&clock_mode.to_hardware(alarm_time));
+        state.armed = None;
+        warn!(
+            &logger,
+            "Shutdown cancelled, but the wake alarm was changed since it was set, leaving it"
+        );
     }
-
-    rtc.set_alarm_configuration(&alarm_config)?;
-
     Ok(())
 }
 
-fn update_resume_timestamp(logger: &Logger) -> Result<()> {
-    let timestamp = Utc::now();
-    let timestamp_file = resume_timestamp_file();
-    debug!(&logger, "Writing resume timestamp"; "timestamp" => %timestamp, "file" => %timestamp_file.display());
+/// Sets the RTC wake alarm for the soonest activation time across all Night Kitchen timers. Timers are only looked
+/// up, and the alarm only set, if that can be done before `deadline`.
+fn schedule_wakeup(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+    deadline: &Deadline,
+) {
+    let alarm_time = next_wake_time(logger, conn, config, platform, deadline);
+    if let Some(alarm_time) = alarm_time {
+        info!(
+            &logger,
+            "Next timer activation window calls for waking at {}", alarm_time
+        );
+        if !deadline.allows(RTC_WRITE_TIME) {
+            error!(
+                &logger,
+                "Not enough time left before logind stops waiting, not setting wake alarm"
+            );
+            return;
+        }
+        match set_wake_alarm(logger, config, platform, &alarm_time, armed_alarm) {
+            Ok(_) => (),
+            Err(e) => error!(&logger, "Could not set wake alarm: {:?}", e),
+        }
+    }
+    export_metrics(logger, config, platform, alarm_time);
+}
+
+/// Looks up the next activation window of each Night Kitchen timer once the system goes down the way `downtime` says,
+/// as many as possible before `deadline`
+fn next_activations(
+    logger: &Logger,
+    conn: &Connection,
+    platform: &dyn Platform,
+    downtime: Downtime,
+    deadline: &Deadline,
+) -> Vec<(&'static str, ActivationWindow)> {
+    let now = platform.now();
+    TIMER_UNITS
+        .iter()
+        .take_while(|unit| {
+            if deadline.is_expired() {
+                warn!(&logger, "Out of time to look up timers, skipping the rest"; "unit" => unit);
+                false
+            } else {
+                true
+            }
+        })
+        .filter_map(
+            |unit| match next_activation(logger, conn, unit, &now, downtime, deadline) {
+                Ok(window) => window.map(|window| (*unit, window)),
+                Err(e) => {
+                    warn!(&logger, "Could not get timer activation time: {:?}", e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
 
-    let mut f = File::create(&timestamp_file)
-        .with_context(|| format!("Could not create {}", timestamp_file.display()))?;
-    // Write as a string for debuggability
-    write!(&mut f, "{}", timestamp.timestamp_millis())
-        .context("Could not write to timestamp file")?;
+/// Finds the soonest time any Night Kitchen timer calls for waking the system, leaving out skipped activations and
+/// any before a pause ends
+fn next_wake_time(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    deadline: &Deadline,
+) -> Option<DateTime<Utc>> {
+    let skips = Skips::load(&config.state_directory).unwrap_or_else(|e| {
+        error!(&logger, "Could not check for skipped activations: {:?}", e);
+        Skips::default()
+    });
 
-    Ok(())
+    next_activations(logger, conn, platform, Downtime::Shutdown, deadline)
+        .into_iter()
+        .filter_map(|(unit, window)| first_unskipped(logger, conn, &skips, unit, window, deadline))
+        .map(|window| window.wake_time(config.wake_policy))
+        .min()
 }
 
-fn next_activation(logger: &Logger, conn: &Connection, timer_unit: &str) -> Result<DateTime<Utc>> {
-    let timer = systemd_unit(conn, timer_unit)?;
+/// Moves `window` past any skipped activations of `unit`, and any before a pause ends. Returns `None` if there are no
+/// later activations, or they can't be worked out before `deadline`.
+fn first_unskipped(
+    logger: &Logger,
+    conn: &Connection,
+    skips: &Skips,
+    unit: &str,
+    mut window: ActivationWindow,
+    deadline: &Deadline,
+) -> Option<ActivationWindow> {
+    // Each step passes either a skipped activation or the whole pause
+    let max_steps = skips.activations.get(unit).map_or(0, Vec::len) + 1;
+    for _ in 0..=max_steps {
+        if !skips.skips(unit, &window.elapse) {
+            return Some(window);
+        }
+        debug!(&logger, "Skipping timer activation"; "unit" => unit, "elapse" => %window.elapse);
+        if deadline.is_expired() {
+            warn!(&logger, "Out of time to find an activation that isn't skipped"; "unit" => unit);
+            return None;
+        }
 
-    // If either is 0, that means the timer doesn't include any events using the corresponding clock
-    let next_realtime = match timer
-        .next_elapse_usec_realtime()
-        .context("Could not get next CLOCK_REALTIME elapsation point")?
-    {
-        0 => None,
-        realtime_usecs => {
-            let next_realtime = from_timestamp_usecs(realtime_usecs);
-            debug!(&logger, "Next CLOCK_REALTIME elapsation point is {}", next_realtime; "unit" => timer_unit);
-            Some(next_realtime)
+        // Jump straight to the end of a pause rather than stepping through every activation during it
+        let after = match skips.paused_until {
+            Some(until) if until > window.elapse => until - chrono::Duration::seconds(1),
+            _ => window.elapse,
+        };
+        match calendar_elapse_after(logger, conn, unit, &after, deadline) {
+            Ok(Some(elapse)) => window = window.moved_to(elapse),
+            Ok(None) => {
+                info!(&logger, "Timer has no activations after skipped ones"; "unit" => unit);
+                return None;
+            }
+            Err(e) => {
+                warn!(&logger, "Could not find activation after skipped one: {:?}", e; "unit" => unit);
+                return None;
+            }
         }
+    }
+    warn!(&logger, "Could not find an activation that isn't skipped"; "unit" => unit);
+    None
+}
+
+/// Looks up when the system should next be woken up and writes the scheduler's metrics, if a metrics directory is
+/// configured. This keeps them current while the system is up, since timers move on after each run.
+fn refresh_metrics(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+) {
+    if config.metrics_directory.is_some() {
+        let next_wake = next_wake_time(logger, conn, config, platform, &Deadline::none());
+        export_metrics(logger, config, platform, next_wake);
+    }
+}
+
+/// Writes the scheduler's metrics for node_exporter, if a metrics directory is configured
+fn export_metrics(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    next_wake: Option<DateTime<Utc>>,
+) {
+    let dir = match &config.metrics_directory {
+        Some(dir) => dir,
+        None => return,
     };
+    let result = read_wake_alarm(platform).and_then(|rtc_alarm| {
+        metrics::write_textfile(
+            dir,
+            SCHEDULER_METRICS_FILE,
+            &metrics::scheduler_metrics(next_wake, rtc_alarm),
+        )
+    });
+    if let Err(err) = result {
+        warn!(&logger, "Could not export metrics: {:?}", err);
+    }
+}
 
-    let next_monotonic = match timer
-        .next_elapse_usec_monotonic()
-        .context("Could not get next monotonic elapsation point")?
-    {
-        0 => None,
-        monotonic_usecs => {
-            let next_monotonic = monotonic_to_realtime(from_timestamp_usecs(monotonic_usecs));
-            debug!(&logger, "Next CLOCK_MONOTONIC elapsation point is {}", next_monotonic; "unit" => timer_unit);
-            Some(next_monotonic)
+/// Reads the time the RTC wake alarm is set for, if it's enabled
+fn read_wake_alarm(platform: &dyn Platform) -> Result<Option<DateTime<Utc>>> {
+    let alarm = platform.rtc()?.alarm_configuration()?;
+    if !alarm.enabled() {
+        return Ok(None);
+    }
+    Ok(Some(platform.adjtime()?.to_datetime(&alarm.time())))
+}
+
+/// Sets the RTC wake alarm for a timer that next elapses at `elapse_time`. `armed_alarm` records the alarm this
+/// scheduler last set, which it's always free to replace.
+fn set_wake_alarm(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    elapse_time: &DateTime<Utc>,
+    armed_alarm: &Mutex<AlarmState>,
+) -> Result<()> {
+    let rtc = platform.rtc()?;
+    let adjtime = platform.adjtime()?;
+    let mut state = lock_alarm(armed_alarm)?;
+
+    match rtc::arm_wake_alarm(
+        &*rtc,
+        &adjtime,
+        config,
+        platform.now(),
+        elapse_time,
+        &mut state.armed,
+    )? {
+        AlarmDecision::Set { time, replaced } => {
+            if &time > elapse_time {
+                warn!(
+                    &logger,
+                    "Timer elapses within the minimum lead time, pushed RTC alarm back to {}", time
+                );
+            }
+            if let Some(replaced) = replaced {
+                debug!(&logger, "Replaced previous alarm at {}", replaced);
+            }
+            info!(&logger, "Scheduled wake alarm for {}", time);
         }
-    };
+        AlarmDecision::KeptEarlier(existing) => {
+            info!(&logger, "Will not override earlier alarm at {}", existing);
+        }
+        AlarmDecision::Skipped => {
+            warn!(
+                &logger,
+                "Timer already elapsed at {}, not setting RTC alarm", elapse_time
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Notes when the first Night Kitchen timer is due as the system goes to sleep, so that once it resumes the scheduler
+/// can tell whether that timer woke it. systemd wakes the system for these timers as soon as they elapse.
+fn prepare_for_sleep(
+    logger: &Logger,
+    conn: &Connection,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+    deadline: &Deadline,
+) -> Result<()> {
+    let due = next_activations(logger, conn, platform, Downtime::Suspend, deadline)
+        .into_iter()
+        .map(|(_, window)| window.start)
+        .min();
+    debug!(&logger, "Going to sleep"; "timer_due" => ?due);
+    lock_alarm(armed_alarm)?.due_at_sleep = due;
+    Ok(())
+}
 
-    let next_elapse = match (next_realtime, next_monotonic) {
-        (_, None) => next_realtime,
-        (None, _) => next_monotonic,
-        (Some(next_realtime), Some(next_monotonic)) => Some(next_realtime.min(next_monotonic)),
+/// Records that the system resumed, and whether a Night Kitchen timer woke it, for the runner to query
+fn resumed(
+    logger: &Logger,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+) -> Result<()> {
+    let now = platform.now();
+    let mut state = lock_alarm(armed_alarm)?;
+    let tolerance = chrono::Duration::from_std(WAKE_TOLERANCE)?;
+    let cause = match state.due_at_sleep.take() {
+        Some(due) if now + tolerance >= due => WakeCause::Timer,
+        _ => WakeCause::Other,
     };
+    info!(&logger, "Resumed from sleep"; "time" => %now, "cause" => %cause);
+    state.last_resume = Some((now, cause));
+    Ok(())
+}
+
+/// Skips the next `count` activations of `timer` that aren't skipped already, returning when they elapse
+fn skip_next(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    timer: &str,
+    count: u32,
+) -> Result<Vec<DateTime<Utc>>> {
+    check_timer(timer)?;
+    if count == 0 {
+        bail!("Nothing to skip");
+    }
+    let next = next_activation(
+        logger,
+        conn,
+        timer,
+        &platform.now(),
+        Downtime::Shutdown,
+        &Deadline::none(),
+    )?
+    .ok_or_else(|| anyhow!("{} has no upcoming activation to skip", timer))?
+    .elapse;
 
-    next_elapse.ok_or_else(|| anyhow!("Neither monotonic nor realtime next elapsation point"))
+    Skips::update(&config.state_directory, |skips| {
+        let mut elapses = Vec::new();
+        let mut elapse = Some(next);
+        while elapses.len() < count as usize {
+            let current = match elapse {
+                Some(current) => current,
+                None => bail!("{} only has {} more activations", timer, elapses.len()),
+            };
+            if !skips.is_skipped(timer, &current) {
+                elapses.push(current);
+            }
+            elapse = calendar_elapse_after(logger, conn, timer, &current, &Deadline::none())?;
+        }
+
+        for elapse in &elapses {
+            info!(&logger, "Skipping timer activation"; "unit" => timer, "elapse" => %elapse);
+            skips.skip(timer, *elapse);
+        }
+        Ok(elapses)
+    })
+}
+
+/// Stops skipping activations of `timer`, or of every timer if it's `None`
+fn clear_skips(logger: &Logger, config: &SchedulerConfig, timer: Option<&str>) -> Result<()> {
+    if let Some(timer) = timer {
+        check_timer(timer)?;
+    }
+    Skips::update(&config.state_directory, |skips| {
+        skips.clear(timer);
+        Ok(())
+    })?;
+    info!(&logger, "Cleared skipped activations"; "unit" => timer);
+    Ok(())
+}
+
+/// Pauses Night Kitchen until `until`, or ends the pause if it's `None`
+fn pause(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    until: Option<DateTime<Utc>>,
+) -> Result<()> {
+    if let Some(until) = until {
+        if until <= platform.now() {
+            bail!("Pause would already have ended at {}", until);
+        }
+    }
+    Skips::update(&config.state_directory, |skips| {
+        skips.paused_until = until;
+        Ok(())
+    })?;
+    match until {
+        Some(until) => info!(&logger, "Paused until {}", until),
+        None => info!(&logger, "Ended pause"),
+    }
+    Ok(())
+}
+
+fn check_timer(timer: &str) -> Result<()> {
+    if !TIMER_UNITS.contains(&timer) {
+        bail!("{} is not a Night Kitchen timer", timer);
+    }
+    Ok(())
+}
+
+/// Sets the wake alarm again if this scheduler has set one, so that it reflects a change to what's skipped
+fn rearm(
+    logger: &Logger,
+    conn: &Connection,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    armed_alarm: &Mutex<AlarmState>,
+) {
+    match is_armed(armed_alarm) {
+        Ok(true) => schedule_wakeup(
+            logger,
+            conn,
+            config,
+            platform,
+            armed_alarm,
+            &Deadline::none(),
+        ),
+        Ok(false) => (),
+        Err(e) => error!(
+            &logger,
+            "Could not check whether the wake alarm is set: {:?}", e
+        ),
+    }
+}
+
+/// Disables the RTC wake alarm, whether or not this scheduler set it
+fn disarm(logger: &Logger, platform: &dyn Platform, armed_alarm: &Mutex<AlarmState>) -> Result<()> {
+    let rtc = platform.rtc()?;
+    let mut state = lock_alarm(armed_alarm)?;
+    let mut alarm = rtc.alarm_configuration()?;
+    alarm.set_enabled(false);
+    rtc.set_alarm_configuration(&alarm)?;
+    state.armed = None;
+    info!(&logger, "Disabled wake alarm");
+    Ok(())
+}
+
+/// Records the kind of shutdown in the state directory, so that the runner can tell whether the system came back up on
+/// its own after a reboot
+fn record_shutdown(
+    logger: &Logger,
+    config: &SchedulerConfig,
+    platform: &dyn Platform,
+    kind: ShutdownKind,
+) -> Result<()> {
+    let record = ShutdownRecord {
+        kind,
+        time: platform.now(),
+    };
+    debug!(&logger, "Recording shutdown"; "kind" => %kind, "timestamp" => %record.time, "directory" => %config.state_directory.display());
+    record.write(&config.state_directory)
 }
@@ -0,0 +1,190 @@
+//! Runs the scheduler and the runner through whole shutdowns and wakeups, against a simulated clock and RTC and a fake
+//! logind and systemd on a private bus
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use dbus::blocking::Connection;
+use slog::{o, Discard, Logger};
+use tempfile::TempDir;
+
+use night_kitchen::boot::ShutdownRecord;
+use night_kitchen::config::SchedulerConfig;
+use night_kitchen::dbus::testing::{process_until, FakeLogind, FakeSystemd, FakeTimer, TestBus};
+use night_kitchen::history::{History, PowerAction, RunCause, RunRecord};
+use night_kitchen::platform::{Platform, SimulatedPlatform};
+use night_kitchen::power_monitor::{PowerMonitor, ShutdownKind};
+use night_kitchen::runner;
+use night_kitchen::skips::Skips;
+use night_kitchen::time::to_timestamp_usecs;
+
+use crate::{power_monitor, AlarmState};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const DAILY_TIMER: &str = "night-kitchen-daily.timer";
+const DAILY_TARGET: &str = "night-kitchen-daily.target";
+
+/// A scheduler serving a private bus with fake logind and systemd, on a simulated system that has been up since
+/// 08:00 and is about to shut down at 22:00, four hours before the daily timer elapses
+struct Simulation {
+    logger: Logger,
+    state: TempDir,
+    platform: Arc<SimulatedPlatform>,
+    logind: FakeLogind,
+    systemd: FakeSystemd,
+    scheduler: Connection,
+    bus: TestBus,
+}
+
+impl Simulation {
+    fn start() -> Simulation {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        systemd.add_timer(
+            DAILY_TIMER,
+            FakeTimer {
+                next_elapse_usec_realtime: to_timestamp_usecs(&daily_elapse()),
+                persistent: true,
+                wake_system: true,
+                timers_calendar: vec![(
+                    "OnCalendar".to_string(),
+                    "*-*-* 02:00:00 UTC".to_string(),
+                    0,
+                )],
+                ..FakeTimer::default()
+            },
+        );
+
+        let logger = Logger::root(Discard, o!());
+        let state = TempDir::new().unwrap();
+        let config = SchedulerConfig {
+            wake_ahead: Duration::from_secs(120),
+            state_directory: state.path().to_path_buf(),
+            ..SchedulerConfig::default()
+        };
+        let platform = Arc::new(SimulatedPlatform::new(
+            Utc.ymd(2020, 3, 1).and_hms(8, 0, 0),
+            Utc.ymd(2020, 3, 1).and_hms(22, 0, 0),
+        ));
+
+        let monitor = power_monitor(
+            &logger,
+            &config,
+            platform.clone(),
+            Arc::new(Mutex::new(AlarmState::default())),
+        );
+        let scheduler = bus.connect().unwrap();
+        PowerMonitor::register(&scheduler, monitor).unwrap();
+
+        Simulation {
+            logger,
+            state,
+            platform,
+            logind,
+            systemd,
+            scheduler,
+            bus,
+        }
+    }
+
+    /// Has logind announce a shutdown of type `kind`, and waits for the scheduler to get ready for it
+    fn shut_down(&self, kind: &str) {
+        self.logind.set_shutdown_type(Some(kind));
+        self.logind.prepare_for_shutdown(true);
+        process_until(&self.scheduler, TIMEOUT, || {
+            self.logind.held_inhibitors() == 0
+                && ShutdownRecord::read(self.state.path()).unwrap().is_some()
+        })
+        .unwrap();
+    }
+
+    /// Runs the runner as the daily timer would start it. The scheduler's control interface isn't served, so the runner
+    /// can't find out about resumes, but nothing here suspends the system anyway.
+    fn run_daily(&self) -> RunRecord {
+        let mut conn = self.bus.connect().unwrap();
+        runner::run(
+            &self.logger,
+            &mut conn,
+            &*self.platform,
+            self.state.path(),
+            DAILY_TARGET,
+            Some(DAILY_TIMER),
+            self.platform.now(),
+        )
+        .unwrap()
+    }
+}
+
+fn daily_elapse() -> DateTime<Utc> {
+    Utc.ymd(2020, 3, 2).and_hms(2, 0, 0)
+}
+
+#[test]
+fn wakes_for_timer_and_powers_off_again() {
+    let simulation = Simulation::start();
+
+    simulation.shut_down("poweroff");
+    let wake = daily_elapse() - chrono::Duration::minutes(2);
+    let alarm = simulation.platform.fake_rtc().alarm();
+    assert!(alarm.enabled());
+    assert_eq!(alarm.time(), wake.naive_utc());
+    assert_eq!(
+        ShutdownRecord::read(simulation.state.path()).unwrap(),
+        Some(ShutdownRecord {
+            kind: ShutdownKind::PowerOff,
+            time: Utc.ymd(2020, 3, 1).and_hms(22, 0, 0),
+        })
+    );
+
+    assert_eq!(simulation.platform.boot_at_alarm().unwrap(), wake);
+    simulation.platform.advance(chrono::Duration::minutes(2));
+    let record = simulation.run_daily();
+    assert_eq!(record.cause, RunCause::Boot);
+    assert_eq!(simulation.systemd.started_units(), vec![DAILY_TARGET]);
+    assert_eq!(record.units.len(), 1);
+    assert_eq!(record.power_action, PowerAction::PowerOff);
+    assert_eq!(simulation.logind.power_off_calls(), 1);
+}
+
+#[test]
+fn leaves_system_up_after_reboot() {
+    let simulation = Simulation::start();
+
+    simulation.shut_down("reboot");
+    assert!(!simulation.platform.fake_rtc().alarm().enabled());
+    assert!(simulation.platform.boot_at_alarm().is_err());
+
+    // The system comes straight back up, just as a timer starts the runner
+    simulation
+        .platform
+        .boot_at(Utc.ymd(2020, 3, 1).and_hms(22, 1, 0));
+    simulation.platform.advance(chrono::Duration::minutes(1));
+    let record = simulation.run_daily();
+    assert_eq!(record.cause, RunCause::None);
+    assert_eq!(record.power_action, PowerAction::None);
+    assert_eq!(simulation.logind.power_off_calls(), 0);
+}
+
+#[test]
+fn records_skipped_activation() {
+    let simulation = Simulation::start();
+    Skips::update(simulation.state.path(), |skips| {
+        skips.skip(DAILY_TIMER, daily_elapse());
+        Ok(())
+    })
+    .unwrap();
+
+    // The system stays up, and the daily timer starts the runner 20 minutes after it elapses
+    simulation.platform.advance(chrono::Duration::minutes(260));
+    let record = simulation.run_daily();
+    assert!(record.skipped.is_some());
+    assert!(simulation.systemd.started_units().is_empty());
+    assert_eq!(
+        History::open(simulation.state.path()).runs().unwrap(),
+        vec![record]
+    );
+    assert!(!Skips::load(simulation.state.path())
+        .unwrap()
+        .is_skipped(DAILY_TIMER, &daily_elapse()));
+}
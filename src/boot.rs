@@ -0,0 +1,143 @@
+//! Works out whether Night Kitchen powered the system on.
+//!
+//! The scheduler records how the system last shut down in the [state directory](../fn.state_directory.html). If a
+//! runner starts soon after boot, and the system didn't just reboot, Night Kitchen's wake alarm most likely brought it
+//! up, so the runner powers it off again afterwards.
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use slog::{debug, error, info, Logger};
+
+use crate::platform::Platform;
+use crate::power_monitor::ShutdownKind;
+
+const SHUTDOWN_FILE: &str = "last-shutdown";
+
+/// This is the shortest uptime for which Night Kitchen will not hold itself responsible for booting. If the uptime when
+/// the runner starts is any less than this, the runner will shut the system down afterwards.
+pub const MIN_INNOCENT_UPTIME: Duration = Duration::from_secs(300);
+
+/// This is the longest a reboot is expected to take. If the system booted within this long of the scheduler recording
+/// a reboot, that reboot is what brought the system up, rather than Night Kitchen.
+pub const MAX_REBOOT_TIME: Duration = Duration::from_secs(600);
+
+/// How and when the system last shut down
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ShutdownRecord {
+    pub kind: ShutdownKind,
+    pub time: DateTime<Utc>,
+}
+
+impl ShutdownRecord {
+    /// Records the shutdown in `dir`, replacing the previous one
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(SHUTDOWN_FILE);
+        fs::write(
+            &path,
+            format!("{} {}", self.kind, self.time.timestamp_millis()),
+        )
+        .with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    /// Reads the last shutdown recorded in `dir`. There is none if the system has never shut down with the scheduler
+    /// running.
+    pub fn read(dir: &Path) -> Result<Option<ShutdownRecord>> {
+        let path = dir.join(SHUTDOWN_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+
+        let mut fields = contents.split_whitespace();
+        let kind = fields.next().and_then(|kind| kind.parse().ok());
+        let timestamp_ms = fields.next().and_then(|ts| ts.parse().ok());
+        match (kind, timestamp_ms) {
+            (Some(kind), Some(timestamp_ms)) => Ok(Some(ShutdownRecord {
+                kind,
+                time: Utc.timestamp_millis(timestamp_ms),
+            })),
+            _ => Err(anyhow!("Shutdown record was corrupted: {:?}", contents)),
+        }
+    }
+}
+
+/// Returns `true` if Night Kitchen was most likely responsible for the system booting, going by the shutdown recorded
+/// in `state_dir`. This uses the current uptime as a heuristic, so it must be called early on. Boots that directly
+/// follow a reboot are never Night Kitchen's doing.
+pub fn caused_boot(logger: &Logger, platform: &dyn Platform, state_dir: &Path) -> bool {
+    let uptime = match platform.uptime() {
+        Ok(uptime) => uptime,
+        Err(err) => {
+            error!(&logger, "Could not determine uptime"; "error" => ?err);
+            return false;
+        }
+    };
+    debug!(&logger, "Uptime is {:?}", uptime);
+    if uptime >= MIN_INNOCENT_UPTIME {
+        return false;
+    }
+
+    let last_shutdown = ShutdownRecord::read(state_dir).unwrap_or_else(|err| {
+        error!(&logger, "Could not read last shutdown: {:?}", err);
+        None
+    });
+    match last_shutdown {
+        Some(ShutdownRecord { kind, time }) if kind.is_reboot() => {
+            let boot_time = platform.now()
+                - chrono::Duration::from_std(uptime).unwrap_or_else(|_| chrono::Duration::zero());
+            match (boot_time - time).to_std() {
+                Ok(reboot_time) if reboot_time < MAX_REBOOT_TIME => {
+                    info!(&logger, "System booted from a reboot"; "kind" => %kind, "shutdown_time" => %time);
+                    false
+                }
+                _ => true,
+            }
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::{o, Discard};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::platform::SimulatedPlatform;
+
+    #[test]
+    fn blames_boots_on_wake_alarm_but_not_reboots() {
+        let logger = Logger::root(Discard, o!());
+        let dir = TempDir::new().unwrap();
+        let shutdown = Utc.ymd(2020, 3, 1).and_hms(22, 0, 0);
+        let platform = SimulatedPlatform::new(shutdown, shutdown);
+        assert_eq!(ShutdownRecord::read(dir.path()).unwrap(), None);
+
+        let record = ShutdownRecord {
+            kind: ShutdownKind::Reboot,
+            time: shutdown,
+        };
+        record.write(dir.path()).unwrap();
+        assert_eq!(ShutdownRecord::read(dir.path()).unwrap(), Some(record));
+        platform.boot_at(shutdown + chrono::Duration::minutes(1));
+        assert!(!caused_boot(&logger, &platform, dir.path()));
+
+        ShutdownRecord {
+            kind: ShutdownKind::PowerOff,
+            ..record
+        }
+        .write(dir.path())
+        .unwrap();
+        assert!(caused_boot(&logger, &platform, dir.path()));
+        platform.advance(chrono::Duration::minutes(10));
+        assert!(!caused_boot(&logger, &platform, dir.path()));
+
+        fs::write(dir.path().join(SHUTDOWN_FILE), "poweroff").unwrap();
+        assert!(ShutdownRecord::read(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,525 @@
+//! Evaluator for systemd calendar event expressions, as used by `OnCalendar=`.
+//!
+//! This is used to work out when a timer will next elapse without relying on systemd, which may not report it
+//! correctly once shutdown has started. It supports the syntax systemd uses when normalizing expressions (which is what
+//! the `TimersCalendar` D-Bus property contains), including weekday lists, ranges (`..`) and repetitions (`/`), as well
+//! as the shorthands like `daily` and `weekly`. Day-of-month counting from the end (`~`) and timezones other than UTC
+//! and the local timezone are not supported.
+//!
+//! See [`man:systemd.time(7)`](https://www.freedesktop.org/software/systemd/man/systemd.time.html#Calendar%20Events)
+//! for the full syntax.
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{
+    DateTime, Datelike, Duration, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime,
+    TimeZone, Timelike, Utc,
+};
+
+use crate::time::local_to_utc;
+
+/// The latest year that will be searched for the next occurrence of an event
+const MAX_YEAR: i32 = 2200;
+
+/// The abbreviated and full names systemd accepts for each weekday, starting with Monday
+const WEEKDAY_NAMES: &[(&str, &str)] = &[
+    ("mon", "monday"),
+    ("tue", "tuesday"),
+    ("wed", "wednesday"),
+    ("thu", "thursday"),
+    ("fri", "friday"),
+    ("sat", "saturday"),
+    ("sun", "sunday"),
+];
+
+/// A parsed calendar event expression
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CalendarSpec {
+    /// Bitmask of matching weekdays, where bit 0 is Monday
+    weekdays: u8,
+    years: Component,
+    months: Component,
+    days: Component,
+    hours: Component,
+    minutes: Component,
+    seconds: Component,
+    utc: bool,
+}
+
+/// One field of a calendar event, such as the month or the hour. An empty list of ranges matches any value.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Component(Vec<ValueRange>);
+
+/// A range of values with an optional repetition, such as `1..5` or `0/15`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct ValueRange {
+    start: u32,
+    end: u32,
+    step: u32,
+}
+
+impl ValueRange {
+    fn matches(&self, value: u32) -> bool {
+        (self.start..=self.end)
+            .step_by(self.step as usize)
+            .any(|v| v == value)
+    }
+}
+
+impl Component {
+    /// Parses a component, where values must be between `min` and `max`
+    fn parse(s: &str, min: u32, max: u32) -> Result<Component> {
+        if s == "*" {
+            return Ok(Component(Vec::new()));
+        }
+
+        let mut ranges = Vec::new();
+        for item in s.split(',') {
+            let (range, step) = match item.find('/') {
+                Some(idx) => (
+                    &item[..idx],
+                    Some(item[idx + 1..].parse::<u32>().with_context(|| {
+                        format!("Invalid repetition in calendar component {}", s)
+                    })?),
+                ),
+                None => (item, None),
+            };
+
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some(idx) = range.find("..") {
+                (
+                    parse_value(&range[..idx], min, max)?,
+                    parse_value(&range[idx + 2..], min, max)?,
+                )
+            } else {
+                let value = parse_value(range, min, max)?;
+                // A repetition without a range end continues until the maximum value
+                (value, if step.is_some() { max } else { value })
+            };
+
+            let step = step.unwrap_or(1);
+            if step == 0 || start > end {
+                bail!("Invalid calendar component {}", s);
+            }
+            ranges.push(ValueRange { start, end, step });
+        }
+
+        Ok(Component(ranges))
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.0.is_empty() || self.0.iter().any(|range| range.matches(value))
+    }
+
+    /// Finds the smallest matching value that's at least `from` and at most `max`
+    fn next_match(&self, from: u32, max: u32) -> Option<u32> {
+        (from..=max).find(|&value| self.matches(value))
+    }
+}
+
+fn parse_value(s: &str, min: u32, max: u32) -> Result<u32> {
+    let value: u32 = s
+        .parse()
+        .with_context(|| format!("Invalid calendar value {}", s))?;
+    if value < min || value > max {
+        bail!(
+            "Calendar value {} is not between {} and {}",
+            value,
+            min,
+            max
+        );
+    }
+    Ok(value)
+}
+
+fn parse_weekday(s: &str) -> Result<u32> {
+    let lower = s.to_ascii_lowercase();
+    WEEKDAY_NAMES
+        .iter()
+        .position(|(short, long)| lower == *short || lower == *long)
+        .map(|idx| idx as u32)
+        .ok_or_else(|| anyhow!("Invalid weekday {}", s))
+}
+
+fn parse_weekdays(s: &str) -> Result<u8> {
+    let mut mask = 0u8;
+    for item in s.split(',') {
+        // Older versions of systemd also accept `-` for weekday ranges
+        let separator = item
+            .find("..")
+            .map(|idx| (idx, 2))
+            .or_else(|| item.find('-').map(|idx| (idx, 1)));
+        let (start, end) = match separator {
+            Some((idx, len)) => (
+                parse_weekday(&item[..idx])?,
+                parse_weekday(&item[idx + len..])?,
+            ),
+            None => {
+                let day = parse_weekday(item)?;
+                (day, day)
+            }
+        };
+        if start > end {
+            bail!("Invalid weekday range {}", item);
+        }
+        for day in start..=end {
+            mask |= 1 << day;
+        }
+    }
+    Ok(mask)
+}
+
+impl FromStr for CalendarSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<CalendarSpec> {
+        let expanded = match s.trim() {
+            "minutely" => "*-*-* *:*:00",
+            "hourly" => "*-*-* *:00:00",
+            "daily" => "*-*-* 00:00:00",
+            "monthly" => "*-*-01 00:00:00",
+            "weekly" => "Mon *-*-* 00:00:00",
+            "yearly" | "annually" => "*-01-01 00:00:00",
+            "quarterly" => "*-01,04,07,10-01 00:00:00",
+            "semiannually" => "*-01,07-01 00:00:00",
+            other => other,
+        };
+
+        let mut tokens: Vec<&str> = expanded.split_whitespace().collect();
+        if tokens.is_empty() {
+            bail!("Empty calendar expression");
+        }
+
+        let mut utc = false;
+        if let Some(&last) = tokens.last() {
+            if last.eq_ignore_ascii_case("UTC") {
+                utc = true;
+                tokens.pop();
+            } else if !last.contains(':') && !last.contains('-') && tokens.len() > 1 {
+                bail!("Unsupported timezone {} in calendar expression", last);
+            }
+        }
+
+        let mut weekdays = 0x7f;
+        let mut date = "*-*-*";
+        let mut time = "00:00:00";
+        for token in tokens {
+            if token.contains(':') {
+                time = token;
+            } else if token.contains('-') && token.starts_with(|c: char| !c.is_ascii_alphabetic()) {
+                date = token;
+            } else {
+                weekdays = parse_weekdays(token)?;
+            }
+        }
+
+        if date.contains('~') {
+            bail!("Counting days from the end of the month is not supported");
+        }
+        let date_parts: Vec<&str> = date.split('-').collect();
+        let (years, months, days) = match date_parts.as_slice() {
+            [year, month, day] => (*year, *month, *day),
+            [month, day] => ("*", *month, *day),
+            _ => bail!("Invalid date in calendar expression {}", s),
+        };
+
+        let time_parts: Vec<&str> = time.split(':').collect();
+        let (hours, minutes, seconds) = match time_parts.as_slice() {
+            [hour, minute, second] => (*hour, *minute, *second),
+            [hour, minute] => (*hour, *minute, "00"),
+            _ => bail!("Invalid time in calendar expression {}", s),
+        };
+        // Sub-second precision isn't meaningful for wakeups, so ignore any fractional seconds
+        let seconds = seconds.split('.').next().unwrap_or(seconds);
+
+        Ok(CalendarSpec {
+            weekdays,
+            years: Component::parse(years, 1970, MAX_YEAR as u32)?,
+            months: Component::parse(months, 1, 12)?,
+            days: Component::parse(days, 1, 31)?,
+            hours: Component::parse(hours, 0, 23)?,
+            minutes: Component::parse(minutes, 0, 59)?,
+            seconds: Component::parse(seconds, 0, 59)?,
+            utc,
+        })
+    }
+}
+
+impl CalendarSpec {
+    /// Finds the next time after `after` that this calendar event occurs, or `None` if it never occurs again.
+    pub fn next_elapse(&self, after: &DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = *after + Duration::seconds(1);
+        let start = if self.utc {
+            start.naive_utc()
+        } else {
+            start.with_timezone(&Local).naive_local()
+        }
+        .with_nanosecond(0)?;
+
+        let mut date = start.date();
+        let mut from_time = start.time();
+        while date.year() <= MAX_YEAR {
+            if !self.years.matches(date.year() as u32) {
+                date = NaiveDate::from_ymd_opt(date.year() + 1, 1, 1)?;
+                from_time = NaiveTime::from_hms(0, 0, 0);
+                continue;
+            }
+            if !self.months.matches(date.month()) {
+                date = first_of_next_month(date)?;
+                from_time = NaiveTime::from_hms(0, 0, 0);
+                continue;
+            }
+
+            if self.days.matches(date.day())
+                && self.weekdays & (1 << date.weekday().num_days_from_monday()) != 0
+            {
+                if let Some(time) = self.next_time(from_time) {
+                    if let Some(elapse) = self.to_utc(&date.and_time(time)) {
+                        return Some(elapse);
+                    }
+                    // The time was skipped by a DST transition, so keep looking after it
+                    from_time = time + Duration::seconds(1);
+                    if from_time > time {
+                        continue;
+                    }
+                }
+            }
+
+            date = date.succ_opt()?;
+            from_time = NaiveTime::from_hms(0, 0, 0);
+        }
+
+        None
+    }
+
+    /// Finds the earliest time of day at or after `from` that matches this event
+    fn next_time(&self, from: NaiveTime) -> Option<NaiveTime> {
+        let mut hour = self.hours.next_match(from.hour(), 23)?;
+        loop {
+            let min_minute = if hour == from.hour() {
+                from.minute()
+            } else {
+                0
+            };
+            let mut minute = self.minutes.next_match(min_minute, 59);
+            while let Some(m) = minute {
+                let min_second = if hour == from.hour() && m == from.minute() {
+                    from.second()
+                } else {
+                    0
+                };
+                if let Some(second) = self.seconds.next_match(min_second, 59) {
+                    return Some(NaiveTime::from_hms(hour, m, second));
+                }
+                minute = self.minutes.next_match(m + 1, 59);
+            }
+            hour = self.hours.next_match(hour + 1, 23)?;
+        }
+    }
+
+    /// Converts a time in this event's timezone to UTC. Ambiguous local times resolve to the earlier instant, and
+    /// local times that don't exist because of a DST transition resolve to `None`.
+    fn to_utc(&self, time: &NaiveDateTime) -> Option<DateTime<Utc>> {
+        if self.utc {
+            return Some(Utc.from_utc_datetime(time));
+        }
+        match local_to_utc(time) {
+            LocalResult::Single(dt) => Some(dt),
+            LocalResult::Ambiguous(earliest, _) => Some(earliest),
+            LocalResult::None => None,
+        }
+    }
+}
+
+fn first_of_next_month(date: NaiveDate) -> Option<NaiveDate> {
+    if date.month() == 12 {
+        NaiveDate::from_ymd_opt(date.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(date.year(), date.month() + 1, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::testing::{with_timezone, CENTRAL_EUROPEAN_TIME};
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn next(spec: &str, after: &str) -> Option<DateTime<Utc>> {
+        spec.parse::<CalendarSpec>()
+            .unwrap()
+            .next_elapse(&utc(after))
+    }
+
+    #[test]
+    fn expands_shorthands() {
+        for &(shorthand, expanded) in &[
+            ("daily", "*-*-* 00:00:00"),
+            ("weekly", "Mon *-*-* 00:00:00"),
+            ("monthly", "*-*-01 00:00:00"),
+            ("yearly", "*-01-01 00:00:00"),
+            ("hourly", "*-*-* *:00:00"),
+        ] {
+            assert_eq!(
+                shorthand.parse::<CalendarSpec>().unwrap(),
+                expanded.parse::<CalendarSpec>().unwrap(),
+                "{}",
+                shorthand
+            );
+        }
+    }
+
+    #[test]
+    fn matches_weekdays() {
+        // 2020-03-03 is a Tuesday
+        assert_eq!(
+            next("Mon,Wed *-*-* 06:00:00 UTC", "2020-03-03T00:00:00Z"),
+            Some(utc("2020-03-04T06:00:00Z"))
+        );
+        assert_eq!(
+            next("Sat..Sun 10:00 UTC", "2020-03-03T00:00:00Z"),
+            Some(utc("2020-03-07T10:00:00Z"))
+        );
+        assert_eq!(
+            next("Mon-Fri *-*-* 09:00 UTC", "2020-03-07T12:00:00Z"),
+            Some(utc("2020-03-09T09:00:00Z"))
+        );
+        assert_eq!(
+            next("monday,Thu..Fri 09:00 UTC", "2020-03-03T12:00:00Z"),
+            Some(utc("2020-03-05T09:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn matches_ranges_and_repetitions() {
+        assert_eq!(
+            next("*-*-* 08..10:00:00 UTC", "2020-03-01T09:10:00Z"),
+            Some(utc("2020-03-01T10:00:00Z"))
+        );
+        assert_eq!(
+            next("*-*-* 08..10:00:00 UTC", "2020-03-01T10:30:00Z"),
+            Some(utc("2020-03-02T08:00:00Z"))
+        );
+        assert_eq!(
+            next("*-*-* *:0/15:00 UTC", "2020-03-01T10:16:00Z"),
+            Some(utc("2020-03-01T10:30:00Z"))
+        );
+        assert_eq!(
+            next("*-*-01/10 00:00:00 UTC", "2020-03-01T00:00:00Z"),
+            Some(utc("2020-03-11T00:00:00Z"))
+        );
+        assert_eq!(
+            next("*-*-* 02:00..30/10:00 UTC", "2020-03-01T02:21:00Z"),
+            Some(utc("2020-03-01T02:30:00Z"))
+        );
+        // The elapse has to be strictly after the given time
+        assert_eq!(
+            next("*-*-* 04:00:00 UTC", "2020-03-01T04:00:00Z"),
+            Some(utc("2020-03-02T04:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn rolls_over_months_and_years() {
+        assert_eq!(
+            next("*-*-31 12:00:00 UTC", "2020-04-01T00:00:00Z"),
+            Some(utc("2020-05-31T12:00:00Z"))
+        );
+        assert_eq!(
+            next("*-01-01 00:00:00 UTC", "2020-12-31T23:59:59Z"),
+            Some(utc("2021-01-01T00:00:00Z"))
+        );
+        assert_eq!(
+            next("*-12-31 23:59:59 UTC", "2020-12-31T23:59:59Z"),
+            Some(utc("2021-12-31T23:59:59Z"))
+        );
+        assert_eq!(
+            next("*-02-29 06:00:00 UTC", "2020-03-01T00:00:00Z"),
+            Some(utc("2024-02-29T06:00:00Z"))
+        );
+        // 2100 isn't a leap year
+        assert_eq!(
+            next("*-02-29 06:00:00 UTC", "2096-03-01T00:00:00Z"),
+            Some(utc("2104-02-29T06:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn stops_at_max_year() {
+        assert_eq!(
+            next("2200-12-31 00:00:00 UTC", "2200-01-01T00:00:00Z"),
+            Some(utc("2200-12-31T00:00:00Z"))
+        );
+        assert_eq!(
+            next("2200-12-31 00:00:00 UTC", "2200-12-31T00:00:00Z"),
+            None
+        );
+        // 2200 isn't a leap year, and 2204 is past the cutoff
+        assert_eq!(next("*-02-29 06:00:00 UTC", "2196-03-01T00:00:00Z"), None);
+        assert_eq!(
+            next("2019-01-01 00:00:00 UTC", "2020-01-01T00:00:00Z"),
+            None
+        );
+        assert!("2201-01-01 00:00:00 UTC".parse::<CalendarSpec>().is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_specs() {
+        for spec in &[
+            "",
+            "  ",
+            "*-13-01 00:00:00",
+            "*-*-32 00:00:00",
+            "*-*-00 00:00:00",
+            "25:00",
+            "*-*-* 12:60:00",
+            "Funday 00:00",
+            "Monkey 00:00",
+            "Thurs 00:00",
+            "Sun..Mon 00:00",
+            "*-*-5..1 00:00:00",
+            "*-*-1/0 00:00:00",
+            "*-*-1/x 00:00:00",
+            "1-2-3-4 00:00",
+            "00:00:00:00",
+            "*-*~01 00:00:00",
+            "*-*-* 00:00:00 Europe/Berlin",
+        ] {
+            assert!(spec.parse::<CalendarSpec>().is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn follows_local_timezone() {
+        // In 2020, DST started at 2am on 29 March and ended at 3am on 25 October
+        with_timezone(CENTRAL_EUROPEAN_TIME, || {
+            // Local times are UTC+1 in winter, unlike UTC ones
+            assert_eq!(
+                next("*-*-* 04:00:00", "2020-01-15T00:00:00Z"),
+                Some(utc("2020-01-15T03:00:00Z"))
+            );
+            assert_eq!(
+                next("*-*-* 04:00:00 UTC", "2020-01-15T00:00:00Z"),
+                Some(utc("2020-01-15T04:00:00Z"))
+            );
+            assert_eq!(
+                next("weekly", "2020-01-15T00:00:00Z"),
+                Some(utc("2020-01-19T23:00:00Z"))
+            );
+            // 2:30 doesn't exist on the day DST starts, so that day is skipped
+            assert_eq!(
+                next("*-*-* 02:30:00", "2020-03-28T12:00:00Z"),
+                Some(utc("2020-03-30T00:30:00Z"))
+            );
+            // 2:30 happens twice on the day DST ends, and the first one counts
+            assert_eq!(
+                next("*-*-* 02:30:00", "2020-10-24T12:00:00Z"),
+                Some(utc("2020-10-25T00:30:00Z"))
+            );
+        });
+    }
+}
@@ -0,0 +1,669 @@
+//! Runtime configuration. Night Kitchen is configured through environment variables, which are normally set using
+//! `Environment=` lines in its systemd units and can be overridden with drop-ins (`systemctl edit <unit>`).
+use std::env;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::history::RunOutcome;
+
+/// Environment variable for how long before a timer elapses the scheduler should wake the system
+pub const WAKE_AHEAD_VAR: &str = "NIGHT_KITCHEN_WAKE_AHEAD";
+
+/// Environment variable for the minimum time between shutdown and the RTC alarm going off
+pub const MIN_LEAD_TIME_VAR: &str = "NIGHT_KITCHEN_MIN_LEAD_TIME";
+
+/// Environment variable for where in a timer's trigger window the system should be woken up
+pub const WAKE_POLICY_VAR: &str = "NIGHT_KITCHEN_WAKE_POLICY";
+
+/// Where in a timer's trigger window the system should be woken up.
+///
+/// systemd does not necessarily trigger a timer as soon as it elapses. It may delay it by up to `RandomizedDelaySec=`,
+/// and then coalesce it with other wakeups anywhere within `AccuracySec=`, often near the end of that window. With
+/// long accuracies like the `12h` the Night Kitchen timers use, waking up when the timer elapses can mean sitting idle
+/// for hours before anything runs.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WakePolicy {
+    /// Wake up at the start of the window, when the timer elapses, and let systemd decide when to trigger it
+    Start,
+
+    /// Wake up at the end of the window. This relies on `Persistent=` so that systemd triggers the missed timer
+    /// immediately after booting.
+    End,
+
+    /// Use `End` for timers with `Persistent=` set and `Start` for all others
+    Auto,
+}
+
+impl WakePolicy {
+    /// Resolves `Auto` to the policy to use for a timer, depending on whether or not it is persistent
+    pub fn for_timer(self, persistent: bool) -> WakePolicy {
+        match self {
+            WakePolicy::Auto if persistent => WakePolicy::End,
+            WakePolicy::Auto => WakePolicy::Start,
+            other => other,
+        }
+    }
+}
+
+impl FromStr for WakePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<WakePolicy> {
+        match s.trim() {
+            "start" => Ok(WakePolicy::Start),
+            "end" => Ok(WakePolicy::End),
+            "auto" => Ok(WakePolicy::Auto),
+            other => Err(anyhow!("Unknown wake policy: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for WakePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WakePolicy::Start => write!(f, "start"),
+            WakePolicy::End => write!(f, "end"),
+            WakePolicy::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Environment variable for which interface to use to access the RTC
+pub const RTC_INTERFACE_VAR: &str = "NIGHT_KITCHEN_RTC_INTERFACE";
+
+/// How to access the RTC wake alarm
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RtcInterface {
+    /// Use the `RTC_WKALRM_RD` and `RTC_WKALRM_SET` ioctls on `/dev/rtc0`
+    Ioctl,
+    /// Use `/sys/class/rtc/rtc0/wakealarm`, for RTCs that don't support the wake alarm ioctls
+    Sysfs,
+}
+
+impl FromStr for RtcInterface {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<RtcInterface> {
+        match s.trim() {
+            "ioctl" => Ok(RtcInterface::Ioctl),
+            "sysfs" => Ok(RtcInterface::Sysfs),
+            other => Err(anyhow!("Unknown RTC interface: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for RtcInterface {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RtcInterface::Ioctl => write!(f, "ioctl"),
+            RtcInterface::Sysfs => write!(f, "sysfs"),
+        }
+    }
+}
+
+/// Environment variable for what to do with the RTC alarm when a shutdown is cancelled
+pub const CANCEL_POLICY_VAR: &str = "NIGHT_KITCHEN_CANCEL_POLICY";
+
+/// What to do with the RTC wake alarm set for a shutdown that was then cancelled
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CancelPolicy {
+    /// Leave the alarm set. It's replaced anyway the next time the system shuts down.
+    Keep,
+    /// Put back whatever alarm was set before the shutdown started, or disable the alarm if there was none
+    Revert,
+}
+
+impl FromStr for CancelPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<CancelPolicy> {
+        match s.trim() {
+            "keep" => Ok(CancelPolicy::Keep),
+            "revert" => Ok(CancelPolicy::Revert),
+            other => Err(anyhow!("Unknown cancel policy: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for CancelPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CancelPolicy::Keep => write!(f, "keep"),
+            CancelPolicy::Revert => write!(f, "revert"),
+        }
+    }
+}
+
+/// Environment variable for the directory to write metrics to for node_exporter's textfile collector. Metrics aren't
+/// written if it's unset or empty.
+pub const METRICS_DIRECTORY_VAR: &str = "NIGHT_KITCHEN_METRICS_DIRECTORY";
+
+/// Reads the [metrics directory](constant.METRICS_DIRECTORY_VAR.html), which both the runner and scheduler use
+pub fn metrics_directory() -> Result<Option<PathBuf>> {
+    env_var(METRICS_DIRECTORY_VAR, None, |value| {
+        Ok(Some(value.trim())
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from))
+    })
+}
+
+/// Configuration for `night-kitchen-scheduler`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SchedulerConfig {
+    /// How long before a timer's next elapse the system should be woken up. This gives firmware, disk unlocking, and
+    /// the rest of the boot process time to finish so that tasks start on time.
+    pub wake_ahead: Duration,
+
+    /// The shortest time in the future for which an RTC alarm will be set. Alarms closer than this may go off before
+    /// the system has finished shutting down, in which case they're missed entirely.
+    pub min_lead_time: Duration,
+
+    /// Where in each timer's trigger window to wake up
+    pub wake_policy: WakePolicy,
+
+    /// How to access the RTC
+    pub rtc_interface: RtcInterface,
+
+    /// What to do with the RTC alarm if a shutdown is cancelled
+    pub cancel_policy: CancelPolicy,
+
+    /// Where to write metrics for node_exporter, if anywhere
+    pub metrics_directory: Option<PathBuf>,
+
+    /// Where skipped activations and pauses are kept, which is normally the
+    /// [state directory](../fn.state_directory.html)
+    pub state_directory: PathBuf,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> SchedulerConfig {
+        SchedulerConfig {
+            wake_ahead: Duration::from_secs(0),
+            min_lead_time: Duration::from_secs(60),
+            wake_policy: WakePolicy::Auto,
+            rtc_interface: RtcInterface::Ioctl,
+            cancel_policy: CancelPolicy::Keep,
+            metrics_directory: None,
+            state_directory: crate::state_directory(),
+        }
+    }
+}
+
+impl SchedulerConfig {
+    /// Loads the scheduler configuration from the environment, using defaults for any unset variables.
+    pub fn from_env() -> Result<SchedulerConfig> {
+        let defaults = SchedulerConfig::default();
+        Ok(SchedulerConfig {
+            wake_ahead: env_var(WAKE_AHEAD_VAR, defaults.wake_ahead, parse_timespan)?,
+            min_lead_time: env_var(MIN_LEAD_TIME_VAR, defaults.min_lead_time, parse_timespan)?,
+            wake_policy: env_var(WAKE_POLICY_VAR, defaults.wake_policy, str::parse)?,
+            rtc_interface: env_var(RTC_INTERFACE_VAR, defaults.rtc_interface, str::parse)?,
+            cancel_policy: env_var(CANCEL_POLICY_VAR, defaults.cancel_policy, str::parse)?,
+            metrics_directory: metrics_directory()?,
+            state_directory: defaults.state_directory,
+        })
+    }
+}
+
+/// Environment variable with a command for the runner to run through `/bin/sh -c` once a run is over, if it's set and
+/// not empty
+pub const RUN_HOOK_VAR: &str = "NIGHT_KITCHEN_RUN_HOOK";
+
+/// Environment variable with a template unit, such as `night-kitchen-notify@.service`, for the runner to start with the
+/// target as its instance once a run is over
+pub const NOTIFY_UNIT_VAR: &str = "NIGHT_KITCHEN_NOTIFY_UNIT";
+
+/// Environment variable listing the run outcomes the runner's hooks are for, such as `failure,timeout`
+pub const NOTIFY_ON_VAR: &str = "NIGHT_KITCHEN_NOTIFY_ON";
+
+/// Environment variable for the addresses to email a report of a run to
+pub const NOTIFY_EMAIL_VAR: &str = "NIGHT_KITCHEN_NOTIFY_EMAIL";
+
+/// Environment variable for the URL to post a report of a run to
+pub const NOTIFY_WEBHOOK_VAR: &str = "NIGHT_KITCHEN_NOTIFY_WEBHOOK";
+
+/// Environment variable for the `sendmail` binary of the local MTA, which sends the email reports
+pub const SENDMAIL_VAR: &str = "NIGHT_KITCHEN_SENDMAIL";
+
+/// Environment variable for the `curl` binary that posts the webhook reports
+pub const CURL_VAR: &str = "NIGHT_KITCHEN_CURL";
+
+/// Configuration for the runner's notification hooks
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HookConfig {
+    /// The command to run after a run, if any
+    pub command: Option<String>,
+    /// The template unit to start an instance of after a run, if any
+    pub unit: Option<String>,
+    /// The addresses to email a report of the run to
+    pub email: Vec<String>,
+    /// The `http://` or `https://` URL to post a report of the run to, if any
+    pub webhook: Option<String>,
+    /// The local MTA's `sendmail` binary
+    pub sendmail: PathBuf,
+    /// The `curl` binary, which is looked up in `PATH` unless it's a path
+    pub curl: PathBuf,
+    /// Which outcomes to run the hooks and send reports for
+    pub notify_on: Vec<RunOutcome>,
+}
+
+impl Default for HookConfig {
+    fn default() -> HookConfig {
+        HookConfig {
+            command: None,
+            unit: None,
+            email: Vec::new(),
+            webhook: None,
+            sendmail: PathBuf::from("/usr/sbin/sendmail"),
+            curl: PathBuf::from("curl"),
+            notify_on: vec![RunOutcome::Failure, RunOutcome::Timeout],
+        }
+    }
+}
+
+impl HookConfig {
+    /// Loads the hook configuration from the environment, using defaults for any unset variables.
+    pub fn from_env() -> Result<HookConfig> {
+        let defaults = HookConfig::default();
+        Ok(HookConfig {
+            command: env_var(RUN_HOOK_VAR, defaults.command, |value| {
+                Ok(Some(value.trim().to_string()).filter(|command| !command.is_empty()))
+            })?,
+            unit: env_var(NOTIFY_UNIT_VAR, defaults.unit, parse_template_unit)?,
+            email: env_var(NOTIFY_EMAIL_VAR, defaults.email, |value| {
+                split_list(value)
+                    .map(|address| {
+                        if !address.contains('@') || address.starts_with('-') {
+                            bail!("{} is not an email address", address);
+                        }
+                        Ok(address.to_string())
+                    })
+                    .collect()
+            })?,
+            webhook: env_var(NOTIFY_WEBHOOK_VAR, defaults.webhook, parse_webhook)?,
+            sendmail: env_var(SENDMAIL_VAR, defaults.sendmail, |value| {
+                Ok(PathBuf::from(value.trim()))
+            })?,
+            curl: env_var(CURL_VAR, defaults.curl, |value| {
+                Ok(PathBuf::from(value.trim()))
+            })?,
+            notify_on: env_var(NOTIFY_ON_VAR, defaults.notify_on, |value| {
+                split_list(value).map(str::parse).collect()
+            })?,
+        })
+    }
+
+    /// Whether the hooks are for runs with `outcome`
+    pub fn notifies_on(&self, outcome: RunOutcome) -> bool {
+        self.notify_on.contains(&outcome)
+    }
+}
+
+/// Environment variable for whether the runner should wait for `network-online.target` before starting the target
+pub const WAIT_FOR_NETWORK_VAR: &str = "NIGHT_KITCHEN_WAIT_FOR_NETWORK";
+
+/// Environment variable listing `host:port` pairs the runner should be able to connect to before starting the target
+pub const WAIT_FOR_HOSTS_VAR: &str = "NIGHT_KITCHEN_WAIT_FOR_HOSTS";
+
+/// Environment variable for how long the runner waits for the network before giving up
+pub const NETWORK_TIMEOUT_VAR: &str = "NIGHT_KITCHEN_NETWORK_TIMEOUT";
+
+/// Environment variable for what the runner does with the target if the network doesn't come up in time
+pub const NETWORK_FAILURE_VAR: &str = "NIGHT_KITCHEN_NETWORK_FAILURE";
+
+/// What the runner does with the target if the network doesn't come up in time
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NetworkFailurePolicy {
+    /// Don't start the target, and return the system to the state it was in as usual
+    Skip,
+    /// Fail the run, as if the target couldn't be started, which leaves the system running
+    Fail,
+}
+
+impl FromStr for NetworkFailurePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<NetworkFailurePolicy> {
+        match s.trim() {
+            "skip" => Ok(NetworkFailurePolicy::Skip),
+            "fail" => Ok(NetworkFailurePolicy::Fail),
+            other => Err(anyhow!("Unknown network failure policy: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for NetworkFailurePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkFailurePolicy::Skip => write!(f, "skip"),
+            NetworkFailurePolicy::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// Configuration for the network a target needs, which the runner waits for before starting it
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct NetworkConfig {
+    /// Whether to wait for `network-online.target`
+    pub wait_online: bool,
+    /// The `host:port` pairs to wait until a TCP connection can be made to
+    pub hosts: Vec<String>,
+    /// How long to wait for all of them, in total
+    pub timeout: Duration,
+    pub on_failure: NetworkFailurePolicy,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig {
+            wait_online: false,
+            hosts: Vec::new(),
+            timeout: Duration::from_secs(120),
+            on_failure: NetworkFailurePolicy::Skip,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Loads the network configuration from the environment, using defaults for any unset variables.
+    pub fn from_env() -> Result<NetworkConfig> {
+        let defaults = NetworkConfig::default();
+        Ok(NetworkConfig {
+            wait_online: env_var(WAIT_FOR_NETWORK_VAR, defaults.wait_online, parse_bool)?,
+            hosts: env_var(WAIT_FOR_HOSTS_VAR, defaults.hosts, |value| {
+                split_list(value).map(parse_host_port).collect()
+            })?,
+            timeout: env_var(NETWORK_TIMEOUT_VAR, defaults.timeout, parse_timespan)?,
+            on_failure: env_var(NETWORK_FAILURE_VAR, defaults.on_failure, str::parse)?,
+        })
+    }
+
+    /// Whether the target needs the network at all
+    pub fn needs_network(&self) -> bool {
+        self.wait_online || !self.hosts.is_empty()
+    }
+}
+
+/// Parses a boolean the way systemd does, such as `yes`, `no`, `true` or `false`
+fn parse_bool(s: &str) -> Result<bool> {
+    match s.trim() {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Ok(true),
+        "0" | "no" | "n" | "false" | "f" | "off" | "" => Ok(false),
+        other => Err(anyhow!("Not a boolean: {}", other)),
+    }
+}
+
+/// Parses a `host:port` pair, where IPv6 addresses are in brackets, such as `[::1]:22`
+fn parse_host_port(s: &str) -> Result<String> {
+    let valid = match s.rfind(':') {
+        Some(colon) => {
+            let host = &s[..colon];
+            let bracketed = host.len() > 2 && host.starts_with('[') && host.ends_with(']');
+            !host.is_empty()
+                && (bracketed || !host.contains(&[':', '[', ']'][..]))
+                && s[colon + 1..].parse::<u16>().is_ok()
+        }
+        None => false,
+    };
+    if !valid {
+        bail!("{} is not a host:port pair", s);
+    }
+    Ok(s.to_string())
+}
+
+/// Splits a comma or whitespace separated list
+fn split_list(s: &str) -> impl Iterator<Item = &str> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+}
+
+/// Parses a webhook URL. An empty URL means there isn't one.
+fn parse_webhook(s: &str) -> Result<Option<String>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    if !s.starts_with("http://") && !s.starts_with("https://") {
+        bail!("{} is not an http:// or https:// URL", s);
+    }
+    Ok(Some(s.to_string()))
+}
+
+/// Parses the name of a template service, such as `night-kitchen-notify@.service`. An empty name means there isn't one.
+fn parse_template_unit(s: &str) -> Result<Option<String>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    if !s.ends_with("@.service") || s.len() == "@.service".len() {
+        bail!(
+            "{} is not a template service, such as night-kitchen-notify@.service",
+            s
+        );
+    }
+    Ok(Some(s.to_string()))
+}
+
+/// Reads and parses the environment variable `name`, falling back to `default` if it is not set.
+fn env_var<T, F: FnOnce(&str) -> Result<T>>(name: &str, default: T, parse: F) -> Result<T> {
+    match env::var(name) {
+        Ok(value) => parse(&value).with_context(|| format!("Invalid value for {}", name)),
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(e).with_context(|| format!("Could not read {}", name)),
+    }
+}
+
+/// Parses a time span using the same syntax as systemd, such as `90s`, `5min` or `1h 30min`. A number without a unit
+/// is interpreted as seconds.
+///
+/// See [`man:systemd.time(7)`](https://www.freedesktop.org/software/systemd/man/systemd.time.html) for the full syntax.
+pub fn parse_timespan(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    if s.is_empty() {
+        bail!("Empty time span");
+    }
+
+    let mut total_usecs: u64 = 0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            bail!("Invalid time span: {}", s);
+        }
+        let value: u64 = rest[..digits].parse()?;
+        rest = rest[digits..].trim_start();
+
+        let unit_len = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let unit = &rest[..unit_len];
+        rest = rest[unit_len..].trim_start();
+
+        let usecs = value
+            .checked_mul(
+                timespan_unit_usecs(unit).ok_or_else(|| anyhow!("Unknown time unit: {}", unit))?,
+            )
+            .ok_or_else(|| anyhow!("Time span is too large: {}", s))?;
+        total_usecs = total_usecs
+            .checked_add(usecs)
+            .ok_or_else(|| anyhow!("Time span is too large: {}", s))?;
+    }
+
+    Ok(Duration::from_micros(total_usecs))
+}
+
+/// Gets the number of microseconds in a systemd time span unit
+fn timespan_unit_usecs(unit: &str) -> Option<u64> {
+    const SEC: u64 = 1_000_000;
+    let usecs = match unit {
+        "us" | "usec" => 1,
+        "ms" | "msec" => 1_000,
+        "" | "s" | "sec" | "second" | "seconds" => SEC,
+        "m" | "min" | "minute" | "minutes" => 60 * SEC,
+        "h" | "hr" | "hour" | "hours" => 60 * 60 * SEC,
+        "d" | "day" | "days" => 24 * 60 * 60 * SEC,
+        "w" | "week" | "weeks" => 7 * 24 * 60 * 60 * SEC,
+        "M" | "month" | "months" => 2_629_800 * SEC,
+        "y" | "year" | "years" => 31_557_600 * SEC,
+        _ => return None,
+    };
+    Some(usecs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timespans() {
+        let cases = [
+            ("0", 0),
+            ("90", 90_000_000),
+            ("250us", 250),
+            ("250usec", 250),
+            ("15ms", 15_000),
+            ("90s", 90_000_000),
+            ("5min", 300_000_000),
+            ("5m", 300_000_000),
+            ("2h", 7_200_000_000),
+            ("1d", 86_400_000_000),
+            ("1w", 604_800_000_000),
+            ("1h30min", 5_400_000_000),
+            ("1h 30min", 5_400_000_000),
+            (" 2 min 5 s ", 125_000_000),
+            ("1d 2h 3min 4s 5ms 6us", 93_784_005_006),
+        ];
+        for &(input, usecs) in &cases {
+            assert_eq!(
+                parse_timespan(input).unwrap(),
+                Duration::from_micros(usecs),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_timespans() {
+        for input in &[
+            "",
+            "   ",
+            "min",
+            "5 fortnights",
+            "-5s",
+            "1.5h",
+            "5s,",
+            "99999999999999999999",
+            "18446744073709551615w",
+            "10000000000000s 10000000000000s",
+        ] {
+            assert!(parse_timespan(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parses_policies() {
+        for policy in &[WakePolicy::Start, WakePolicy::End, WakePolicy::Auto] {
+            assert_eq!(policy.to_string().parse::<WakePolicy>().unwrap(), *policy);
+        }
+        assert_eq!(" end\n".parse::<WakePolicy>().unwrap(), WakePolicy::End);
+        assert!("End".parse::<WakePolicy>().is_err());
+        assert!("".parse::<WakePolicy>().is_err());
+
+        for interface in &[RtcInterface::Ioctl, RtcInterface::Sysfs] {
+            assert_eq!(
+                interface.to_string().parse::<RtcInterface>().unwrap(),
+                *interface
+            );
+        }
+        assert!("procfs".parse::<RtcInterface>().is_err());
+
+        for policy in &[CancelPolicy::Keep, CancelPolicy::Revert] {
+            assert_eq!(policy.to_string().parse::<CancelPolicy>().unwrap(), *policy);
+        }
+        assert!("forget".parse::<CancelPolicy>().is_err());
+
+        for policy in &[NetworkFailurePolicy::Skip, NetworkFailurePolicy::Fail] {
+            assert_eq!(
+                policy.to_string().parse::<NetworkFailurePolicy>().unwrap(),
+                *policy
+            );
+        }
+        assert!("retry".parse::<NetworkFailurePolicy>().is_err());
+    }
+
+    #[test]
+    fn resolves_auto_wake_policy() {
+        assert_eq!(WakePolicy::Auto.for_timer(true), WakePolicy::End);
+        assert_eq!(WakePolicy::Auto.for_timer(false), WakePolicy::Start);
+        assert_eq!(WakePolicy::Start.for_timer(true), WakePolicy::Start);
+        assert_eq!(WakePolicy::End.for_timer(false), WakePolicy::End);
+    }
+
+    #[test]
+    fn parses_booleans() {
+        for input in &["1", "yes", "y", "true", "t", "on", " yes "] {
+            assert!(parse_bool(input).unwrap(), "{:?}", input);
+        }
+        for input in &["0", "no", "n", "false", "f", "off", ""] {
+            assert!(!parse_bool(input).unwrap(), "{:?}", input);
+        }
+        for input in &["2", "YES", "enabled"] {
+            assert!(parse_bool(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn parses_host_port_pairs() {
+        for input in &[
+            "backup.example.com:22",
+            "192.0.2.1:873",
+            "[::1]:22",
+            "[2001:db8::1]:443",
+        ] {
+            assert_eq!(parse_host_port(input).unwrap(), *input);
+        }
+        for input in &[
+            "backup.example.com",
+            "backup.example.com:",
+            ":22",
+            "backup.example.com:ssh",
+            "backup.example.com:65536",
+            "::1",
+            "::1:22",
+            "[::1]",
+            "[]:22",
+        ] {
+            assert!(parse_host_port(input).is_err(), "{:?}", input);
+        }
+
+        assert_eq!(
+            split_list("a:1, b:2\tc:3,,").collect::<Vec<_>>(),
+            vec!["a:1", "b:2", "c:3"]
+        );
+    }
+
+    #[test]
+    fn parses_hook_settings() {
+        assert_eq!(parse_webhook("  ").unwrap(), None);
+        assert_eq!(
+            parse_webhook("https://example.com/hook").unwrap(),
+            Some("https://example.com/hook".to_string())
+        );
+        assert!(parse_webhook("ftp://example.com/hook").is_err());
+
+        assert_eq!(parse_template_unit("").unwrap(), None);
+        assert_eq!(
+            parse_template_unit("night-kitchen-notify@.service").unwrap(),
+            Some("night-kitchen-notify@.service".to_string())
+        );
+        assert!(parse_template_unit("@.service").is_err());
+        assert!(parse_template_unit("night-kitchen-notify.service").is_err());
+    }
+}
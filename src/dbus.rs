@@ -1,31 +1,76 @@
 //! D-Bus bindings generated by [dbus-codegen-rust](https://github.com/diwic/dbus-rs/tree/master/dbus-codegen)
-use std::time::Duration;
+//!
+//! The [`LoginManager`](trait.LoginManager.html) and [`SystemdManager`](trait.SystemdManager.html) traits cover the small part of those APIs that Night Kitchen uses, so
+//! that code can be written against them instead of the much larger generated traits. The [`testing`](testing/index.html) module provides
+//! fake implementations of both services for running against a private bus.
+//!
+//! [`scheduler`](scheduler/index.html) has client bindings for night-kitchen-scheduler's own control interface.
+//!
+//! [`start_unit`](fn.start_unit.html) starts a systemd unit and waits for the jobs that come with it, and
+//! [`start_unit_by`](fn.start_unit_by.html) gives up waiting at a deadline.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use dbus::arg::{OwnedFd, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
 use dbus::blocking::{Connection, Proxy};
+use dbus::{Message, Path};
+use slog::{debug, error, Logger};
 
-use crate::dbus::systemd::OrgFreedesktopSystemd1Manager;
+use crate::dbus::logind::OrgFreedesktopLogin1Manager;
+use crate::dbus::systemd::{
+    OrgFreedesktopSystemd1Manager, OrgFreedesktopSystemd1ManagerJobRemoved,
+};
+use crate::history::UnitOutcome;
 
 pub mod logind;
+pub mod scheduler;
 pub mod systemd;
 pub mod systemd_timer;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
-const PROXY_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long D-Bus calls wait for a reply, unless they have a deadline that's sooner
+pub const PROXY_TIMEOUT: Duration = Duration::from_millis(500);
 
 /// Creates a D-Bus connection proxy referring to the systemd-logind manager API object
 pub fn login_manager(connection: &Connection) -> Proxy<'_, &Connection> {
-    connection.with_proxy(
-        "org.freedesktop.login1",
-        "/org/freedesktop/login1",
-        PROXY_TIMEOUT,
-    )
+    login_manager_with_timeout(connection, PROXY_TIMEOUT)
+}
+
+/// Like [`login_manager`](fn.login_manager.html), but calls give up after `timeout`
+pub fn login_manager_with_timeout(
+    connection: &Connection,
+    timeout: Duration,
+) -> Proxy<'_, &Connection> {
+    connection.with_proxy("org.freedesktop.login1", "/org/freedesktop/login1", timeout)
 }
 
 /// Creates a D-Bus connection proxy referring to the systemd manager API object
 pub fn systemd_manager(connection: &Connection) -> Proxy<'_, &Connection> {
+    systemd_manager_with_timeout(connection, PROXY_TIMEOUT)
+}
+
+/// Like [`systemd_manager`](fn.systemd_manager.html), but calls give up after `timeout`
+pub fn systemd_manager_with_timeout(
+    connection: &Connection,
+    timeout: Duration,
+) -> Proxy<'_, &Connection> {
     connection.with_proxy(
         "org.freedesktop.systemd1",
         "/org/freedesktop/systemd1",
+        timeout,
+    )
+}
+
+/// Creates a D-Bus connection proxy referring to night-kitchen-scheduler's control object
+pub fn scheduler_control(connection: &Connection) -> Proxy<'_, &Connection> {
+    connection.with_proxy(
+        scheduler::SCHEDULER_NAME,
+        scheduler::SCHEDULER_PATH,
         PROXY_TIMEOUT,
     )
 }
@@ -36,10 +81,288 @@ pub fn systemd_unit<'a>(
     connection: &'a Connection,
     unit_name: &str,
 ) -> Result<Proxy<'a, &'a Connection>> {
-    let manager = systemd_manager(connection);
-    let unit_path = manager
-        .get_unit(unit_name)
-        .with_context(|| format!("Could not find D-Bus path for systemd unit {}", unit_name))?;
+    let unit_path = systemd_unit_path(connection, unit_name, PROXY_TIMEOUT)?;
+    Ok(systemd_unit_at(connection, unit_path, PROXY_TIMEOUT))
+}
+
+/// Looks up the D-Bus path of the systemd unit with the given name, giving up after `timeout`
+pub fn systemd_unit_path(
+    connection: &Connection,
+    unit_name: &str,
+    timeout: Duration,
+) -> Result<Path<'static>> {
+    let manager = systemd_manager_with_timeout(connection, timeout);
+    SystemdManager::get_unit(&manager, unit_name)
+        .with_context(|| format!("Could not find D-Bus path for systemd unit {}", unit_name))
+}
+
+/// Creates a D-Bus connection proxy referring to the systemd unit at `unit_path`, whose calls give up after `timeout`
+pub fn systemd_unit_at<'a>(
+    connection: &'a Connection,
+    unit_path: Path<'a>,
+    timeout: Duration,
+) -> Proxy<'a, &'a Connection> {
+    connection.with_proxy("org.freedesktop.systemd1", unit_path, timeout)
+}
+
+/// An inhibitor lock held by some process, as listed by
+/// [`LoginManager::list_inhibitors`](trait.LoginManager.html#tymethod.list_inhibitors)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InhibitorLock {
+    /// The lock types, such as `sleep:shutdown`
+    pub what: String,
+    pub who: String,
+    pub why: String,
+    /// Either `block` or `delay`
+    pub mode: String,
+    pub uid: u32,
+    pub pid: u32,
+}
+
+/// The systemd-logind manager methods Night Kitchen depends on
+pub trait LoginManager {
+    /// Takes an inhibitor lock. See `man:org.freedesktop.login1(5)` for the meaning of each argument.
+    fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str)
+        -> Result<OwnedFd, dbus::Error>;
+
+    /// Lists the inhibitor locks currently held by all processes
+    fn list_inhibitors(&self) -> Result<Vec<InhibitorLock>, dbus::Error>;
+
+    /// Gracefully powers off the system. If `interactive` is set, PolicyKit may prompt for authentication.
+    fn power_off(&self, interactive: bool) -> Result<(), dbus::Error>;
+
+    /// Suspends the system. If `interactive` is set, PolicyKit may prompt for authentication.
+    fn suspend(&self, interactive: bool) -> Result<(), dbus::Error>;
+
+    /// The longest that logind will wait for delay inhibitor locks to be released, in microseconds
+    fn inhibit_delay_max_usec(&self) -> Result<u64, dbus::Error>;
+
+    /// The type of shutdown scheduled with `ScheduleShutdown()`, such as `poweroff` or `reboot`, and when it will happen
+    /// in microseconds since the epoch. The type is empty if no shutdown is scheduled.
+    fn scheduled_shutdown(&self) -> Result<(String, u64), dbus::Error>;
+
+    /// Details of the shutdown logind is preparing for, such as its `type`. Only available since systemd 255.
+    fn preparing_for_shutdown_with_metadata(
+        &self,
+    ) -> Result<HashMap<String, Variant<Box<dyn RefArg>>>, dbus::Error>;
+}
+
+impl<T: OrgFreedesktopLogin1Manager + Properties> LoginManager for T {
+    fn inhibit(
+        &self,
+        what: &str,
+        who: &str,
+        why: &str,
+        mode: &str,
+    ) -> Result<OwnedFd, dbus::Error> {
+        OrgFreedesktopLogin1Manager::inhibit(self, what, who, why, mode)
+    }
+
+    fn list_inhibitors(&self) -> Result<Vec<InhibitorLock>, dbus::Error> {
+        let inhibitors = OrgFreedesktopLogin1Manager::list_inhibitors(self)?;
+        Ok(inhibitors
+            .into_iter()
+            .map(|(what, who, why, mode, uid, pid)| InhibitorLock {
+                what,
+                who,
+                why,
+                mode,
+                uid,
+                pid,
+            })
+            .collect())
+    }
+
+    fn power_off(&self, interactive: bool) -> Result<(), dbus::Error> {
+        OrgFreedesktopLogin1Manager::power_off(self, interactive)
+    }
+
+    fn suspend(&self, interactive: bool) -> Result<(), dbus::Error> {
+        OrgFreedesktopLogin1Manager::suspend(self, interactive)
+    }
+
+    fn inhibit_delay_max_usec(&self) -> Result<u64, dbus::Error> {
+        OrgFreedesktopLogin1Manager::inhibit_delay_max_usec(self)
+    }
+
+    fn scheduled_shutdown(&self) -> Result<(String, u64), dbus::Error> {
+        OrgFreedesktopLogin1Manager::scheduled_shutdown(self)
+    }
+
+    fn preparing_for_shutdown_with_metadata(
+        &self,
+    ) -> Result<HashMap<String, Variant<Box<dyn RefArg>>>, dbus::Error> {
+        Properties::get(
+            self,
+            "org.freedesktop.login1.Manager",
+            "PreparingForShutdownWithMetadata",
+        )
+    }
+}
+
+/// A job queued in systemd, as listed by [`SystemdManager::list_jobs`](trait.SystemdManager.html#tymethod.list_jobs)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Job {
+    pub id: u32,
+    /// The unit the job is for
+    pub unit: String,
+    /// What the job does, such as `start` or `stop`
+    pub job_type: String,
+    /// Either `waiting` or `running`
+    pub state: String,
+}
+
+/// The systemd manager methods Night Kitchen depends on
+pub trait SystemdManager {
+    /// Enables job and unit signals, such as `JobRemoved`, for this client
+    fn subscribe(&self) -> Result<(), dbus::Error>;
+
+    /// Enqueues a start job for the named unit, returning the job's object path
+    fn start_unit(&self, name: &str, mode: &str) -> Result<Path<'static>, dbus::Error>;
+
+    /// Looks up the object path of a loaded unit
+    fn get_unit(&self, name: &str) -> Result<Path<'static>, dbus::Error>;
+
+    /// Lists the jobs that are currently queued
+    fn list_jobs(&self) -> Result<Vec<Job>, dbus::Error>;
+}
+
+impl<T: OrgFreedesktopSystemd1Manager> SystemdManager for T {
+    fn subscribe(&self) -> Result<(), dbus::Error> {
+        OrgFreedesktopSystemd1Manager::subscribe(self)
+    }
+
+    fn start_unit(&self, name: &str, mode: &str) -> Result<Path<'static>, dbus::Error> {
+        OrgFreedesktopSystemd1Manager::start_unit(self, name, mode)
+    }
+
+    fn get_unit(&self, name: &str) -> Result<Path<'static>, dbus::Error> {
+        OrgFreedesktopSystemd1Manager::get_unit(self, name)
+    }
+
+    fn list_jobs(&self) -> Result<Vec<Job>, dbus::Error> {
+        let jobs = OrgFreedesktopSystemd1Manager::list_jobs(self)?;
+        Ok(jobs
+            .into_iter()
+            .map(|(id, unit, job_type, state, _, _)| Job {
+                id,
+                unit,
+                job_type,
+                state,
+            })
+            .collect())
+    }
+}
+
+/// Starts the given systemd unit and blocks until it has started. Returns how each job that finished in the meantime
+/// went, ending with the job for `unit` itself.
+pub fn start_unit(logger: &Logger, conn: &mut Connection, unit: &str) -> Result<Vec<UnitOutcome>> {
+    start_unit_until(logger, conn, unit, None)
+}
+
+/// Starts the given systemd unit like [`start_unit`](fn.start_unit.html), but fails if it hasn't started by
+/// `deadline`. The job is left queued in that case.
+pub fn start_unit_by(
+    logger: &Logger,
+    conn: &mut Connection,
+    unit: &str,
+    deadline: Instant,
+) -> Result<Vec<UnitOutcome>> {
+    start_unit_until(logger, conn, unit, Some(deadline))
+}
+
+fn start_unit_until(
+    logger: &Logger,
+    conn: &mut Connection,
+    unit: &str,
+    deadline: Option<Instant>,
+) -> Result<Vec<UnitOutcome>> {
+    let manager = systemd_manager(conn);
+
+    SystemdManager::subscribe(&manager).context("Could not subscribe to systemd signals")?;
+
+    let started = Arc::new(AtomicBool::new(false));
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+    let token = {
+        let logger = logger.clone();
+        let started = started.clone();
+        let outcomes = outcomes.clone();
+        let unit = unit.to_string();
+
+        manager.match_signal(move |j: OrgFreedesktopSystemd1ManagerJobRemoved, _: &Connection, _: &Message| {
+            debug!(&logger, "Job for {} completed with result: {}", j.arg2, j.arg3; "unit" => &j.arg2, "result" => &j.arg3, "job" => %j.arg1, "id" => j.arg0);
+            if let Ok(mut outcomes) = outcomes.lock() {
+                outcomes.push(UnitOutcome { unit: j.arg2.clone(), result: j.arg3 });
+            }
+            if j.arg2 == unit {
+                started.store(true, Ordering::Relaxed);
+                false
+            } else {
+                true
+            }
+        }).context("Could not listen for job signals")?
+    };
+
+    match SystemdManager::start_unit(&manager, unit, "fail") {
+        Ok(job) => {
+            debug!(logger, "Started job {} for {}", job, unit; "job" => %job, "unit" => unit);
+        }
+        Err(err) => {
+            error!(logger, "Failed to start {}", unit; "unit" => unit, "error" => ?err);
+            return Err(err.into());
+        }
+    };
+
+    while !started.load(Ordering::Relaxed) {
+        let mut timeout = Duration::from_millis(500);
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                let _ = conn.remove_match(token);
+                bail!("Timed out waiting for {} to start", unit);
+            }
+            timeout = timeout.min(deadline - now);
+        }
+        conn.process(timeout)
+            .context("Failed waiting for D-Bus signals from systemd")?;
+    }
+
+    let outcomes = outcomes
+        .lock()
+        .map_err(|_| anyhow!("Mutex containing job outcomes was poisoned"))?
+        .clone();
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::{o, Discard};
+
+    use super::*;
+    use crate::dbus::testing::{FakeSystemd, TestBus};
+
+    #[test]
+    fn start_unit_waits_for_job() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        systemd.set_job_result("night-kitchen-daily.target", "failed");
+        let mut conn = bus.connect().unwrap();
+
+        let outcomes = start_unit(
+            &Logger::root(Discard, o!()),
+            &mut conn,
+            "night-kitchen-daily.target",
+        )
+        .unwrap();
 
-    Ok(connection.with_proxy("org.freedesktop.systemd1", unit_path, PROXY_TIMEOUT))
+        assert_eq!(systemd.started_units(), vec!["night-kitchen-daily.target"]);
+        assert_eq!(
+            outcomes,
+            vec![UnitOutcome {
+                unit: "night-kitchen-daily.target".to_string(),
+                result: "failed".to_string()
+            }]
+        );
+    }
 }
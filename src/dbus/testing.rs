@@ -0,0 +1,695 @@
+//! Fake logind, systemd and UPower services for testing code that talks to them over D-Bus.
+//!
+//! [`TestBus`](struct.TestBus.html) starts a private `dbus-daemon --session` instance, so tests never touch the real system bus. The fakes
+//! claim the usual well-known names on that bus, which means the proxies from [`login_manager`](../fn.login_manager.html)
+//! and [`systemd_manager`](../fn.systemd_manager.html) work against them unchanged. Each fake answers method calls on a
+//! background thread, records what it was asked to do, and can emit the signals the real services would.
+//!
+//! These require `dbus-daemon` to be on the `PATH`.
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use dbus::arg::{OwnedFd, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::blocking::Connection;
+use dbus::channel::{Channel, MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::strings::ErrorName;
+use dbus::{Message, Path};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::pipe;
+
+use crate::dbus::logind::{
+    OrgFreedesktopLogin1ManagerPrepareForShutdown, OrgFreedesktopLogin1ManagerPrepareForSleep,
+};
+use crate::dbus::systemd::OrgFreedesktopSystemd1ManagerJobRemoved;
+
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const SYSTEMD_NAME: &str = "org.freedesktop.systemd1";
+const SYSTEMD_PATH: &str = "/org/freedesktop/systemd1";
+const SYSTEMD_MANAGER_INTERFACE: &str = "org.freedesktop.systemd1.Manager";
+const SYSTEMD_TIMER_INTERFACE: &str = "org.freedesktop.systemd1.Timer";
+const UPOWER_NAME: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const UPOWER_INTERFACE: &str = "org.freedesktop.UPower";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// How often service threads check for outgoing signals and shutdown requests
+const SERVICE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A private D-Bus daemon, which is stopped when this is dropped
+pub struct TestBus {
+    daemon: Child,
+    address: String,
+}
+
+impl TestBus {
+    /// Starts a new bus daemon using the standard session bus configuration.
+    pub fn start() -> Result<TestBus> {
+        let mut daemon = Command::new("dbus-daemon")
+            .arg("--session")
+            .arg("--nofork")
+            .arg("--print-address")
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Could not start dbus-daemon")?;
+
+        let stdout = daemon
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("dbus-daemon has no stdout"))?;
+        let mut address = String::new();
+        BufReader::new(stdout)
+            .read_line(&mut address)
+            .context("Could not read bus address from dbus-daemon")?;
+        let address = address.trim().to_string();
+        if address.is_empty() {
+            let _ = daemon.kill();
+            bail!("dbus-daemon exited without printing its address");
+        }
+
+        Ok(TestBus { daemon, address })
+    }
+
+    /// The address clients can connect to this bus on
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Opens a new connection to this bus.
+    pub fn connect(&self) -> Result<Connection> {
+        let mut channel = Channel::open_private(&self.address)
+            .with_context(|| format!("Could not connect to test bus at {}", self.address))?;
+        channel
+            .register()
+            .context("Could not register with test bus")?;
+        Ok(Connection::from(channel))
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// Processes messages on `conn` until `condition` holds, failing if that takes longer than `timeout`.
+pub fn process_until<F: FnMut() -> bool>(
+    conn: &Connection,
+    timeout: Duration,
+    mut condition: F,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    while !condition() {
+        if Instant::now() >= deadline {
+            bail!("Condition not met within {:?}", timeout);
+        }
+        conn.process(SERVICE_POLL_INTERVAL)
+            .context("Could not process D-Bus messages")?;
+    }
+    Ok(())
+}
+
+/// Serves a fake D-Bus service from a background thread. `handler` is called for every method call the service
+/// receives, and returns the messages to send in response (usually a method return, possibly followed by signals).
+struct ServiceThread {
+    outgoing: mpsc::Sender<Message>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ServiceThread {
+    fn spawn<H>(bus: &TestBus, name: &'static str, mut handler: H) -> Result<ServiceThread>
+    where
+        H: FnMut(&Message) -> Vec<Message> + Send + 'static,
+    {
+        let conn = bus.connect()?;
+        conn.request_name(name, false, true, true)
+            .with_context(|| format!("Could not claim {} on test bus", name))?;
+
+        let (outgoing, queued) = mpsc::channel::<Message>();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                conn.start_receive(
+                    MatchRule::new_method_call(),
+                    Box::new(move |call, conn| {
+                        for message in handler(&call) {
+                            let _ = conn.send(message);
+                        }
+                        true
+                    }),
+                );
+
+                while !stop.load(Ordering::SeqCst) {
+                    if conn.process(SERVICE_POLL_INTERVAL).is_err() {
+                        break;
+                    }
+                    while let Ok(message) = queued.try_recv() {
+                        let _ = conn.send(message);
+                    }
+                }
+            })
+        };
+
+        Ok(ServiceThread {
+            outgoing,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Sends a message, such as a signal, from this service's connection.
+    fn send(&self, message: Message) {
+        let _ = self.outgoing.send(message);
+    }
+}
+
+impl Drop for ServiceThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn lock<T>(state: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panicking test thread shouldn't hide the state from the assertions that follow
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn is_call(message: &Message, interface: &str, member: &str) -> bool {
+    message.interface().as_deref() == Some(interface) && message.member().as_deref() == Some(member)
+}
+
+fn error_reply(call: &Message, name: &'static str, description: &str) -> Vec<Message> {
+    let description = CString::new(description).unwrap_or_default();
+    vec![call.error(&ErrorName::from(name), &description)]
+}
+
+fn invalid_args(call: &Message) -> Vec<Message> {
+    error_reply(
+        call,
+        "org.freedesktop.DBus.Error.InvalidArgs",
+        "Invalid arguments",
+    )
+}
+
+fn unknown_method(call: &Message) -> Vec<Message> {
+    error_reply(
+        call,
+        "org.freedesktop.DBus.Error.UnknownMethod",
+        "Not implemented by the fake service",
+    )
+}
+
+/// An inhibitor lock handed out by [`FakeLogind`](struct.FakeLogind.html)
+#[derive(Debug)]
+pub struct Inhibitor {
+    /// The lock types, such as `sleep:shutdown`
+    pub what: String,
+    /// Who took the lock
+    pub who: String,
+    /// Why the lock was taken
+    pub why: String,
+    /// Either `block` or `delay`
+    pub mode: String,
+    /// The read end of a pipe whose write end was given to the client. Once the client closes its end, the lock is
+    /// released.
+    pipe: File,
+}
+
+impl Inhibitor {
+    fn is_released(&self) -> bool {
+        let mut fds = [PollFd::new(self.pipe.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, 0) {
+            Ok(0) => false,
+            Ok(_) => fds[0]
+                .revents()
+                .unwrap_or_else(PollFlags::empty)
+                .contains(PollFlags::POLLHUP),
+            Err(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct LogindState {
+    inhibitors: Vec<Inhibitor>,
+    power_off_calls: usize,
+    suspend_calls: usize,
+    inhibit_delay_max_usec: u64,
+    shutdown_type: Option<String>,
+    lid_closed: bool,
+    on_external_power: bool,
+}
+
+/// A fake `org.freedesktop.login1` service. It supports taking and listing inhibitor locks, powering off, suspending,
+/// and reading `InhibitDelayMaxUSec`, `LidClosed`, `OnExternalPower` and the type of the pending shutdown. It can emit
+/// the `PrepareForSleep` and `PrepareForShutdown` signals. Like the real logind, it doesn't emit `PropertiesChanged`
+/// when the lid or external power changes; [`FakeUPower`](struct.FakeUPower.html) does.
+pub struct FakeLogind {
+    state: Arc<Mutex<LogindState>>,
+    service: ServiceThread,
+}
+
+impl FakeLogind {
+    /// Starts serving a fake logind on `bus`.
+    pub fn start(bus: &TestBus) -> Result<FakeLogind> {
+        let state = Arc::new(Mutex::new(LogindState {
+            // logind's default InhibitDelayMaxSec=
+            inhibit_delay_max_usec: 5_000_000,
+            ..LogindState::default()
+        }));
+        let service = {
+            let state = state.clone();
+            ServiceThread::spawn(bus, LOGIND_NAME, move |call| {
+                FakeLogind::handle(&state, call)
+            })?
+        };
+        Ok(FakeLogind { state, service })
+    }
+
+    fn handle(state: &Mutex<LogindState>, call: &Message) -> Vec<Message> {
+        if is_call(call, LOGIND_MANAGER_INTERFACE, "Inhibit") {
+            let (what, who, why, mode) = match call.read4::<&str, &str, &str, &str>() {
+                Ok(args) => args,
+                Err(_) => return invalid_args(call),
+            };
+            let (read_end, write_end) = match pipe() {
+                Ok(fds) => fds,
+                Err(_) => {
+                    return error_reply(
+                        call,
+                        "org.freedesktop.DBus.Error.Failed",
+                        "Could not create inhibitor pipe",
+                    )
+                }
+            };
+            // Appending the write end to the reply duplicates it, so the client ends up holding the only copy
+            let (pipe, client_end) =
+                unsafe { (File::from_raw_fd(read_end), OwnedFd::from_raw_fd(write_end)) };
+            lock(state).inhibitors.push(Inhibitor {
+                what: what.to_string(),
+                who: who.to_string(),
+                why: why.to_string(),
+                mode: mode.to_string(),
+                pipe,
+            });
+            vec![call.method_return().append1(client_end)]
+        } else if is_call(call, LOGIND_MANAGER_INTERFACE, "ListInhibitors") {
+            let mut state = lock(state);
+            state
+                .inhibitors
+                .retain(|inhibitor| !inhibitor.is_released());
+            let inhibitors: Vec<_> = state
+                .inhibitors
+                .iter()
+                .map(|inhibitor| {
+                    (
+                        inhibitor.what.clone(),
+                        inhibitor.who.clone(),
+                        inhibitor.why.clone(),
+                        inhibitor.mode.clone(),
+                        0u32,
+                        0u32,
+                    )
+                })
+                .collect();
+            vec![call.method_return().append1(inhibitors)]
+        } else if is_call(call, LOGIND_MANAGER_INTERFACE, "PowerOff") {
+            lock(state).power_off_calls += 1;
+            vec![call.method_return()]
+        } else if is_call(call, LOGIND_MANAGER_INTERFACE, "Suspend") {
+            lock(state).suspend_calls += 1;
+            vec![call.method_return()]
+        } else if is_call(call, PROPERTIES_INTERFACE, "Get") {
+            match call.read2::<&str, &str>() {
+                Ok((LOGIND_MANAGER_INTERFACE, "InhibitDelayMaxUSec")) => {
+                    let usecs = lock(state).inhibit_delay_max_usec;
+                    vec![call.method_return().append1(Variant(usecs))]
+                }
+                Ok((LOGIND_MANAGER_INTERFACE, "LidClosed")) => {
+                    vec![call
+                        .method_return()
+                        .append1(Variant(lock(state).lid_closed))]
+                }
+                Ok((LOGIND_MANAGER_INTERFACE, "OnExternalPower")) => {
+                    vec![call
+                        .method_return()
+                        .append1(Variant(lock(state).on_external_power))]
+                }
+                Ok((LOGIND_MANAGER_INTERFACE, "ScheduledShutdown")) => {
+                    let kind = lock(state).shutdown_type.clone().unwrap_or_default();
+                    vec![call.method_return().append1(Variant((kind, 0u64)))]
+                }
+                Ok((LOGIND_MANAGER_INTERFACE, "PreparingForShutdownWithMetadata")) => {
+                    let mut metadata: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+                    if let Some(kind) = lock(state).shutdown_type.clone() {
+                        metadata.insert("type".to_string(), Variant(Box::new(kind)));
+                    }
+                    vec![call.method_return().append1(Variant(metadata))]
+                }
+                _ => invalid_args(call),
+            }
+        } else {
+            unknown_method(call)
+        }
+    }
+
+    /// Emits `PrepareForSleep`, with `start` set if the system is about to sleep and unset if it just resumed.
+    pub fn prepare_for_sleep(&self, start: bool) {
+        let signal = OrgFreedesktopLogin1ManagerPrepareForSleep { arg0: start };
+        self.service
+            .send(signal.to_emit_message(&Path::from(LOGIND_PATH)));
+    }
+
+    /// Emits `PrepareForShutdown`, with `start` set if the system is about to shut down and unset if a shutdown was
+    /// cancelled.
+    pub fn prepare_for_shutdown(&self, start: bool) {
+        let signal = OrgFreedesktopLogin1ManagerPrepareForShutdown { arg0: start };
+        self.service
+            .send(signal.to_emit_message(&Path::from(LOGIND_PATH)));
+    }
+
+    /// Sets the type of shutdown, such as `reboot`, reported in both `ScheduledShutdown` and
+    /// `PreparingForShutdownWithMetadata`. With `None`, neither reports a type.
+    pub fn set_shutdown_type(&self, kind: Option<&str>) {
+        lock(&self.state).shutdown_type = kind.map(str::to_string);
+    }
+
+    /// Sets what `LidClosed` reports
+    pub fn set_lid_closed(&self, closed: bool) {
+        lock(&self.state).lid_closed = closed;
+    }
+
+    /// Sets what `OnExternalPower` reports
+    pub fn set_on_external_power(&self, connected: bool) {
+        lock(&self.state).on_external_power = connected;
+    }
+
+    /// Calls `f` with the inhibitor locks that are still held. Locks that clients have released are discarded.
+    pub fn with_inhibitors<T, F: FnOnce(&[Inhibitor]) -> T>(&self, f: F) -> T {
+        let mut state = lock(&self.state);
+        state
+            .inhibitors
+            .retain(|inhibitor| !inhibitor.is_released());
+        f(&state.inhibitors)
+    }
+
+    /// How many inhibitor locks are still held
+    pub fn held_inhibitors(&self) -> usize {
+        self.with_inhibitors(|inhibitors| inhibitors.len())
+    }
+
+    /// Sets how long logind claims to wait for delay inhibitor locks.
+    pub fn set_inhibit_delay_max(&self, delay: Duration) {
+        lock(&self.state).inhibit_delay_max_usec = delay.as_micros() as u64;
+    }
+
+    /// How many times `PowerOff` was called
+    pub fn power_off_calls(&self) -> usize {
+        lock(&self.state).power_off_calls
+    }
+
+    /// How many times `Suspend` was called
+    pub fn suspend_calls(&self) -> usize {
+        lock(&self.state).suspend_calls
+    }
+}
+
+#[derive(Debug, Default)]
+struct UPowerState {
+    lid_closed: bool,
+    on_battery: bool,
+}
+
+/// A fake `org.freedesktop.UPower` service. It reports whether the lid is closed and whether the system is on battery,
+/// and emits `PropertiesChanged` when either changes.
+pub struct FakeUPower {
+    state: Arc<Mutex<UPowerState>>,
+    service: ServiceThread,
+}
+
+impl FakeUPower {
+    /// Starts serving a fake UPower on `bus`, with the lid open and the system on external power.
+    pub fn start(bus: &TestBus) -> Result<FakeUPower> {
+        let state = Arc::new(Mutex::new(UPowerState::default()));
+        let service = {
+            let state = state.clone();
+            ServiceThread::spawn(bus, UPOWER_NAME, move |call| {
+                FakeUPower::handle(&state, call)
+            })?
+        };
+        Ok(FakeUPower { state, service })
+    }
+
+    fn handle(state: &Mutex<UPowerState>, call: &Message) -> Vec<Message> {
+        if !is_call(call, PROPERTIES_INTERFACE, "Get") {
+            return unknown_method(call);
+        }
+        match call.read2::<&str, &str>() {
+            Ok((UPOWER_INTERFACE, "LidIsPresent")) => {
+                vec![call.method_return().append1(Variant(true))]
+            }
+            Ok((UPOWER_INTERFACE, "LidIsClosed")) => {
+                vec![call
+                    .method_return()
+                    .append1(Variant(lock(state).lid_closed))]
+            }
+            Ok((UPOWER_INTERFACE, "OnBattery")) => {
+                vec![call
+                    .method_return()
+                    .append1(Variant(lock(state).on_battery))]
+            }
+            _ => invalid_args(call),
+        }
+    }
+
+    /// Sets `LidIsClosed`, emitting `PropertiesChanged`
+    pub fn set_lid_closed(&self, closed: bool) {
+        lock(&self.state).lid_closed = closed;
+        self.property_changed("LidIsClosed", closed);
+    }
+
+    /// Sets `OnBattery`, emitting `PropertiesChanged`
+    pub fn set_on_battery(&self, on_battery: bool) {
+        lock(&self.state).on_battery = on_battery;
+        self.property_changed("OnBattery", on_battery);
+    }
+
+    fn property_changed(&self, name: &str, value: bool) {
+        let mut changed_properties: HashMap<String, Variant<Box<dyn RefArg>>> = HashMap::new();
+        changed_properties.insert(name.to_string(), Variant(Box::new(value)));
+        let signal = PropertiesPropertiesChanged {
+            interface_name: UPOWER_INTERFACE.to_string(),
+            changed_properties,
+            invalidated_properties: Vec::new(),
+        };
+        self.service
+            .send(signal.to_emit_message(&Path::from(UPOWER_PATH)));
+    }
+}
+
+/// The properties of a timer unit served by [`FakeSystemd`](struct.FakeSystemd.html), named after their D-Bus equivalents
+#[derive(Debug, Clone, Default)]
+pub struct FakeTimer {
+    pub next_elapse_usec_realtime: u64,
+    pub accuracy_usec: u64,
+    pub randomized_delay_usec: u64,
+    pub persistent: bool,
+    pub wake_system: bool,
+    pub timers_calendar: Vec<(String, String, u64)>,
+    pub timers_monotonic: Vec<(String, u64, u64)>,
+}
+
+impl FakeTimer {
+    fn property(&self, name: &str, reply: Message) -> Option<Message> {
+        let reply = match name {
+            "NextElapseUSecRealtime" => reply.append1(Variant(self.next_elapse_usec_realtime)),
+            "AccuracyUSec" => reply.append1(Variant(self.accuracy_usec)),
+            "RandomizedDelayUSec" => reply.append1(Variant(self.randomized_delay_usec)),
+            "Persistent" => reply.append1(Variant(self.persistent)),
+            "WakeSystem" => reply.append1(Variant(self.wake_system)),
+            "TimersCalendar" => reply.append1(Variant(self.timers_calendar.clone())),
+            "TimersMonotonic" => reply.append1(Variant(self.timers_monotonic.clone())),
+            _ => return None,
+        };
+        Some(reply)
+    }
+}
+
+#[derive(Debug, Default)]
+struct SystemdState {
+    timers: HashMap<String, FakeTimer>,
+    job_results: HashMap<String, String>,
+    started_units: Vec<String>,
+    queued_jobs: Vec<(u32, String, String)>,
+    next_job_id: u32,
+}
+
+/// A fake `org.freedesktop.systemd1` service. It supports starting units, which immediately emits `JobRemoved`, listing
+/// jobs added with [`FakeSystemd::queue_job`](struct.FakeSystemd.html#method.queue_job), and looking up the properties
+/// of timer units added with [`FakeSystemd::add_timer`](struct.FakeSystemd.html#method.add_timer).
+pub struct FakeSystemd {
+    state: Arc<Mutex<SystemdState>>,
+    _service: ServiceThread,
+}
+
+impl FakeSystemd {
+    /// Starts serving a fake systemd on `bus`.
+    pub fn start(bus: &TestBus) -> Result<FakeSystemd> {
+        let state = Arc::new(Mutex::new(SystemdState::default()));
+        let service = {
+            let state = state.clone();
+            ServiceThread::spawn(bus, SYSTEMD_NAME, move |call| {
+                FakeSystemd::handle(&state, call)
+            })?
+        };
+        Ok(FakeSystemd {
+            state,
+            _service: service,
+        })
+    }
+
+    fn handle(state: &Mutex<SystemdState>, call: &Message) -> Vec<Message> {
+        if is_call(call, SYSTEMD_MANAGER_INTERFACE, "Subscribe") {
+            vec![call.method_return()]
+        } else if is_call(call, SYSTEMD_MANAGER_INTERFACE, "StartUnit") {
+            let (name, _mode) = match call.read2::<&str, &str>() {
+                Ok(args) => args,
+                Err(_) => return invalid_args(call),
+            };
+            let mut state = lock(state);
+            state.next_job_id += 1;
+            let id = state.next_job_id;
+            let job = Path::from(format!("{}/job/{}", SYSTEMD_PATH, id));
+            let result = state
+                .job_results
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| "done".to_string());
+            state.started_units.push(name.to_string());
+
+            let job_removed = OrgFreedesktopSystemd1ManagerJobRemoved {
+                arg0: id,
+                arg1: job.clone(),
+                arg2: name.to_string(),
+                arg3: result,
+            };
+            vec![
+                call.method_return().append1(job),
+                job_removed.to_emit_message(&Path::from(SYSTEMD_PATH)),
+            ]
+        } else if is_call(call, SYSTEMD_MANAGER_INTERFACE, "ListJobs") {
+            let jobs: Vec<_> = lock(state)
+                .queued_jobs
+                .iter()
+                .map(|(id, unit, job_type)| {
+                    (
+                        *id,
+                        unit.clone(),
+                        job_type.clone(),
+                        "waiting".to_string(),
+                        Path::from(format!("{}/job/{}", SYSTEMD_PATH, id)),
+                        unit_path(unit),
+                    )
+                })
+                .collect();
+            vec![call.method_return().append1(jobs)]
+        } else if is_call(call, SYSTEMD_MANAGER_INTERFACE, "GetUnit") {
+            let name = match call.read1::<&str>() {
+                Ok(name) => name,
+                Err(_) => return invalid_args(call),
+            };
+            if lock(state).timers.contains_key(name) {
+                vec![call.method_return().append1(unit_path(name))]
+            } else {
+                error_reply(
+                    call,
+                    "org.freedesktop.systemd1.NoSuchUnit",
+                    &format!("Unit {} not loaded.", name),
+                )
+            }
+        } else if is_call(call, PROPERTIES_INTERFACE, "Get") {
+            let (interface, property) = match call.read2::<&str, &str>() {
+                Ok(args) => args,
+                Err(_) => return invalid_args(call),
+            };
+            let state = lock(state);
+            let timer = call.path().and_then(|path| {
+                state
+                    .timers
+                    .iter()
+                    .find(|(name, _)| unit_path(name) == path)
+                    .map(|(_, timer)| timer)
+            });
+            match timer {
+                Some(timer) if interface == SYSTEMD_TIMER_INTERFACE => timer
+                    .property(property, call.method_return())
+                    .into_iter()
+                    .collect(),
+                _ => invalid_args(call),
+            }
+        } else {
+            unknown_method(call)
+        }
+    }
+
+    /// Adds or replaces a timer unit.
+    pub fn add_timer<S: Into<String>>(&self, name: S, timer: FakeTimer) {
+        lock(&self.state).timers.insert(name.into(), timer);
+    }
+
+    /// Adds a job of type `job_type`, such as `start`, for `unit` to the list returned by `ListJobs`. The job stays
+    /// queued forever.
+    pub fn queue_job<S1: Into<String>, S2: Into<String>>(&self, unit: S1, job_type: S2) {
+        let mut state = lock(&self.state);
+        state.next_job_id += 1;
+        let id = state.next_job_id;
+        state.queued_jobs.push((id, unit.into(), job_type.into()));
+    }
+
+    /// Sets the result reported in `JobRemoved` when `unit` is started. By default, jobs succeed with `done`.
+    pub fn set_job_result<S1: Into<String>, S2: Into<String>>(&self, unit: S1, result: S2) {
+        lock(&self.state)
+            .job_results
+            .insert(unit.into(), result.into());
+    }
+
+    /// The units that were started, in order
+    pub fn started_units(&self) -> Vec<String> {
+        lock(&self.state).started_units.clone()
+    }
+}
+
+/// Builds the object path systemd uses for a unit, escaping characters that aren't allowed in paths
+fn unit_path(name: &str) -> Path<'static> {
+    let mut path = format!("{}/unit/", SYSTEMD_PATH);
+    for (i, byte) in name.bytes().enumerate() {
+        if byte.is_ascii_alphabetic() || (i > 0 && byte.is_ascii_digit()) {
+            path.push(byte as char);
+        } else {
+            path.push_str(&format!("_{:02x}", byte));
+        }
+    }
+    Path::from(path)
+}
@@ -0,0 +1,455 @@
+//! A persistent record of the runner's past runs.
+//!
+//! Each run is appended as a line of JSON to `runs.jsonl` in the state directory. Once that file grows past
+//! [`MAX_FILE_SIZE`](constant.MAX_FILE_SIZE.html), it's rotated to `runs.jsonl.1`, and older files are shifted along
+//! until [`ROTATED_FILES`](constant.ROTATED_FILES.html) of them are kept.
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use nix::fcntl::{flock, FlockArg};
+use serde::{Deserialize, Serialize};
+
+const HISTORY_FILE: &str = "runs.jsonl";
+
+/// How large the current history file can get before it's rotated
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// How many rotated history files are kept
+pub const ROTATED_FILES: usize = 3;
+
+/// Why the system was up for a run
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunCause {
+    /// Night Kitchen booted the system for this run
+    Boot,
+    /// Night Kitchen woke the system from suspend for this run
+    Wake,
+    /// The system was already up
+    None,
+    /// Someone started the run by hand, with `night-kitchen run-now`
+    Manual,
+}
+
+impl RunCause {
+    /// What to do with the system once a run is over, so that it goes back to how it was before Night Kitchen brought
+    /// it up
+    pub fn power_action(self) -> PowerAction {
+        match self {
+            RunCause::Boot => PowerAction::PowerOff,
+            RunCause::Wake => PowerAction::Suspend,
+            RunCause::None | RunCause::Manual => PowerAction::None,
+        }
+    }
+}
+
+impl fmt::Display for RunCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunCause::Boot => write!(f, "boot"),
+            RunCause::Wake => write!(f, "wake"),
+            RunCause::None => write!(f, "none"),
+            RunCause::Manual => write!(f, "manual"),
+        }
+    }
+}
+
+/// What the runner did with the system after a run
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerAction {
+    PowerOff,
+    Suspend,
+    None,
+}
+
+impl FromStr for PowerAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<PowerAction> {
+        match s {
+            "poweroff" => Ok(PowerAction::PowerOff),
+            "suspend" => Ok(PowerAction::Suspend),
+            "none" => Ok(PowerAction::None),
+            other => Err(anyhow!("Unknown power action: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for PowerAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerAction::PowerOff => write!(f, "poweroff"),
+            PowerAction::Suspend => write!(f, "suspend"),
+            PowerAction::None => write!(f, "none"),
+        }
+    }
+}
+
+/// How a run went overall
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RunOutcome {
+    /// The target started and every job succeeded
+    Success,
+    /// The target couldn't be started, or a job failed
+    Failure,
+    /// A job timed out
+    Timeout,
+    /// The runner didn't start the target, because a precondition such as the network being up didn't hold
+    Skipped,
+}
+
+impl FromStr for RunOutcome {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<RunOutcome> {
+        match s.trim() {
+            "success" => Ok(RunOutcome::Success),
+            "failure" => Ok(RunOutcome::Failure),
+            "timeout" => Ok(RunOutcome::Timeout),
+            "skipped" => Ok(RunOutcome::Skipped),
+            other => Err(anyhow!("Unknown run outcome: {}", other)),
+        }
+    }
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunOutcome::Success => write!(f, "success"),
+            RunOutcome::Failure => write!(f, "failure"),
+            RunOutcome::Timeout => write!(f, "timeout"),
+            RunOutcome::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// How a systemd job started as part of a run finished
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct UnitOutcome {
+    pub unit: String,
+    /// The job result reported by systemd, such as `done` or `failed`
+    pub result: String,
+}
+
+impl UnitOutcome {
+    /// Whether the job succeeded
+    pub fn succeeded(&self) -> bool {
+        self.result == "done"
+    }
+}
+
+/// A single run of a Night Kitchen target
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// The target that was started, such as `night-kitchen-daily.target`
+    pub target: String,
+    /// The timer that triggered the run, if known
+    pub timer: Option<String>,
+    pub cause: RunCause,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    /// The jobs that finished while the target was starting, including the target's own
+    pub units: Vec<UnitOutcome>,
+    /// Why the target couldn't be started, if it couldn't
+    pub error: Option<String>,
+    /// Why the runner didn't start the target, if it skipped it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    pub power_action: PowerAction,
+}
+
+impl RunRecord {
+    /// Whether the target started and every job finished successfully
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+            && self.skipped.is_none()
+            && self.units.iter().all(UnitOutcome::succeeded)
+    }
+
+    /// How the run went overall. A run where any job timed out counts as timing out, even if others failed.
+    pub fn outcome(&self) -> RunOutcome {
+        if self.skipped.is_some() {
+            RunOutcome::Skipped
+        } else if self.units.iter().any(|unit| unit.result == "timeout") {
+            RunOutcome::Timeout
+        } else if self.succeeded() {
+            RunOutcome::Success
+        } else {
+            RunOutcome::Failure
+        }
+    }
+
+    /// A run of `target` that woke the system up at 2am on 2020-03-01, finished with `result` 30 seconds later,
+    /// and powered it off again
+    #[cfg(test)]
+    pub(crate) fn sample(target: &str, result: &str) -> RunRecord {
+        use chrono::TimeZone;
+
+        let started = Utc.ymd(2020, 3, 1).and_hms(2, 0, 0);
+        RunRecord {
+            target: target.to_string(),
+            timer: Some(target.replace(".target", ".timer")),
+            cause: RunCause::Boot,
+            started,
+            finished: started + chrono::Duration::seconds(30),
+            units: vec![UnitOutcome {
+                unit: target.to_string(),
+                result: result.to_string(),
+            }],
+            error: None,
+            skipped: None,
+            power_action: PowerAction::PowerOff,
+        }
+    }
+}
+
+/// The run history stored in a directory
+#[derive(Debug, Clone)]
+pub struct History {
+    dir: PathBuf,
+}
+
+impl History {
+    /// Opens the history kept in `dir`, which is normally the [state directory](../fn.state_directory.html)
+    pub fn open<P: Into<PathBuf>>(dir: P) -> History {
+        History { dir: dir.into() }
+    }
+
+    fn file(&self, generation: usize) -> PathBuf {
+        match generation {
+            0 => self.dir.join(HISTORY_FILE),
+            n => self.dir.join(format!("{}.{}", HISTORY_FILE, n)),
+        }
+    }
+
+    /// Adds a run to the history, rotating the history files if needed.
+    pub fn append(&self, record: &RunRecord) -> Result<()> {
+        let mut line = serde_json::to_string(record).context("Could not serialize run record")?;
+        line.push('\n');
+
+        let mut file = self.lock_current()?;
+        let size = file
+            .metadata()
+            .context("Could not read size of history file")?
+            .len();
+        if size > 0 && size + line.len() as u64 > MAX_FILE_SIZE {
+            self.rotate()?;
+            file = self.lock_current()?;
+        }
+
+        file.write_all(line.as_bytes())
+            .context("Could not write to history file")
+    }
+
+    /// Opens and locks the current history file. Runners for different targets can finish at the same time, so
+    /// appending and rotating has to happen under the lock.
+    fn lock_current(&self) -> Result<File> {
+        let path = self.file(0);
+        loop {
+            let file = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(&path)
+                .with_context(|| format!("Could not open {}", path.display()))?;
+            flock(file.as_raw_fd(), FlockArg::LockExclusive)
+                .with_context(|| format!("Could not lock {}", path.display()))?;
+
+            // If another runner rotated the file while this one waited for the lock, start over with the new one
+            let locked = file
+                .metadata()
+                .with_context(|| format!("Could not read {}", path.display()))?;
+            match fs::metadata(&path) {
+                Ok(current) if current.ino() == locked.ino() && current.dev() == locked.dev() => {
+                    return Ok(file)
+                }
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Could not read {}", path.display()))
+                }
+            }
+        }
+    }
+
+    /// Shifts each history file to the next generation, dropping the oldest
+    fn rotate(&self) -> Result<()> {
+        for generation in (0..ROTATED_FILES).rev() {
+            let from = self.file(generation);
+            let to = self.file(generation + 1);
+            match fs::rename(&from, &to) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("Could not rotate {} to {}", from.display(), to.display())
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// All recorded runs, oldest first. Lines that can't be parsed, such as one cut off by a crash, are skipped.
+    pub fn runs(&self) -> Result<Vec<RunRecord>> {
+        let mut runs = Vec::new();
+        for generation in (0..=ROTATED_FILES).rev() {
+            read_runs(&self.file(generation), &mut runs)?;
+        }
+        Ok(runs)
+    }
+
+    /// The `n` most recent runs, oldest first
+    pub fn last(&self, n: usize) -> Result<Vec<RunRecord>> {
+        let mut runs = self.runs()?;
+        let skip = runs.len().saturating_sub(n);
+        Ok(runs.split_off(skip))
+    }
+
+    /// Runs that started at or after `time`, oldest first
+    pub fn since(&self, time: DateTime<Utc>) -> Result<Vec<RunRecord>> {
+        let mut runs = self.runs()?;
+        runs.retain(|run| run.started >= time);
+        Ok(runs)
+    }
+
+    /// The most recent run of `target`, if it has ever run
+    pub fn last_run_of(&self, target: &str) -> Result<Option<RunRecord>> {
+        Ok(self
+            .runs()?
+            .into_iter()
+            .rev()
+            .find(|run| run.target == target))
+    }
+}
+
+fn read_runs(path: &Path, runs: &mut Vec<RunRecord>) -> Result<()> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Could not open {}", path.display())),
+    };
+
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Could not read {}", path.display()))?;
+        if let Ok(run) = serde_json::from_str(&line) {
+            runs.push(run);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A successful run of `target`, started `minutes` after the sample run
+    fn run_at(target: &str, minutes: i64) -> RunRecord {
+        let run = RunRecord::sample(target, "done");
+        RunRecord {
+            started: run.started + Duration::minutes(minutes),
+            finished: run.finished + Duration::minutes(minutes),
+            ..run
+        }
+    }
+
+    #[test]
+    fn queries_appended_runs() {
+        let dir = TempDir::new().unwrap();
+        let history = History::open(dir.path());
+        assert!(history.runs().unwrap().is_empty());
+
+        let daily = run_at("night-kitchen-daily.target", 0);
+        let weekly = run_at("night-kitchen-weekly.target", 10);
+        let daily_again = run_at("night-kitchen-daily.target", 20);
+        for run in &[&daily, &weekly, &daily_again] {
+            history.append(run).unwrap();
+        }
+
+        assert_eq!(
+            history.runs().unwrap(),
+            vec![daily.clone(), weekly.clone(), daily_again.clone()]
+        );
+        assert_eq!(
+            history.last(2).unwrap(),
+            vec![weekly.clone(), daily_again.clone()]
+        );
+        assert_eq!(history.since(weekly.started).unwrap().len(), 2);
+        assert_eq!(
+            history.last_run_of("night-kitchen-daily.target").unwrap(),
+            Some(daily_again)
+        );
+    }
+
+    #[test]
+    fn records_why_runs_were_skipped() {
+        let dir = TempDir::new().unwrap();
+        let history = History::open(dir.path());
+        let run = run_at("night-kitchen-daily.target", 0);
+        assert_eq!(run.outcome(), RunOutcome::Success);
+        assert!(!serde_json::to_string(&run).unwrap().contains("skipped"));
+
+        let skipped = RunRecord {
+            units: Vec::new(),
+            skipped: Some("backup.example.com:22 was not reachable in time".to_string()),
+            ..run
+        };
+        history.append(&skipped).unwrap();
+        let runs = history.runs().unwrap();
+        assert_eq!(runs, vec![skipped]);
+        assert_eq!(runs[0].outcome(), RunOutcome::Skipped);
+        assert!(!runs[0].succeeded());
+    }
+
+    #[test]
+    fn skips_corrupt_lines() {
+        let dir = TempDir::new().unwrap();
+        let history = History::open(dir.path());
+        let run = run_at("night-kitchen-daily.target", 0);
+        history.append(&run).unwrap();
+        fs::write(
+            dir.path().join(HISTORY_FILE),
+            format!("{}\n{{\"target\":", serde_json::to_string(&run).unwrap()),
+        )
+        .unwrap();
+
+        assert_eq!(history.runs().unwrap(), vec![run]);
+    }
+
+    #[test]
+    fn rotates_full_files() {
+        let dir = TempDir::new().unwrap();
+        let history = History::open(dir.path());
+        let line_len = serde_json::to_string(&run_at("night-kitchen-daily.target", 0))
+            .unwrap()
+            .len() as u64
+            + 1;
+        let per_file = MAX_FILE_SIZE / line_len;
+        let total = per_file * (ROTATED_FILES as u64 + 2);
+        for i in 0..total {
+            history
+                .append(&run_at("night-kitchen-daily.target", i as i64))
+                .unwrap();
+        }
+
+        assert!(dir.path().join("runs.jsonl.3").exists());
+        assert!(!dir.path().join("runs.jsonl.4").exists());
+        let runs = history.runs().unwrap();
+        assert!(runs.len() < total as usize);
+        assert_eq!(
+            runs.last().unwrap(),
+            &run_at("night-kitchen-daily.target", total as i64 - 1)
+        );
+        assert!(runs.windows(2).all(|w| w[0].started < w[1].started));
+    }
+}
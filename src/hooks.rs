@@ -0,0 +1,201 @@
+//! Tells someone how a run went, once it's over.
+//!
+//! The runner can run a command through `/bin/sh -c`, with the run's details in `NIGHT_KITCHEN_*` environment variables
+//! and its [run record](../history/struct.RunRecord.html) as JSON on stdin, and start an instance of a template unit
+//! named after the target, such as `night-kitchen-notify@night-kitchen-daily.target.service`. Both only happen for the
+//! [outcomes](../history/enum.RunOutcome.html) they're [configured](../config/struct.HookConfig.html) for. The runner
+//! waits for them to finish, so that they aren't cut short by the system going back to sleep.
+use std::io::{ErrorKind, Write};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dbus::blocking::Connection;
+use slog::{debug, error, info, warn, Logger};
+
+use crate::config::HookConfig;
+use crate::dbus::start_unit;
+use crate::history::RunRecord;
+
+/// How long the hook command can take before it's killed, so that a stuck hook can't keep the system up
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(120);
+
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs the hooks configured for how the run in `record` went, logging any that fail
+pub fn notify(logger: &Logger, conn: &mut Connection, config: &HookConfig, record: &RunRecord) {
+    let outcome = record.outcome();
+    if !config.notifies_on(outcome) {
+        debug!(&logger, "No hooks for run outcome"; "outcome" => %outcome);
+        return;
+    }
+
+    if let Some(command) = &config.command {
+        info!(&logger, "Running hook command"; "command" => command, "outcome" => %outcome);
+        match run_command(command, record, COMMAND_TIMEOUT) {
+            Ok(status) if status.success() => (),
+            Ok(status) => warn!(&logger, "Hook command failed"; "status" => %status),
+            Err(err) => error!(&logger, "Could not run hook command: {:?}", err),
+        }
+    }
+
+    if let Some(template) = &config.unit {
+        let unit = instance_unit(template, &record.target);
+        info!(&logger, "Starting notification unit"; "unit" => &unit, "outcome" => %outcome);
+        match start_unit(logger, conn, &unit) {
+            Ok(outcomes) => {
+                if let Some(failed) = outcomes.iter().find(|outcome| !outcome.succeeded()) {
+                    warn!(&logger, "Notification unit failed"; "unit" => &failed.unit, "result" => &failed.result);
+                }
+            }
+            Err(err) => error!(&logger, "Could not start notification unit: {:?}", err),
+        }
+    }
+}
+
+/// Names the instance of `template`, such as `night-kitchen-notify@.service`, for `target`
+pub fn instance_unit(template: &str, target: &str) -> String {
+    template.replacen("@.", &format!("@{}.", target), 1)
+}
+
+/// Runs `command` with a shell, passing it `record` in its environment and on stdin, and waits up to `timeout` for it
+/// to exit
+pub fn run_command(command: &str, record: &RunRecord, timeout: Duration) -> Result<ExitStatus> {
+    let json = serde_json::to_vec(record).context("Could not serialize run record")?;
+    let mut shell = Command::new("/bin/sh");
+    shell.arg("-c").arg(command).envs(environment(record));
+    run_with_input(&mut shell, &json, timeout).context("Could not run hook command")
+}
+
+/// Runs `command` with `input` on its stdin, and waits up to `timeout` for it to exit. It's killed if it takes longer.
+pub(crate) fn run_with_input(
+    command: &mut Command,
+    input: &[u8],
+    timeout: Duration,
+) -> Result<ExitStatus> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Could not start {:?}", command))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // Commands that don't care about their input can exit without reading it
+        match stdin.write_all(input) {
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e).context("Could not write to stdin");
+            }
+            _ => (),
+        }
+    }
+
+    let give_up = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().context("Could not wait for exit")? {
+            return Ok(status);
+        }
+        if Instant::now() >= give_up {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("Took longer than {:?}", timeout);
+        }
+        thread::sleep(COMMAND_POLL_INTERVAL);
+    }
+}
+
+/// The environment variables describing `record` for the hook command
+fn environment(record: &RunRecord) -> Vec<(&'static str, String)> {
+    let failed_units: Vec<&str> = record
+        .units
+        .iter()
+        .filter(|unit| !unit.succeeded())
+        .map(|unit| unit.unit.as_str())
+        .collect();
+    vec![
+        ("NIGHT_KITCHEN_TARGET", record.target.clone()),
+        ("NIGHT_KITCHEN_OUTCOME", record.outcome().to_string()),
+        ("NIGHT_KITCHEN_CAUSE", record.cause.to_string()),
+        ("NIGHT_KITCHEN_STARTED", record.started.to_rfc3339()),
+        ("NIGHT_KITCHEN_FINISHED", record.finished.to_rfc3339()),
+        ("NIGHT_KITCHEN_FAILED_UNITS", failed_units.join(" ")),
+        (
+            "NIGHT_KITCHEN_ERROR",
+            record.error.clone().unwrap_or_default(),
+        ),
+        (
+            "NIGHT_KITCHEN_POWER_ACTION",
+            record.power_action.to_string(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use slog::{o, Discard};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::dbus::testing::{FakeSystemd, TestBus};
+
+    #[test]
+    fn passes_run_to_command() {
+        let dir = TempDir::new().unwrap();
+        let out = dir.path().join("out");
+        let command = format!(
+            "echo $NIGHT_KITCHEN_OUTCOME $NIGHT_KITCHEN_CAUSE $NIGHT_KITCHEN_FAILED_UNITS > {0}; cat >> {0}",
+            out.display()
+        );
+
+        let failed = RunRecord::sample("night-kitchen-daily.target", "timeout");
+        assert!(run_command(&command, &failed, COMMAND_TIMEOUT)
+            .unwrap()
+            .success());
+        let output = fs::read_to_string(&out).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(
+            lines.next(),
+            Some("timeout boot night-kitchen-daily.target")
+        );
+        let json: RunRecord = serde_json::from_str(lines.next().unwrap()).unwrap();
+        assert_eq!(json, failed);
+
+        assert!(!run_command("exit 3", &failed, COMMAND_TIMEOUT)
+            .unwrap()
+            .success());
+        assert!(run_command("sleep 5", &failed, Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn starts_unit_for_configured_outcomes() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let mut conn = bus.connect().unwrap();
+        let logger = Logger::root(Discard, o!());
+        let config = HookConfig {
+            unit: Some("night-kitchen-notify@.service".to_string()),
+            ..HookConfig::default()
+        };
+
+        notify(
+            &logger,
+            &mut conn,
+            &config,
+            &RunRecord::sample("night-kitchen-daily.target", "done"),
+        );
+        assert!(systemd.started_units().is_empty());
+        notify(
+            &logger,
+            &mut conn,
+            &config,
+            &RunRecord::sample("night-kitchen-daily.target", "failed"),
+        );
+        assert_eq!(
+            systemd.started_units(),
+            vec!["night-kitchen-notify@night-kitchen-daily.target.service"]
+        );
+    }
+}
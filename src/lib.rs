@@ -1,13 +1,62 @@
+//! Building blocks for Night Kitchen, which runs systemd units on a schedule even if the system is suspended or
+//! powered off.
+//!
+//! * [`activation`](activation/index.html) works out when timers will next activate
+//! * [`boot`](boot/index.html) works out whether Night Kitchen powered the system on
+//! * [`calendar`](calendar/index.html) evaluates `OnCalendar=` expressions
+//! * [`config`](config/index.html) holds the scheduler's configuration
+//! * [`dbus`](dbus/index.html) has bindings for the logind and systemd D-Bus APIs
+//! * [`history`](history/index.html) records the runner's past runs
+//! * [`hooks`](hooks/index.html) tells someone how a run went
+//! * [`metrics`](metrics/index.html) exports metrics for node_exporter
+//! * [`network`](network/index.html) waits for the network a target needs
+//! * [`notifier`](notifier/index.html) emails or posts reports of runs, retrying them until they're sent
+//! * [`platform`](platform/index.html) is the clocks and hardware Night Kitchen depends on, or a simulation of them
+//! * [`power_monitor`](power_monitor/index.html) reacts to the system suspending, resuming and shutting down
+//! * [`rtc`](rtc/index.html) reads and sets the hardware clock's wake alarm
+//! * [`run_request`](run_request/index.html) passes requests to run a target by hand to the runner
+//! * [`runner`](runner/index.html) runs a target and returns the system to the state it was in, as the runner does
+//! * [`skips`](skips/index.html) keeps track of skipped timer activations and pauses
+//! * [`time`](time/index.html) converts between the clocks systemd uses
+#[macro_use]
+extern crate nix;
+
 use std::env;
-use std::path::PathBuf;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 
+pub mod activation;
+pub mod boot;
+pub mod calendar;
+pub mod config;
 pub mod dbus;
+pub mod history;
+pub mod hooks;
+pub mod metrics;
+pub mod network;
+pub mod notifier;
+pub mod platform;
+pub mod power_monitor;
+pub mod rtc;
+pub mod run_request;
+pub mod runner;
+pub mod skips;
+pub mod time;
 
+use anyhow::{Context, Result};
+use nix::fcntl::{flock, FlockArg};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use slog::{o, Drain, Duplicate, Logger};
 use slog_async::Async;
 use slog_journald::JournaldDrain;
 use slog_term::{FullFormat, TermDecorator};
 
+/// Who the scheduler says is taking its inhibitor lock, which tells its lock apart from any others
+pub const SCHEDULER_INHIBITOR_SOURCE: &str = "Night Kitchen Scheduler";
+
 /// Creates a root logger
 pub fn root_logger() -> Logger {
     let decorator = TermDecorator::new().build();
@@ -20,11 +69,63 @@ pub fn root_logger() -> Logger {
     )
 }
 
-/// Determines where the system resume timestamp file is. The scheduler updates this whenever the system
-/// wakes from suspend, and the runner uses it to decide whether or not to re-suspend.
-pub fn resume_timestamp_file() -> PathBuf {
-    let runtime_dir = env::var("RUNTIME_DIRECTORY")
+/// Determines where Night Kitchen keeps state that has to survive shutting down, such as the
+/// [run history](history/index.html). systemd creates this directory for units with `StateDirectory=` set.
+pub fn state_directory() -> PathBuf {
+    env::var("STATE_DIRECTORY")
         .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("."));
-    runtime_dir.join("resume-timestamp")
+        .unwrap_or_else(|_| PathBuf::from("."))
+}
+
+/// Reads the JSON value kept at `path`, which is the default value if the file is missing or empty. The file is
+/// locked while it's read, so that it's never read halfway through an update.
+pub(crate) fn load_json<T: Default + DeserializeOwned>(path: &Path) -> Result<T> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e).with_context(|| format!("Could not open {}", path.display())),
+    };
+    flock(file.as_raw_fd(), FlockArg::LockShared)
+        .with_context(|| format!("Could not lock {}", path.display()))?;
+    read_json(&mut file).with_context(|| format!("Could not read {}", path.display()))
+}
+
+/// Changes the JSON value kept at `path` with `change`, which runs while the file is locked, since the scheduler and
+/// runners for different targets can change the same state at the same time. A missing or empty file holds the default
+/// value. The value is only saved if `change` succeeds.
+pub(crate) fn update_json<T, R, F>(path: &Path, change: F) -> Result<R>
+where
+    T: Default + Serialize + DeserializeOwned,
+    F: FnOnce(&mut T) -> Result<R>,
+{
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)
+        .with_context(|| format!("Could not lock {}", path.display()))?;
+
+    let mut value =
+        read_json(&mut file).with_context(|| format!("Could not read {}", path.display()))?;
+    let result = change(&mut value)?;
+
+    let contents = serde_json::to_vec(&value)
+        .with_context(|| format!("Could not serialize {}", path.display()))?;
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(&contents))
+        .with_context(|| format!("Could not write {}", path.display()))?;
+    Ok(result)
+}
+
+fn read_json<T: Default + DeserializeOwned>(file: &mut File) -> Result<T> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    if contents.trim().is_empty() {
+        return Ok(T::default());
+    }
+    Ok(serde_json::from_str(&contents)?)
 }
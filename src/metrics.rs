@@ -0,0 +1,326 @@
+//! Metrics for node_exporter's [textfile collector](https://github.com/prometheus/node_exporter#textfile-collector).
+//!
+//! The runner and scheduler each write their own file, in the Prometheus text format, to the directory set by
+//! [`METRICS_DIRECTORY_VAR`](../config/constant.METRICS_DIRECTORY_VAR.html). Files are written to a temporary name and
+//! then renamed, so that node_exporter never reads one that's only partly written.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::history::{PowerAction, RunRecord};
+use crate::update_json;
+
+/// The file the runner writes its metrics to
+pub const RUNNER_METRICS_FILE: &str = "night-kitchen-runner.prom";
+
+/// The file the scheduler writes its metrics to
+pub const SCHEDULER_METRICS_FILE: &str = "night-kitchen-scheduler.prom";
+
+const POWER_ACTIONS_FILE: &str = "power-actions.json";
+
+/// The kind of a metric family
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetricType::Gauge => write!(f, "gauge"),
+            MetricType::Counter => write!(f, "counter"),
+        }
+    }
+}
+
+/// A metric and its samples, which formats as the Prometheus text format
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricFamily {
+    name: String,
+    metric_type: MetricType,
+    help: String,
+    samples: Vec<(Vec<(String, String)>, f64)>,
+}
+
+impl MetricFamily {
+    pub fn new<S1: Into<String>, S2: Into<String>>(
+        name: S1,
+        metric_type: MetricType,
+        help: S2,
+    ) -> MetricFamily {
+        MetricFamily {
+            name: name.into(),
+            metric_type,
+            help: help.into(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Adds a sample with the given labels
+    pub fn sample(&mut self, labels: &[(&str, &str)], value: f64) -> &mut MetricFamily {
+        let labels = labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        self.samples.push((labels, value));
+        self
+    }
+}
+
+impl fmt::Display for MetricFamily {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# HELP {} {}", self.name, escape(&self.help, false))?;
+        writeln!(f, "# TYPE {} {}", self.name, self.metric_type)?;
+        for (labels, value) in &self.samples {
+            write!(f, "{}", self.name)?;
+            if !labels.is_empty() {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(name, value)| format!("{}=\"{}\"", name, escape(value, true)))
+                    .collect();
+                write!(f, "{{{}}}", labels.join(","))?;
+            }
+            writeln!(f, " {}", value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Escapes backslashes and newlines, and also double quotes in label values
+fn escape(s: &str, quotes: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes `families` to `file` in `dir`, replacing it atomically
+pub fn write_textfile(dir: &Path, file: &str, families: &[MetricFamily]) -> Result<()> {
+    let path = dir.join(file);
+    // node_exporter only reads files ending in .prom, so it skips this one while it's being written
+    let temp_path = dir.join(format!(".{}.{}", file, std::process::id()));
+
+    let contents: String = families.iter().map(MetricFamily::to_string).collect();
+    fs::write(&temp_path, contents)
+        .with_context(|| format!("Could not write {}", temp_path.display()))?;
+    if let Err(e) = fs::rename(&temp_path, &path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e).with_context(|| format!("Could not replace {}", path.display()));
+    }
+    Ok(())
+}
+
+/// How many times the runner has powered off or suspended the system. These are kept in the state directory rather
+/// than counted from the [run history](../history/index.html), since the history is rotated and the counts have to
+/// keep going up.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PowerActionCounts {
+    pub power_off: u64,
+    pub suspend: u64,
+}
+
+impl PowerActionCounts {
+    /// Adds `action` to the counts kept in `dir`, returning the updated counts
+    pub fn record(dir: &Path, action: PowerAction) -> Result<PowerActionCounts> {
+        // Runners for different targets can finish at the same time
+        update_json(
+            &dir.join(POWER_ACTIONS_FILE),
+            |counts: &mut PowerActionCounts| {
+                match action {
+                    PowerAction::PowerOff => counts.power_off += 1,
+                    PowerAction::Suspend => counts.suspend += 1,
+                    PowerAction::None => (),
+                }
+                Ok(*counts)
+            },
+        )
+    }
+}
+
+/// Builds the runner's metrics: how the last run of each target went, from `runs`, and how often the runner has
+/// powered off or suspended the system.
+pub fn runner_metrics(runs: &[RunRecord], counts: PowerActionCounts) -> Vec<MetricFamily> {
+    let mut last_runs = BTreeMap::new();
+    for run in runs {
+        last_runs.insert(run.target.as_str(), run);
+    }
+
+    let mut timestamp = MetricFamily::new(
+        "night_kitchen_last_run_timestamp_seconds",
+        MetricType::Gauge,
+        "When the last run of the target started",
+    );
+    let mut duration = MetricFamily::new(
+        "night_kitchen_last_run_duration_seconds",
+        MetricType::Gauge,
+        "How long the last run of the target took",
+    );
+    let mut success = MetricFamily::new(
+        "night_kitchen_last_run_success",
+        MetricType::Gauge,
+        "Whether the last run of the target succeeded",
+    );
+    let mut failed_units = MetricFamily::new(
+        "night_kitchen_last_run_failed_units",
+        MetricType::Gauge,
+        "How many jobs failed in the last run of the target",
+    );
+    for (target, run) in last_runs {
+        let labels = [("target", target)];
+        timestamp.sample(&labels, seconds(&run.started));
+        duration.sample(
+            &labels,
+            (run.finished - run.started).num_milliseconds() as f64 / 1000.0,
+        );
+        success.sample(&labels, if run.succeeded() { 1.0 } else { 0.0 });
+        failed_units.sample(
+            &labels,
+            run.units.iter().filter(|unit| !unit.succeeded()).count() as f64,
+        );
+    }
+
+    let mut actions = MetricFamily::new(
+        "night_kitchen_power_actions_total",
+        MetricType::Counter,
+        "How many times the runner has powered off or suspended the system after a run",
+    );
+    actions
+        .sample(&[("action", "poweroff")], counts.power_off as f64)
+        .sample(&[("action", "suspend")], counts.suspend as f64);
+
+    vec![timestamp, duration, success, failed_units, actions]
+}
+
+/// Builds the scheduler's metrics: when the system should next be woken up for a timer, and when the RTC wake alarm
+/// is set for. Either is left out if there isn't one.
+pub fn scheduler_metrics(
+    next_wake: Option<DateTime<Utc>>,
+    rtc_alarm: Option<DateTime<Utc>>,
+) -> Vec<MetricFamily> {
+    let mut next = MetricFamily::new(
+        "night_kitchen_next_wake_timestamp_seconds",
+        MetricType::Gauge,
+        "When the next Night Kitchen timer calls for waking the system",
+    );
+    if let Some(next_wake) = next_wake {
+        next.sample(&[], seconds(&next_wake));
+    }
+
+    let mut armed = MetricFamily::new(
+        "night_kitchen_rtc_alarm_armed",
+        MetricType::Gauge,
+        "Whether the RTC wake alarm is enabled",
+    );
+    armed.sample(&[], if rtc_alarm.is_some() { 1.0 } else { 0.0 });
+    let mut alarm = MetricFamily::new(
+        "night_kitchen_rtc_alarm_timestamp_seconds",
+        MetricType::Gauge,
+        "When the RTC wake alarm goes off",
+    );
+    if let Some(rtc_alarm) = rtc_alarm {
+        alarm.sample(&[], seconds(&rtc_alarm));
+    }
+
+    vec![next, armed, alarm]
+}
+
+fn seconds(time: &DateTime<Utc>) -> f64 {
+    time.timestamp_millis() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn formats_last_runs() {
+        let failed = RunRecord::sample("night-kitchen-daily.target", "failed");
+        let weekly = RunRecord::sample("night-kitchen-weekly.target", "done");
+        let daily = RunRecord::sample("night-kitchen-daily.target", "done");
+        let runs = vec![
+            failed,
+            RunRecord {
+                started: weekly.started + Duration::minutes(1),
+                finished: weekly.started + Duration::milliseconds(61_500),
+                ..weekly
+            },
+            RunRecord {
+                started: daily.started + Duration::minutes(2),
+                finished: daily.finished + Duration::minutes(2),
+                ..daily
+            },
+        ];
+        let counts = PowerActionCounts {
+            power_off: 3,
+            suspend: 1,
+        };
+        let text: String = runner_metrics(&runs, counts)
+            .iter()
+            .map(MetricFamily::to_string)
+            .collect();
+
+        assert!(text.contains(
+            "night_kitchen_last_run_timestamp_seconds{target=\"night-kitchen-daily.target\"} 1583028120\n"
+        ));
+        assert!(text.contains(
+            "night_kitchen_last_run_duration_seconds{target=\"night-kitchen-weekly.target\"} 1.5\n"
+        ));
+        assert!(text
+            .contains("night_kitchen_last_run_success{target=\"night-kitchen-daily.target\"} 1\n"));
+        assert!(text.contains(
+            "night_kitchen_last_run_failed_units{target=\"night-kitchen-daily.target\"} 0\n"
+        ));
+        assert!(text.contains("# TYPE night_kitchen_power_actions_total counter\n"));
+        assert!(text.contains("night_kitchen_power_actions_total{action=\"poweroff\"} 3\n"));
+    }
+
+    #[test]
+    fn escapes_labels() {
+        let mut family = MetricFamily::new("test", MetricType::Gauge, "Line one\nline two");
+        family.sample(&[("unit", "a\"b\\c")], 1.0);
+        assert_eq!(
+            family.to_string(),
+            "# HELP test Line one\\nline two\n# TYPE test gauge\ntest{unit=\"a\\\"b\\\\c\"} 1\n"
+        );
+    }
+
+    #[test]
+    fn counts_power_actions() {
+        let dir = TempDir::new().unwrap();
+        PowerActionCounts::record(dir.path(), PowerAction::PowerOff).unwrap();
+        PowerActionCounts::record(dir.path(), PowerAction::Suspend).unwrap();
+        PowerActionCounts::record(dir.path(), PowerAction::PowerOff).unwrap();
+        assert_eq!(
+            PowerActionCounts::record(dir.path(), PowerAction::None).unwrap(),
+            PowerActionCounts {
+                power_off: 2,
+                suspend: 1
+            }
+        );
+
+        write_textfile(
+            dir.path(),
+            SCHEDULER_METRICS_FILE,
+            &scheduler_metrics(None, None),
+        )
+        .unwrap();
+        let text = fs::read_to_string(dir.path().join(SCHEDULER_METRICS_FILE)).unwrap();
+        assert!(text.contains("night_kitchen_rtc_alarm_armed 0\n"));
+        assert!(!text.contains("\nnight_kitchen_next_wake_timestamp_seconds"));
+    }
+}
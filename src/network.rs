@@ -0,0 +1,137 @@
+//! Waits for the network a target needs before the runner starts it.
+//!
+//! After a wakeup, Wi-Fi or a VPN can take a while to come back, and tasks like backups fail if they start before it
+//! has. The runner can wait for `network-online.target`, and for TCP connections to a list of `host:port` pairs to go
+//! through, up to a [configured](../config/struct.NetworkConfig.html) deadline.
+//!
+//! `network-online.target` only says that the network came up once since boot. It stays active while the system is
+//! suspended, so after resuming from suspend only a reachability check actually waits for the network.
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use dbus::blocking::Connection;
+use slog::{debug, info, Logger};
+
+use crate::config::NetworkConfig;
+use crate::dbus::start_unit_by;
+
+/// The systemd target that's reached once the network is configured
+pub const NETWORK_ONLINE_TARGET: &str = "network-online.target";
+
+/// How long to wait between attempts to connect to a host
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The longest a single connection attempt can take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for the network `config` asks for. Fails with why if it isn't up within the configured timeout.
+pub fn wait(logger: &Logger, conn: &mut Connection, config: &NetworkConfig) -> Result<()> {
+    if !config.needs_network() {
+        return Ok(());
+    }
+    let deadline = Instant::now() + config.timeout;
+    info!(&logger, "Waiting for the network"; "timeout" => ?config.timeout, "hosts" => ?config.hosts);
+
+    if config.wait_online {
+        wait_online(logger, conn, deadline)?;
+    }
+    for host in &config.hosts {
+        wait_reachable(logger, host, deadline)?;
+    }
+    Ok(())
+}
+
+/// Starts `network-online.target` and waits until `deadline` for it to be reached
+pub fn wait_online(logger: &Logger, conn: &mut Connection, deadline: Instant) -> Result<()> {
+    let outcomes = start_unit_by(logger, conn, NETWORK_ONLINE_TARGET, deadline)
+        .with_context(|| format!("{} was not reached", NETWORK_ONLINE_TARGET))?;
+    match outcomes.last() {
+        Some(outcome) if !outcome.succeeded() => bail!(
+            "{} was not reached: job {}",
+            NETWORK_ONLINE_TARGET,
+            outcome.result
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Tries to connect to `host`, a `host:port` pair, until one connection goes through or `deadline` passes
+pub fn wait_reachable(logger: &Logger, host: &str, deadline: Instant) -> Result<()> {
+    loop {
+        let err = match connect(host, deadline) {
+            Ok(()) => {
+                debug!(&logger, "Host is reachable"; "host" => host);
+                return Ok(());
+            }
+            Err(err) => err,
+        };
+        let now = Instant::now();
+        if now + RETRY_INTERVAL >= deadline {
+            return Err(err).with_context(|| format!("{} was not reachable in time", host));
+        }
+        debug!(&logger, "Host is not reachable yet: {:#}", err; "host" => host);
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Makes a TCP connection to any of the addresses `host` resolves to, giving up on each one at `deadline`
+fn connect(host: &str, deadline: Instant) -> Result<()> {
+    let addrs = host
+        .to_socket_addrs()
+        .with_context(|| format!("Could not resolve {}", host))?;
+    let mut last_err = anyhow!("{} has no addresses", host);
+    for addr in addrs {
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .min(CONNECT_TIMEOUT);
+        if timeout == Duration::from_secs(0) {
+            break;
+        }
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => return Ok(()),
+            Err(e) => last_err = anyhow!(e).context(format!("Could not connect to {}", addr)),
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use slog::{o, Discard};
+
+    use super::*;
+    use crate::dbus::testing::{FakeSystemd, TestBus};
+
+    #[test]
+    fn waits_for_online_target_and_hosts() {
+        let bus = TestBus::start().unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let mut conn = bus.connect().unwrap();
+        let logger = Logger::root(Discard, o!());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = NetworkConfig {
+            wait_online: true,
+            hosts: vec![listener.local_addr().unwrap().to_string()],
+            timeout: Duration::from_secs(5),
+            ..NetworkConfig::default()
+        };
+
+        wait(&logger, &mut conn, &config).unwrap();
+        assert_eq!(systemd.started_units(), vec![NETWORK_ONLINE_TARGET]);
+
+        systemd.set_job_result(NETWORK_ONLINE_TARGET, "failed");
+        assert!(wait(&logger, &mut conn, &config).is_err());
+
+        // Nothing is listening once the listener is dropped
+        config.wait_online = false;
+        drop(listener);
+        config.timeout = Duration::from_secs(1);
+        let started = Instant::now();
+        assert!(wait(&logger, &mut conn, &config).is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,436 @@
+//! Emails a report of a run through the local MTA, or posts it to a webhook.
+//!
+//! Unlike the [hooks](../hooks/index.html), reports are queued in `notifications.json` in the
+//! [state directory](../fn.state_directory.html) before they're sent, and stay there until they are. The network often
+//! isn't up yet when a runner finishes soon after a wakeup, so whatever couldn't be sent is retried the next time a
+//! runner finishes, and at boot by `night-kitchen-send-reports.service`. Each queued report keeps where it's going, so that it
+//! can be retried without the runner's configuration.
+//!
+//! Emails are handed to `sendmail`, and webhooks are posted with `curl`, so that Night Kitchen doesn't need its own TLS
+//! or SMTP implementation.
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::gethostname;
+use serde::{Deserialize, Serialize};
+use slog::{debug, error, info, warn, Logger};
+
+use crate::config::HookConfig;
+use crate::history::{RunOutcome, RunRecord};
+use crate::hooks::run_with_input;
+use crate::{load_json, update_json};
+
+const QUEUE_FILE: &str = "notifications.json";
+
+/// Held while reports are being sent
+const SEND_LOCK_FILE: &str = "notifications.lock";
+
+/// How many times sending a report is tried before it's dropped
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// How long `sendmail` or `curl` can take to send a report before it's given up on
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a report is sent
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Destination {
+    /// Emailed to these addresses
+    Email { to: Vec<String> },
+    /// Posted as JSON to this URL
+    Webhook { url: String },
+}
+
+/// A report of a run waiting to be sent
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Notification {
+    pub destination: Destination,
+    /// The host the run was on, which is named in the report
+    pub host: String,
+    pub record: RunRecord,
+    /// How many times sending it has failed
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// The JSON posted to webhooks
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    host: &'a str,
+    outcome: String,
+    run: &'a RunRecord,
+}
+
+/// Queues reports of the run in `record` for the destinations in `config`, if it's configured to notify about how the
+/// run went, then tries to send everything queued in `dir`. Reports that can't be sent stay queued.
+pub fn notify(logger: &Logger, dir: &Path, config: &HookConfig, record: &RunRecord) {
+    let outcome = record.outcome();
+    let destinations = destinations(config);
+    if !destinations.is_empty() && config.notifies_on(outcome) {
+        let host = hostname();
+        let queued = update_queue(dir, |queue| {
+            queue.extend(destinations.into_iter().map(|destination| Notification {
+                destination,
+                host: host.clone(),
+                record: record.clone(),
+                attempts: 0,
+            }));
+            Ok(())
+        });
+        if let Err(err) = queued {
+            error!(&logger, "Could not queue run reports: {:?}", err);
+        }
+    } else {
+        debug!(&logger, "No reports for run outcome"; "outcome" => %outcome);
+    }
+
+    if let Err(err) = send_queued(logger, dir, config) {
+        error!(&logger, "Could not send queued run reports: {:?}", err);
+    }
+}
+
+/// Tries to send every report queued in `dir`, using the `sendmail` and `curl` binaries in `config`. Each report stays
+/// queued until it's sent, so that none are lost if the runner is killed while sending. Reports that fail are kept for
+/// next time, unless they've failed [`MAX_ATTEMPTS`](constant.MAX_ATTEMPTS.html) times.
+pub fn send_queued(logger: &Logger, dir: &Path, config: &HookConfig) -> Result<()> {
+    // Sending can take a while, so the queue isn't locked while reports are sent, and runners can still queue theirs.
+    // Only one process sends at a time though, so that no report is sent twice. Anything queued after this one starts
+    // is left for whoever queued it to send.
+    let _sending = lock_sending(dir)?;
+    for notification in queued(dir)? {
+        let target = notification.record.target.clone();
+        let result = send(config, &notification);
+        let attempts = update_queue(dir, |queue| {
+            let index = match queue.iter().position(|queued| *queued == notification) {
+                Some(index) => index,
+                None => return Ok(notification.attempts),
+            };
+            if result.is_ok() {
+                queue.remove(index);
+                return Ok(notification.attempts);
+            }
+            queue[index].attempts += 1;
+            let attempts = queue[index].attempts;
+            if attempts >= MAX_ATTEMPTS {
+                queue.remove(index);
+            }
+            Ok(attempts)
+        })?;
+
+        match result {
+            Ok(()) => {
+                info!(&logger, "Sent run report"; "target" => &target, "destination" => ?notification.destination)
+            }
+            Err(err) if attempts >= MAX_ATTEMPTS => {
+                error!(&logger, "Giving up on run report: {:?}", err; "target" => &target, "attempts" => attempts);
+            }
+            Err(err) => {
+                warn!(&logger, "Could not send run report, will retry: {:?}", err; "target" => &target, "attempts" => attempts);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits until no other process is sending the reports queued in `dir`, and stops any others from sending them until
+/// the returned file is dropped
+fn lock_sending(dir: &Path) -> Result<File> {
+    let path = dir.join(SEND_LOCK_FILE);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusive)
+        .with_context(|| format!("Could not lock {}", path.display()))?;
+    Ok(file)
+}
+
+/// Reads the reports queued in `dir`
+pub fn queued(dir: &Path) -> Result<Vec<Notification>> {
+    load_json(&dir.join(QUEUE_FILE))
+}
+
+/// Sends `notification` to its destination
+pub fn send(config: &HookConfig, notification: &Notification) -> Result<()> {
+    match &notification.destination {
+        Destination::Email { to } => {
+            let message = email(to, &notification.host, &notification.record);
+            let mut sendmail = Command::new(&config.sendmail);
+            // -i stops a line with just a dot from ending the message early
+            sendmail.arg("-i").arg("--").args(to);
+            let status = run_with_input(&mut sendmail, message.as_bytes(), SEND_TIMEOUT)
+                .context("Could not run sendmail")?;
+            if !status.success() {
+                bail!("sendmail failed with {}", status);
+            }
+        }
+        Destination::Webhook { url } => {
+            let payload = WebhookPayload {
+                host: &notification.host,
+                outcome: notification.record.outcome().to_string(),
+                run: &notification.record,
+            };
+            let json = serde_json::to_vec(&payload).context("Could not serialize run report")?;
+            let mut curl = Command::new(&config.curl);
+            curl.args(["--silent", "--show-error", "--fail"])
+                .arg("--max-time")
+                .arg(SEND_TIMEOUT.as_secs().to_string())
+                .args(["--header", "Content-Type: application/json"])
+                // Don't wait for the server to agree to take the body
+                .args(["--header", "Expect:"])
+                .args(["--data-binary", "@-", "--"])
+                .arg(url);
+            let status = run_with_input(&mut curl, &json, SEND_TIMEOUT + Duration::from_secs(5))
+                .context("Could not run curl")?;
+            if !status.success() {
+                bail!("curl failed with {}", status);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the email reporting the run in `record` on `host` to the addresses in `to`
+pub fn email(to: &[String], host: &str, record: &RunRecord) -> String {
+    let outcome = record.outcome();
+    let verb = match outcome {
+        RunOutcome::Success => "succeeded",
+        RunOutcome::Failure => "failed",
+        RunOutcome::Timeout => "timed out",
+        RunOutcome::Skipped => "was skipped",
+    };
+
+    let mut message = format!(
+        "To: {}\nSubject: Night Kitchen: {} {} on {}\nContent-Type: text/plain; charset=utf-8\n\n",
+        to.join(", "),
+        record.target,
+        verb,
+        host
+    );
+    message.push_str(&format!("Target: {}\n", record.target));
+    message.push_str(&format!("Outcome: {}\n", outcome));
+    message.push_str(&format!("Cause: {}\n", record.cause));
+    message.push_str(&format!("Started: {}\n", record.started.to_rfc2822()));
+    message.push_str(&format!("Finished: {}\n", record.finished.to_rfc2822()));
+    if let Some(error) = &record.error {
+        message.push_str(&format!("Error: {}\n", error));
+    }
+    if let Some(reason) = &record.skipped {
+        message.push_str(&format!("Skipped: {}\n", reason));
+    }
+    if !record.units.is_empty() {
+        message.push_str("\nUnits:\n");
+        for unit in &record.units {
+            message.push_str(&format!("  {}: {}\n", unit.unit, unit.result));
+        }
+    }
+    message.push_str(&format!("\nPower action: {}\n", record.power_action));
+    message
+}
+
+/// Where `config` says to send reports
+fn destinations(config: &HookConfig) -> Vec<Destination> {
+    let mut destinations = Vec::new();
+    if !config.email.is_empty() {
+        destinations.push(Destination::Email {
+            to: config.email.clone(),
+        });
+    }
+    if let Some(url) = &config.webhook {
+        destinations.push(Destination::Webhook { url: url.clone() });
+    }
+    destinations
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    match gethostname(&mut buf) {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(_) => "localhost".to_string(),
+    }
+}
+
+/// Changes the queue in `dir` with `change`, which runs while it's locked. It's only saved if `change` succeeds.
+fn update_queue<T, F: FnOnce(&mut Vec<Notification>) -> Result<T>>(
+    dir: &Path,
+    change: F,
+) -> Result<T> {
+    // Runners for different targets and night-kitchen-send-reports.service can send reports at the same time
+    update_json(&dir.join(QUEUE_FILE), change)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+
+    use slog::{o, Discard};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Serves one HTTP request for each status in `statuses`, returning the URL and the request bodies it got
+    fn http_stub(statuses: Vec<u16>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(length) = line.strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Stub\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+            bodies
+        });
+        (url, server)
+    }
+
+    #[test]
+    fn retries_webhook_until_it_succeeds() {
+        let logger = Logger::root(Discard, o!());
+        let dir = TempDir::new().unwrap();
+        let (url, server) = http_stub(vec![503, 200]);
+        let config = HookConfig {
+            webhook: Some(url.clone()),
+            ..HookConfig::default()
+        };
+
+        notify(
+            &logger,
+            dir.path(),
+            &config,
+            &RunRecord::sample("night-kitchen-daily.target", "failed"),
+        );
+        let queue = queued(dir.path()).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].destination, Destination::Webhook { url });
+        assert_eq!(queue[0].attempts, 1);
+
+        // Retrying doesn't need the webhook to still be configured
+        send_queued(&logger, dir.path(), &HookConfig::default()).unwrap();
+        assert!(queued(dir.path()).unwrap().is_empty());
+
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        let payload: serde_json::Value = serde_json::from_str(&bodies[1]).unwrap();
+        assert_eq!(payload["outcome"], "failure");
+        assert_eq!(payload["run"]["target"], "night-kitchen-daily.target");
+    }
+
+    #[test]
+    fn keeps_report_queued_while_sending() {
+        let logger = Logger::root(Discard, o!());
+        let dir = TempDir::new().unwrap();
+        // Copies the queue as it is while the report is being sent, which is what's left if the runner is killed
+        let sendmail = dir.path().join("sendmail");
+        fs::write(
+            &sendmail,
+            format!(
+                "#!/bin/sh\ncp {0}/{1} {0}/while-sending\nexit 75\n",
+                dir.path().display(),
+                QUEUE_FILE
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&sendmail, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = HookConfig {
+            email: vec!["ops@example.com".to_string()],
+            sendmail,
+            ..HookConfig::default()
+        };
+
+        notify(
+            &logger,
+            dir.path(),
+            &config,
+            &RunRecord::sample("night-kitchen-daily.target", "failed"),
+        );
+        let while_sending: Vec<Notification> =
+            serde_json::from_slice(&fs::read(dir.path().join("while-sending")).unwrap()).unwrap();
+        assert_eq!(while_sending.len(), 1);
+        assert_eq!(while_sending[0].attempts, 0);
+
+        let queue = queued(dir.path()).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue[0].attempts, 1);
+
+        for _ in 1..MAX_ATTEMPTS {
+            send_queued(&logger, dir.path(), &config).unwrap();
+        }
+        assert!(queued(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn emails_through_sendmail() {
+        let logger = Logger::root(Discard, o!());
+        let dir = TempDir::new().unwrap();
+        let sendmail = dir.path().join("sendmail");
+        fs::write(
+            &sendmail,
+            format!(
+                "#!/bin/sh\necho \"$@\" > {0}/args\ncat > {0}/message\n",
+                dir.path().display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&sendmail, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = HookConfig {
+            email: vec!["ops@example.com".to_string()],
+            sendmail,
+            ..HookConfig::default()
+        };
+
+        let succeeded = RunRecord::sample("night-kitchen-daily.target", "done");
+        notify(&logger, dir.path(), &config, &succeeded);
+        assert!(!dir.path().join("message").exists());
+
+        notify(
+            &logger,
+            dir.path(),
+            &config,
+            &RunRecord::sample("night-kitchen-daily.target", "failed"),
+        );
+        assert!(queued(dir.path()).unwrap().is_empty());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("args")).unwrap(),
+            "-i -- ops@example.com\n"
+        );
+        let message = fs::read_to_string(dir.path().join("message")).unwrap();
+        assert!(message.starts_with(
+            "To: ops@example.com\nSubject: Night Kitchen: night-kitchen-daily.target failed on "
+        ));
+        assert!(message.contains("\n  night-kitchen-daily.target: failed\n"));
+    }
+}
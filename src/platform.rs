@@ -0,0 +1,73 @@
+//! The clocks and hardware the scheduler and runner depend on, behind a trait so that they can be simulated.
+//!
+//! [`SystemPlatform`](struct.SystemPlatform.html) is the real thing. For tests and with the `testing` feature,
+//! [`SimulatedPlatform`](struct.SimulatedPlatform.html) has a clock that only moves when told to and a
+//! [`FakeRtc`](../rtc/struct.FakeRtc.html), and can "boot" when its wake alarm goes off, so that a whole shutdown and
+//! wakeup can be run through in a test without touching the hardware.
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use nix::sys::sysinfo::sysinfo;
+
+use crate::config::RtcInterface;
+use crate::rtc::{self, Adjtime, Rtc};
+
+#[cfg(any(test, feature = "testing"))]
+mod simulated;
+
+#[cfg(any(test, feature = "testing"))]
+pub use self::simulated::SimulatedPlatform;
+
+/// Where Night Kitchen gets the time from and sets wake alarms
+pub trait Platform: Send + Sync {
+    /// The current time
+    fn now(&self) -> DateTime<Utc>;
+
+    /// How long the system has been up
+    fn uptime(&self) -> Result<Duration>;
+
+    /// Opens the RTC
+    fn rtc(&self) -> Result<Box<dyn Rtc + '_>>;
+
+    /// Reads the hardware clock's settings
+    fn adjtime(&self) -> Result<Adjtime>;
+}
+
+/// The system Night Kitchen is running on
+#[derive(Debug, Copy, Clone)]
+pub struct SystemPlatform {
+    rtc_interface: RtcInterface,
+}
+
+impl SystemPlatform {
+    /// Uses the system clock, and the RTC through `rtc_interface`
+    pub fn new(rtc_interface: RtcInterface) -> SystemPlatform {
+        SystemPlatform { rtc_interface }
+    }
+}
+
+impl Default for SystemPlatform {
+    /// Uses the RTC through its ioctls, as the scheduler does by default
+    fn default() -> SystemPlatform {
+        SystemPlatform::new(RtcInterface::Ioctl)
+    }
+}
+
+impl Platform for SystemPlatform {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn uptime(&self) -> Result<Duration> {
+        Ok(sysinfo().context("Could not determine uptime")?.uptime())
+    }
+
+    fn rtc(&self) -> Result<Box<dyn Rtc + '_>> {
+        rtc::open(self.rtc_interface)
+    }
+
+    fn adjtime(&self) -> Result<Adjtime> {
+        Adjtime::read().context("Could not get hardware clock settings")
+    }
+}
@@ -0,0 +1,129 @@
+//! A simulated system for testing
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+
+use super::Platform;
+use crate::rtc::{Adjtime, FakeRtc, Rtc};
+
+/// A simulated system for testing, whose clock only moves when it's advanced or the system "boots"
+#[derive(Debug)]
+pub struct SimulatedPlatform {
+    clock: Mutex<SimulatedClock>,
+    rtc: FakeRtc,
+    adjtime: Adjtime,
+}
+
+#[derive(Debug)]
+struct SimulatedClock {
+    now: DateTime<Utc>,
+    booted: DateTime<Utc>,
+}
+
+impl SimulatedPlatform {
+    /// Creates a system that has been up since `booted`, and where it's now `now`. Its hardware clock keeps UTC and its
+    /// wake alarm is disabled.
+    pub fn new(booted: DateTime<Utc>, now: DateTime<Utc>) -> SimulatedPlatform {
+        SimulatedPlatform {
+            clock: Mutex::new(SimulatedClock { now, booted }),
+            rtc: FakeRtc::new(),
+            adjtime: Adjtime::default(),
+        }
+    }
+
+    /// The simulated RTC, to check what alarm was set
+    pub fn fake_rtc(&self) -> &FakeRtc {
+        &self.rtc
+    }
+
+    /// Moves the clock forward by `duration`
+    pub fn advance(&self, duration: chrono::Duration) {
+        let mut clock = self.lock_clock();
+        clock.now = clock.now + duration;
+    }
+
+    /// Boots the system at `time`
+    pub fn boot_at(&self, time: DateTime<Utc>) {
+        let mut clock = self.lock_clock();
+        clock.now = time;
+        clock.booted = time;
+    }
+
+    /// Lets the powered off system sleep until its wake alarm goes off, and boots it then. The alarm goes off once, so
+    /// it's disabled afterwards, as the RTC does. Fails if no alarm is set.
+    pub fn boot_at_alarm(&self) -> Result<DateTime<Utc>> {
+        let mut alarm = self.rtc.alarm_configuration()?;
+        if !alarm.enabled() {
+            bail!("No wake alarm is set, so the system would stay off");
+        }
+        let time = self.adjtime.to_datetime(&alarm.time());
+        if time < self.now() {
+            bail!("Wake alarm at {} is in the past", time);
+        }
+
+        alarm.set_enabled(false);
+        self.rtc.set_alarm_configuration(&alarm)?;
+        self.boot_at(time);
+        Ok(time)
+    }
+
+    fn lock_clock(&self) -> MutexGuard<'_, SimulatedClock> {
+        self.clock
+            .lock()
+            .expect("Mutex containing simulated clock was poisoned")
+    }
+}
+
+impl Platform for SimulatedPlatform {
+    fn now(&self) -> DateTime<Utc> {
+        self.lock_clock().now
+    }
+
+    fn uptime(&self) -> Result<Duration> {
+        let clock = self.lock_clock();
+        (clock.now - clock.booted)
+            .to_std()
+            .map_err(|_| anyhow!("Simulated clock is before the system booted"))
+    }
+
+    fn rtc(&self) -> Result<Box<dyn Rtc + '_>> {
+        Ok(Box::new(&self.rtc))
+    }
+
+    fn adjtime(&self) -> Result<Adjtime> {
+        Ok(self.adjtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::rtc::RtcWakeAlarm;
+
+    #[test]
+    fn boots_at_wake_alarm() {
+        let booted = Utc.ymd(2020, 3, 1).and_hms(8, 0, 0);
+        let platform = SimulatedPlatform::new(booted, booted + chrono::Duration::hours(14));
+        assert_eq!(platform.uptime().unwrap(), Duration::from_secs(14 * 3600));
+        assert!(platform.boot_at_alarm().is_err());
+
+        let wake = Utc.ymd(2020, 3, 2).and_hms(1, 55, 0);
+        platform
+            .rtc()
+            .unwrap()
+            .set_alarm_configuration(&RtcWakeAlarm::new(true, &wake.naive_utc()))
+            .unwrap();
+
+        assert_eq!(platform.boot_at_alarm().unwrap(), wake);
+        assert_eq!(platform.now(), wake);
+        assert_eq!(platform.uptime().unwrap(), Duration::from_secs(0));
+        assert!(!platform.fake_rtc().alarm().enabled());
+
+        platform.advance(chrono::Duration::minutes(5));
+        assert_eq!(platform.uptime().unwrap(), Duration::from_secs(300));
+    }
+}
@@ -0,0 +1,952 @@
+//! Detects the system suspending, resuming and shutting down, as well as lid and power supply changes, and delays
+//! suspend and shutdown until those events have been handled.
+//!
+//! ```no_run
+//! use dbus::blocking::Connection;
+//! use night_kitchen::power_monitor::{PowerEvent, PowerMonitor};
+//! # fn main() -> anyhow::Result<()> {
+//! # let logger = night_kitchen::root_logger();
+//! let conn = Connection::new_system()?;
+//! let monitor = PowerMonitor::new(logger, "Example", "Saving state");
+//! monitor.subscribe("save state", 0, |_conn, event, deadline| {
+//!     if let PowerEvent::PreShutdown(_) = event {
+//!         if !deadline.is_expired() {
+//!             // Do any work that has to finish before the system shuts down
+//!         }
+//!     }
+//! });
+//! PowerMonitor::register(&conn, monitor)?;
+//! loop {
+//!     conn.process(std::time::Duration::from_secs(1))?;
+//! }
+//! # }
+//! ```
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use dbus::arg::{OwnedFd, RefArg};
+use dbus::blocking::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::blocking::Connection;
+use dbus::channel::MatchingReceiver;
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::Message;
+use slog::{debug, error, info, warn, Logger};
+
+use crate::dbus::logind::{
+    OrgFreedesktopLogin1ManagerPrepareForShutdown, OrgFreedesktopLogin1ManagerPrepareForSleep,
+};
+use crate::dbus::{
+    login_manager, login_manager_with_timeout, systemd_manager_with_timeout, LoginManager,
+    SystemdManager, PROXY_TIMEOUT,
+};
+
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const BUS_NAME: &str = "org.freedesktop.DBus";
+const BUS_INTERFACE: &str = "org.freedesktop.DBus";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const UPOWER_NAME: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const UPOWER_INTERFACE: &str = "org.freedesktop.UPower";
+
+/// logind's default `InhibitDelayMaxSec=`, used if the configured value can't be read
+pub const DEFAULT_INHIBIT_DELAY_MAX: Duration = Duration::from_secs(5);
+
+/// How long before logind stops waiting that a [`Deadline`](struct.Deadline.html) expires, leaving time to release the
+/// inhibitor lock
+const DEADLINE_MARGIN: Duration = Duration::from_millis(500);
+
+/// A power event reported by logind, or by UPower for the lid and power supply
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PowerEvent {
+    /// Indicates that the system is about to suspend/sleep
+    PreSleep,
+
+    /// Indicates that the system has resumed from suspend/sleep
+    PostSleep,
+
+    /// Indicates that the system is about to shut down, and in which way
+    PreShutdown(ShutdownKind),
+
+    /// Indicates that a shutdown the system was preparing for has been cancelled
+    ShutdownCancelled,
+
+    /// Indicates that the lid was opened or closed
+    LidSwitch { closed: bool },
+
+    /// Indicates that the system switched between external power, such as an AC adapter, and its battery
+    ExternalPower { connected: bool },
+}
+
+/// The ways logind can shut the system down, named after the `type` it reports for them
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ShutdownKind {
+    PowerOff,
+    Reboot,
+    Halt,
+    Kexec,
+    SoftReboot,
+    /// logind didn't say, which older versions don't for shutdowns that weren't scheduled ahead of time
+    Unknown,
+}
+
+impl ShutdownKind {
+    /// Whether the system comes straight back up after this kind of shutdown
+    pub fn is_reboot(self) -> bool {
+        matches!(
+            self,
+            ShutdownKind::Reboot | ShutdownKind::Kexec | ShutdownKind::SoftReboot
+        )
+    }
+}
+
+impl FromStr for ShutdownKind {
+    type Err = anyhow::Error;
+
+    /// Parses a logind shutdown type. Dry runs, such as `dry-poweroff`, are treated like the real thing.
+    fn from_str(s: &str) -> Result<ShutdownKind> {
+        match s.trim_start_matches("dry-") {
+            "poweroff" => Ok(ShutdownKind::PowerOff),
+            "reboot" => Ok(ShutdownKind::Reboot),
+            "halt" => Ok(ShutdownKind::Halt),
+            "kexec" => Ok(ShutdownKind::Kexec),
+            "soft-reboot" => Ok(ShutdownKind::SoftReboot),
+            _ => Err(anyhow!("Unknown shutdown type {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ShutdownKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ShutdownKind::PowerOff => "poweroff",
+            ShutdownKind::Reboot => "reboot",
+            ShutdownKind::Halt => "halt",
+            ShutdownKind::Kexec => "kexec",
+            ShutdownKind::SoftReboot => "soft-reboot",
+            ShutdownKind::Unknown => "unknown",
+        })
+    }
+}
+
+/// How long subscribers have to handle a power event.
+///
+/// logind only waits up to `InhibitDelayMaxSec=` for delay inhibitor locks to be released before suspending or shutting
+/// down anyway. Subscribers should check their deadline before starting anything that can't safely be interrupted, and
+/// skip it if there isn't enough time left.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Deadline {
+    start: Instant,
+    expires: Option<Instant>,
+}
+
+impl Deadline {
+    /// A deadline starting now for an event that logind waits at most `inhibit_delay_max` for. It expires slightly
+    /// before logind stops waiting, so that there's time to release the inhibitor lock.
+    pub fn for_inhibit_delay(inhibit_delay_max: Duration) -> Deadline {
+        let start = Instant::now();
+        let budget = inhibit_delay_max
+            .checked_sub(DEADLINE_MARGIN)
+            .unwrap_or_default();
+        Deadline {
+            start,
+            expires: Some(start + budget),
+        }
+    }
+
+    /// No deadline, for events that nothing is waiting on
+    pub fn none() -> Deadline {
+        Deadline {
+            start: Instant::now(),
+            expires: None,
+        }
+    }
+
+    /// How much time is left, or `None` if there is no deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.expires.map(|expires| {
+            expires
+                .checked_duration_since(Instant::now())
+                .unwrap_or_default()
+        })
+    }
+
+    /// Whether there's no time left
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Some(Duration::from_secs(0))
+    }
+
+    /// Whether there's at least `duration` left
+    pub fn allows(&self, duration: Duration) -> bool {
+        match self.remaining() {
+            Some(remaining) => remaining >= duration,
+            None => true,
+        }
+    }
+
+    /// How long a D-Bus call can wait for a reply: the usual [`PROXY_TIMEOUT`](../dbus/constant.PROXY_TIMEOUT.html),
+    /// or whatever is left of the deadline if that's less
+    pub fn call_timeout(&self) -> Duration {
+        self.remaining()
+            .map_or(PROXY_TIMEOUT, |remaining| remaining.min(PROXY_TIMEOUT))
+    }
+
+    /// How long ago the deadline started
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// The targets systemd starts for each kind of shutdown
+const SHUTDOWN_TARGETS: &[(&str, ShutdownKind)] = &[
+    ("poweroff.target", ShutdownKind::PowerOff),
+    ("reboot.target", ShutdownKind::Reboot),
+    ("halt.target", ShutdownKind::Halt),
+    ("kexec.target", ShutdownKind::Kexec),
+    ("soft-reboot.target", ShutdownKind::SoftReboot),
+];
+
+/// Works out which kind of shutdown the system is preparing for, returning `ShutdownKind::Unknown` if it can't be
+/// determined.
+///
+/// Since systemd 255, logind reports the kind of every shutdown. Before that, it's only known for shutdowns that were
+/// scheduled ahead of time. As a last resort, systemd's job queue is checked for a shutdown target, which is there if
+/// the shutdown was started through systemd rather than logind.
+pub fn shutdown_kind<L: LoginManager, S: SystemdManager>(
+    logger: &Logger,
+    login_manager: &L,
+    systemd_manager: &S,
+) -> ShutdownKind {
+    match login_manager.preparing_for_shutdown_with_metadata() {
+        Ok(metadata) => {
+            if let Some(kind) = metadata.get("type").and_then(|kind| kind.0.as_str()) {
+                match kind.parse() {
+                    Ok(kind) => return kind,
+                    Err(e) => warn!(&logger, "Could not parse shutdown type"; "error" => ?e),
+                }
+            }
+        }
+        Err(e) => debug!(&logger, "Could not read PreparingForShutdownWithMetadata"; "error" => ?e),
+    }
+
+    match login_manager.scheduled_shutdown() {
+        Ok((kind, _)) if !kind.is_empty() => match kind.parse() {
+            Ok(kind) => return kind,
+            Err(e) => warn!(&logger, "Could not parse scheduled shutdown type"; "error" => ?e),
+        },
+        Ok(_) => (),
+        Err(e) => debug!(&logger, "Could not read ScheduledShutdown"; "error" => ?e),
+    }
+
+    match systemd_manager.list_jobs() {
+        Ok(jobs) => {
+            let kind = jobs
+                .iter()
+                .filter(|job| job.job_type == "start")
+                .find_map(|job| {
+                    SHUTDOWN_TARGETS
+                        .iter()
+                        .find(|(target, _)| *target == job.unit)
+                        .map(|(_, kind)| *kind)
+                });
+            if let Some(kind) = kind {
+                return kind;
+            }
+        }
+        Err(e) => debug!(&logger, "Could not list systemd jobs"; "error" => ?e),
+    }
+
+    ShutdownKind::Unknown
+}
+
+/// A function that handles power events. See [`PowerMonitor::subscribe`](struct.PowerMonitor.html#method.subscribe).
+pub type Handler = dyn Fn(&Connection, PowerEvent, &Deadline) + Send + Sync + 'static;
+
+/// Identifies a subscription so that it can be removed with
+/// [`PowerMonitor::unsubscribe`](struct.PowerMonitor.html#method.unsubscribe)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct SubscriptionId(u64);
+
+#[derive(Clone)]
+struct Subscriber {
+    id: SubscriptionId,
+    name: String,
+    priority: i32,
+    handler: Arc<Handler>,
+}
+
+/// State manager for detecting events around system suspend and shutdown.
+///
+/// Internally, `PowerMonitor` uses D-Bus signals to notice when the system is preparing to sleep or shutdown. It also
+/// uses systemd inhibitor locks to prevent the system from doing so until all of its subscribers have handled the
+/// event. Since logind only honors those locks for a limited time, subscribers are given a
+/// [`Deadline`](struct.Deadline.html) to finish by.
+///
+/// See [the systemd documentation](https://www.freedesktop.org/wiki/Software/systemd/inhibit/) for more details.
+pub struct PowerMonitor {
+    // The "who" and "why" we're taking inhibitor locks
+    inhibitor_source: String,
+    inhibitor_reason: String,
+
+    // Kept sorted in the order handlers run in
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_subscription: AtomicU64,
+    inhibitor: Mutex<Cell<Option<OwnedFd>>>,
+    // logind's InhibitDelayMaxUSec, which bounds how long the inhibitor lock can delay suspend or shutdown
+    inhibit_delay_max: Mutex<Duration>,
+    logger: Logger,
+}
+
+impl PowerMonitor {
+    /// Create a new `PowerMonitor` with no subscribers.
+    ///
+    /// The `inhibitor_source` and `inhibitor_reason` values are passed to systemd and indicate who is delaying shutdown/suspend and why, respectively.
+    pub fn new<S1: Into<String>, S2: Into<String>>(
+        logger: Logger,
+        inhibitor_source: S1,
+        inhibitor_reason: S2,
+    ) -> Arc<PowerMonitor> {
+        Arc::new(PowerMonitor {
+            inhibitor_source: inhibitor_source.into(),
+            inhibitor_reason: inhibitor_reason.into(),
+            subscribers: Mutex::new(Vec::new()),
+            next_subscription: AtomicU64::new(0),
+            inhibitor: Mutex::new(Cell::new(None)),
+            inhibit_delay_max: Mutex::new(DEFAULT_INHIBIT_DELAY_MAX),
+            logger,
+        })
+    }
+
+    /// Calls `handler` on every power event the monitor detects. `name` identifies the handler in logs.
+    ///
+    /// Handlers with a higher `priority` run first, and handlers with the same priority run in the order they
+    /// subscribed. All handlers of an event share one deadline, so handlers doing time-critical work before suspend or
+    /// shutdown should have a higher priority than ones that can be skipped. Handlers are called from within
+    /// `conn.process()`, and may subscribe or unsubscribe other handlers, which takes effect from the next event.
+    pub fn subscribe<S, F>(&self, name: S, priority: i32, handler: F) -> SubscriptionId
+    where
+        S: Into<String>,
+        F: Fn(&Connection, PowerEvent, &Deadline) + Send + Sync + 'static,
+    {
+        let id = SubscriptionId(self.next_subscription.fetch_add(1, Ordering::Relaxed));
+        let subscriber = Subscriber {
+            id,
+            name: name.into(),
+            priority,
+            handler: Arc::new(handler),
+        };
+        debug!(&self.logger, "Adding power event subscriber"; "name" => &subscriber.name, "priority" => priority);
+
+        let mut subscribers = self.lock_subscribers();
+        let position = subscribers
+            .iter()
+            .position(|s| s.priority < priority)
+            .unwrap_or_else(|| subscribers.len());
+        subscribers.insert(position, subscriber);
+        id
+    }
+
+    /// Stops calling the handler subscribed as `id`. Returns whether it was still subscribed.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.lock_subscribers();
+        let len = subscribers.len();
+        subscribers.retain(|s| s.id != id);
+        subscribers.len() != len
+    }
+
+    fn lock_subscribers(&self) -> std::sync::MutexGuard<'_, Vec<Subscriber>> {
+        // A handler panicking can't leave the list itself inconsistent
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Starts monitoring for power events on `conn`, which should be connected to the system bus, and takes the
+    /// monitor's first inhibitor lock. Subscribers are called from within `conn.process()`, so the caller must keep
+    /// processing messages on `conn` for the monitor to work.
+    ///
+    /// If the connection is lost, the same monitor can be registered on a new connection. It keeps any inhibitor
+    /// lock it already holds.
+    pub fn register(conn: &Connection, monitor: Arc<PowerMonitor>) -> Result<()> {
+        for (rule, match_str) in PowerMonitor::signal_matches() {
+            conn.add_match_no_cb(&match_str)
+                .with_context(|| format!("Could not listen for {}", match_str))?;
+            let monitor = monitor.clone();
+            conn.start_receive(
+                rule,
+                Box::new(move |message: Message, c: &Connection| {
+                    monitor.handle_signal(c, &message);
+                    true
+                }),
+            );
+        }
+        monitor.start(conn)
+    }
+
+    /// The signals the monitor handles, as rules for routing them to
+    /// [`handle_signal`](struct.PowerMonitor.html#method.handle_signal) and the match strings to add on the bus for
+    /// them. These are for connections that aren't processed by [`register`](struct.PowerMonitor.html#method.register),
+    /// such as ones driven by an async event loop.
+    ///
+    /// The monitor watches logind's `PrepareForSleep` and `PrepareForShutdown` signals, and logind restarting so that
+    /// it can take a new inhibitor lock. It also watches UPower's `LidIsClosed` and `OnBattery` properties, since logind
+    /// doesn't announce changes to its own `LidClosed` and `OnExternalPower`.
+    pub fn signal_matches() -> Vec<(MatchRule<'static>, String)> {
+        let logind_name = LOGIND_NAME.into();
+        let logind_path = LOGIND_PATH.into();
+        let rules = vec![
+            OrgFreedesktopLogin1ManagerPrepareForSleep::match_rule(
+                Some(&logind_name),
+                Some(&logind_path),
+            )
+            .static_clone(),
+            OrgFreedesktopLogin1ManagerPrepareForShutdown::match_rule(
+                Some(&logind_name),
+                Some(&logind_path),
+            )
+            .static_clone(),
+            MatchRule::new_signal(PROPERTIES_INTERFACE, "PropertiesChanged")
+                .with_sender(UPOWER_NAME)
+                .with_path(UPOWER_PATH),
+        ];
+        let mut matches: Vec<_> = rules
+            .into_iter()
+            .map(|rule| {
+                let match_str = rule.match_str();
+                (rule, match_str)
+            })
+            .collect();
+
+        let owner_changes =
+            MatchRule::new_signal(BUS_INTERFACE, "NameOwnerChanged").with_sender(BUS_NAME);
+        // MatchRule can't express arg0, so the rule is extended by hand to only get logind's name changes from the bus
+        // rather than every client connecting and disconnecting
+        let match_str = format!("{},arg0='{}'", owner_changes.match_str(), LOGIND_NAME);
+        matches.push((owner_changes, match_str));
+        matches
+    }
+
+    /// Handles `message`, if it's one of the signals in
+    /// [`signal_matches`](struct.PowerMonitor.html#method.signal_matches). Subscribers are called with `conn`, which
+    /// is also used to take and release inhibitor locks, and has to be connected to the system bus.
+    ///
+    /// Inhibitor locks are updated following the standard
+    /// [delay lock pattern](https://www.freedesktop.org/wiki/Software/systemd/inhibit/).
+    pub fn handle_signal(&self, conn: &Connection, message: &Message) {
+        if let Some(p) = OrgFreedesktopLogin1ManagerPrepareForSleep::from_message(message) {
+            self.sleep_changed(conn, p.arg0);
+        } else if let Some(p) = OrgFreedesktopLogin1ManagerPrepareForShutdown::from_message(message)
+        {
+            self.shutdown_changed(conn, p.arg0);
+        } else if let Some(p) = PropertiesPropertiesChanged::from_message(message) {
+            if message.path().as_deref() == Some(UPOWER_PATH) {
+                self.upower_changed(conn, &p);
+            }
+        } else if message.interface().as_deref() == Some(BUS_INTERFACE)
+            && message.member().as_deref() == Some("NameOwnerChanged")
+        {
+            if let Ok((LOGIND_NAME, _, new_owner)) = message.read3::<&str, &str, &str>() {
+                self.logind_owner_changed(conn, new_owner);
+            }
+        }
+    }
+
+    /// Takes the monitor's first inhibitor lock over `conn`, unless it already holds one, and reads how long logind
+    /// waits for it. Call this once the monitor is receiving its signals.
+    pub fn start(&self, conn: &Connection) -> Result<()> {
+        let manager = login_manager(conn);
+        self.take_inhibitor(&manager)
+            .context("Could not take inhibitor lock")?;
+        self.read_inhibit_delay_max(&manager);
+        Ok(())
+    }
+
+    /// Reads how long logind will wait for the inhibitor lock to be released. If it can't be read, logind's default is
+    /// assumed.
+    fn read_inhibit_delay_max<M: LoginManager>(&self, manager: &M) {
+        let inhibit_delay_max = match manager.inhibit_delay_max_usec() {
+            Ok(usecs) => Duration::from_micros(usecs),
+            Err(e) => {
+                warn!(&self.logger, "Could not read InhibitDelayMaxUSec, assuming {:?}", DEFAULT_INHIBIT_DELAY_MAX; "error" => ?e);
+                DEFAULT_INHIBIT_DELAY_MAX
+            }
+        };
+        debug!(
+            &self.logger,
+            "logind waits up to {:?} for inhibitor locks", inhibit_delay_max
+        );
+        match self.inhibit_delay_max.lock() {
+            Ok(mut max) => *max = inhibit_delay_max,
+            Err(_) => error!(
+                &self.logger,
+                "Mutex containing inhibitor delay was poisoned"
+            ),
+        }
+    }
+
+    /// Starts a deadline for handling an event that logind is waiting on
+    fn deadline(&self) -> Deadline {
+        let inhibit_delay_max = self
+            .inhibit_delay_max
+            .lock()
+            .map(|max| *max)
+            .unwrap_or(DEFAULT_INHIBIT_DELAY_MAX);
+        Deadline::for_inhibit_delay(inhibit_delay_max)
+    }
+
+    /// Calls every subscriber's handler for `event` in priority order, logging any that finish after `deadline` has
+    /// expired. Once it has, the rest are skipped, so that the inhibitor lock is released while logind still waits.
+    fn dispatch(&self, conn: &Connection, event: PowerEvent, deadline: &Deadline) {
+        // Work from a copy, so that handlers can change the subscriptions
+        let subscribers = self.lock_subscribers().clone();
+        for subscriber in subscribers {
+            if deadline.is_expired() {
+                warn!(&self.logger, "Out of time to handle {:?}, skipping subscriber", event; "subscriber" => &subscriber.name);
+                continue;
+            }
+            let started = deadline.elapsed();
+            (subscriber.handler)(conn, event, deadline);
+            let took = deadline.elapsed() - started;
+            if deadline.is_expired() {
+                warn!(
+                    &self.logger,
+                    "Handling {:?} took {:?}, which exceeded logind's inhibitor delay",
+                    event,
+                    took;
+                    "subscriber" => &subscriber.name
+                );
+            } else {
+                debug!(&self.logger, "Handled {:?} in {:?}", event, took; "subscriber" => &subscriber.name);
+            }
+        }
+    }
+
+    /// Using the given logind manager, request a `delay` inhibitor lock with the `sleep` and `shutdown` lock types.
+    /// If this monitor already holds an inhibitor lock, it will not take a new one.
+    fn take_inhibitor<M: LoginManager>(&self, manager: &M) -> Result<()> {
+        let inhibitor = self
+            .inhibitor
+            .lock()
+            .map_err(|_| anyhow!("Mutex containing inhibitor lock was poisoned"))?;
+        let new_inhibitor = match inhibitor.take() {
+            Some(fd) => Some(fd), // If we already have the lock, don't re-take it
+            None => {
+                let fd = manager
+                    .inhibit(
+                        "sleep:shutdown",
+                        &self.inhibitor_source,
+                        &self.inhibitor_reason,
+                        "delay",
+                    )
+                    .context("Failed to take inhibitor lock")?;
+                debug!(&self.logger, "Took inhibitor lock"; "fd" => ?fd);
+                Some(fd)
+            }
+        };
+        inhibitor.set(new_inhibitor);
+
+        Ok(())
+    }
+
+    /// If this monitor holds an inhibitor lock, release it.
+    fn release_inhibitor(&self) -> Result<()> {
+        // If we had an inhibitor lock, .take() will replace the Some(OwnedFd) with None
+        // Then, dropping the OwnedFd will close the file descriptor and release the lock
+        debug!(&self.logger, "Releasing inhibitor lock");
+        self.inhibitor
+            .lock()
+            .map_err(|_| anyhow!("Mutex containing inhibitor lock was poisoned"))?
+            .take();
+        Ok(())
+    }
+
+    /// Handles logind starting or stopping, which `new_owner` is empty for. Inhibitor locks taken from a previous
+    /// logind instance may not be honored, so release any held lock and take a new one from the new instance.
+    fn logind_owner_changed(&self, conn: &Connection, new_owner: &str) {
+        if new_owner.is_empty() {
+            warn!(&self.logger, "logind stopped");
+            return;
+        }
+
+        info!(&self.logger, "logind started, re-taking inhibitor lock"; "owner" => new_owner);
+        if let Err(e) = self.release_inhibitor() {
+            error!(&self.logger, "Failed to release inhibitor"; "error" => ?e);
+        }
+        let manager = login_manager(conn);
+        if let Err(e) = self.take_inhibitor(&manager) {
+            error!(&self.logger, "Failed to take inhibitor"; "error" => ?e);
+        }
+        self.read_inhibit_delay_max(&manager);
+    }
+
+    /// Handles logind announcing that the system is about to sleep, or has resumed
+    fn sleep_changed(&self, conn: &Connection, start: bool) {
+        if start {
+            info!(&self.logger, "About to sleep");
+            let deadline = self.deadline();
+            self.dispatch(conn, PowerEvent::PreSleep, &deadline);
+            if let Err(e) = self.release_inhibitor() {
+                error!(&self.logger, "Failed to release inhibitor"; "error" => ?e);
+            }
+        } else {
+            info!(&self.logger, "Resumed from sleep");
+            self.dispatch(conn, PowerEvent::PostSleep, &Deadline::none());
+            if let Err(e) = self.take_inhibitor(&login_manager(conn)) {
+                error!(&self.logger, "Failed to take inhibitor"; "error" => ?e);
+            }
+        }
+    }
+
+    /// Handles logind announcing that the system is about to shut down, or that the shutdown was cancelled
+    fn shutdown_changed(&self, conn: &Connection, start: bool) {
+        if start {
+            let deadline = self.deadline();
+            let kind = shutdown_kind(
+                &self.logger,
+                &login_manager_with_timeout(conn, deadline.call_timeout()),
+                &systemd_manager_with_timeout(conn, deadline.call_timeout()),
+            );
+            info!(&self.logger, "About to shut down"; "kind" => %kind);
+            self.dispatch(conn, PowerEvent::PreShutdown(kind), &deadline);
+            if let Err(e) = self.release_inhibitor() {
+                error!(&self.logger, "Failed to release inhibitor"; "error" => ?e);
+            }
+        } else {
+            info!(&self.logger, "Shutdown cancelled");
+            self.dispatch(conn, PowerEvent::ShutdownCancelled, &Deadline::none());
+            // The inhibitor lock was released for the shutdown, so take it again to delay the next one
+            if let Err(e) = self.take_inhibitor(&login_manager(conn)) {
+                error!(&self.logger, "Failed to take inhibitor"; "error" => ?e);
+            }
+        }
+    }
+
+    /// Handles UPower's properties changing, which is how the monitor notices the lid switch and power supply
+    fn upower_changed(&self, conn: &Connection, p: &PropertiesPropertiesChanged) {
+        if p.interface_name != UPOWER_INTERFACE {
+            return;
+        }
+        let changed = |name: &str| {
+            p.changed_properties
+                .get(name)
+                .and_then(|value| value.0.as_u64())
+                .map(|value| value != 0)
+        };
+        if let Some(closed) = changed("LidIsClosed") {
+            info!(&self.logger, "Lid switch changed"; "closed" => closed);
+            self.dispatch(conn, PowerEvent::LidSwitch { closed }, &Deadline::none());
+        }
+        if let Some(on_battery) = changed("OnBattery") {
+            info!(&self.logger, "External power changed"; "connected" => !on_battery);
+            self.dispatch(
+                conn,
+                PowerEvent::ExternalPower {
+                    connected: !on_battery,
+                },
+                &Deadline::none(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::dbus::systemd_manager;
+    use crate::dbus::testing::{process_until, FakeLogind, FakeSystemd, FakeUPower, TestBus};
+    use slog::{o, Discard, Logger};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn monitor(events: Arc<Mutex<Vec<PowerEvent>>>) -> Arc<PowerMonitor> {
+        let monitor = PowerMonitor::new(Logger::root(Discard, o!()), "Test", "Testing");
+        monitor.subscribe("test", 0, move |_, ev, _| events.lock().unwrap().push(ev));
+        monitor
+    }
+
+    #[test]
+    fn takes_delay_inhibitor() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+
+        PowerMonitor::register(&conn, monitor(Arc::new(Mutex::new(Vec::new())))).unwrap();
+
+        logind.with_inhibitors(|inhibitors| {
+            assert_eq!(inhibitors.len(), 1);
+            assert_eq!(inhibitors[0].what, "sleep:shutdown");
+            assert_eq!(inhibitors[0].who, "Test");
+            assert_eq!(inhibitors[0].mode, "delay");
+        });
+    }
+
+    #[test]
+    fn releases_inhibitor_while_asleep() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        PowerMonitor::register(&conn, monitor(events.clone())).unwrap();
+
+        logind.prepare_for_sleep(true);
+        process_until(&conn, TIMEOUT, || events.lock().unwrap().len() == 1).unwrap();
+        assert_eq!(logind.held_inhibitors(), 0);
+
+        logind.prepare_for_sleep(false);
+        process_until(&conn, TIMEOUT, || events.lock().unwrap().len() == 2).unwrap();
+        assert_eq!(logind.held_inhibitors(), 1);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![PowerEvent::PreSleep, PowerEvent::PostSleep]
+        );
+    }
+
+    #[test]
+    fn releases_inhibitor_on_shutdown() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        PowerMonitor::register(&conn, monitor(events.clone())).unwrap();
+
+        logind.prepare_for_shutdown(true);
+        process_until(&conn, TIMEOUT, || !events.lock().unwrap().is_empty()).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![PowerEvent::PreShutdown(ShutdownKind::Unknown)]
+        );
+        assert_eq!(logind.held_inhibitors(), 0);
+    }
+
+    #[test]
+    fn reports_shutdown_kind() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        logind.set_shutdown_type(Some("reboot"));
+        let conn = bus.connect().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        PowerMonitor::register(&conn, monitor(events.clone())).unwrap();
+
+        logind.prepare_for_shutdown(true);
+        process_until(&conn, TIMEOUT, || !events.lock().unwrap().is_empty()).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![PowerEvent::PreShutdown(ShutdownKind::Reboot)]
+        );
+    }
+
+    #[test]
+    fn finds_shutdown_kind_in_job_queue() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let systemd = FakeSystemd::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+        let logger = Logger::root(Discard, o!());
+
+        assert_eq!(
+            shutdown_kind(&logger, &login_manager(&conn), &systemd_manager(&conn)),
+            ShutdownKind::Unknown
+        );
+
+        systemd.queue_job("kexec.target", "start");
+        assert_eq!(
+            shutdown_kind(&logger, &login_manager(&conn), &systemd_manager(&conn)),
+            ShutdownKind::Kexec
+        );
+
+        // logind knowing the kind takes precedence
+        logind.set_shutdown_type(Some("poweroff"));
+        assert_eq!(
+            shutdown_kind(&logger, &login_manager(&conn), &systemd_manager(&conn)),
+            ShutdownKind::PowerOff
+        );
+    }
+
+    #[test]
+    fn reports_cancelled_shutdown() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        PowerMonitor::register(&conn, monitor(events.clone())).unwrap();
+
+        logind.prepare_for_shutdown(true);
+        logind.prepare_for_shutdown(false);
+        process_until(&conn, TIMEOUT, || events.lock().unwrap().len() == 2).unwrap();
+
+        assert_eq!(events.lock().unwrap()[1], PowerEvent::ShutdownCancelled);
+        process_until(&conn, TIMEOUT, || logind.held_inhibitors() == 1).unwrap();
+    }
+
+    #[test]
+    fn reports_lid_and_power_changes() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let upower = FakeUPower::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        PowerMonitor::register(&conn, monitor(events.clone())).unwrap();
+
+        // logind doesn't announce these, so they mustn't be mistaken for events
+        logind.set_lid_closed(true);
+        logind.set_on_external_power(false);
+        upower.set_lid_closed(true);
+        upower.set_on_battery(true);
+        process_until(&conn, TIMEOUT, || events.lock().unwrap().len() == 2).unwrap();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                PowerEvent::LidSwitch { closed: true },
+                PowerEvent::ExternalPower { connected: false }
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_subscribers_by_priority() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let monitor = PowerMonitor::new(Logger::root(Discard, o!()), "Test", "Testing");
+        for (name, priority) in &[("low", -10), ("first", 0), ("high", 10), ("second", 0)] {
+            let order = order.clone();
+            monitor.subscribe(*name, *priority, move |_, _, _| {
+                order.lock().unwrap().push(*name)
+            });
+        }
+        let removed = {
+            let order = order.clone();
+            monitor.subscribe("removed", 20, move |_, _, _| {
+                order.lock().unwrap().push("removed")
+            })
+        };
+        assert!(monitor.unsubscribe(removed));
+        assert!(!monitor.unsubscribe(removed));
+        PowerMonitor::register(&conn, monitor).unwrap();
+
+        logind.prepare_for_sleep(true);
+        process_until(&conn, TIMEOUT, || order.lock().unwrap().len() == 4).unwrap();
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec!["high", "first", "second", "low"]
+        );
+    }
+
+    #[test]
+    fn retakes_inhibitor_when_logind_restarts() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+        PowerMonitor::register(&conn, monitor(Arc::new(Mutex::new(Vec::new())))).unwrap();
+        assert_eq!(logind.held_inhibitors(), 1);
+
+        drop(logind);
+        let logind = FakeLogind::start(&bus).unwrap();
+        process_until(&conn, TIMEOUT, || logind.held_inhibitors() == 1).unwrap();
+    }
+
+    #[test]
+    fn callbacks_get_inhibitor_deadline() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        logind.set_inhibit_delay_max(Duration::from_secs(2));
+        let conn = bus.connect().unwrap();
+
+        let remaining = Arc::new(Mutex::new(Vec::new()));
+        let monitor = PowerMonitor::new(Logger::root(Discard, o!()), "Test", "Testing");
+        {
+            let remaining = remaining.clone();
+            monitor.subscribe("test", 0, move |_, _, deadline: &Deadline| {
+                remaining.lock().unwrap().push(deadline.remaining())
+            });
+        }
+        PowerMonitor::register(&conn, monitor).unwrap();
+
+        logind.prepare_for_sleep(true);
+        logind.prepare_for_sleep(false);
+        process_until(&conn, TIMEOUT, || remaining.lock().unwrap().len() == 2).unwrap();
+
+        let remaining = remaining.lock().unwrap();
+        let sleep_remaining = remaining[0].unwrap();
+        assert!(sleep_remaining <= Duration::from_millis(1500));
+        assert!(sleep_remaining > Duration::from_secs(1));
+        assert_eq!(remaining[1], None);
+    }
+
+    #[test]
+    fn parses_shutdown_types() {
+        assert_eq!(
+            "poweroff".parse::<ShutdownKind>().unwrap(),
+            ShutdownKind::PowerOff
+        );
+        assert_eq!(
+            "dry-reboot".parse::<ShutdownKind>().unwrap(),
+            ShutdownKind::Reboot
+        );
+        assert_eq!(
+            "soft-reboot".parse::<ShutdownKind>().unwrap(),
+            ShutdownKind::SoftReboot
+        );
+        assert!("suspend".parse::<ShutdownKind>().is_err());
+        assert_eq!(ShutdownKind::Kexec.to_string(), "kexec");
+    }
+
+    #[test]
+    fn deadline_expires_before_inhibit_delay() {
+        let deadline = Deadline::for_inhibit_delay(Duration::from_millis(100));
+        assert!(!deadline.allows(Duration::from_millis(100)));
+
+        let deadline = Deadline::for_inhibit_delay(Duration::from_millis(200));
+        assert!(deadline.is_expired());
+        assert!(!Deadline::none().is_expired());
+        assert!(Deadline::none().allows(Duration::from_secs(3600)));
+
+        assert_eq!(deadline.call_timeout(), Duration::from_secs(0));
+        assert_eq!(Deadline::none().call_timeout(), PROXY_TIMEOUT);
+        let deadline = Deadline::for_inhibit_delay(Duration::from_secs(10));
+        assert_eq!(deadline.call_timeout(), PROXY_TIMEOUT);
+    }
+
+    #[test]
+    fn skips_subscribers_once_deadline_expires() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        // Leaves a 100ms deadline
+        logind.set_inhibit_delay_max(Duration::from_millis(600));
+        let conn = bus.connect().unwrap();
+
+        let called = Arc::new(Mutex::new(Vec::new()));
+        let monitor = PowerMonitor::new(Logger::root(Discard, o!()), "Test", "Testing");
+        for &(name, priority) in &[("slow", 1), ("late", 0)] {
+            let called = called.clone();
+            monitor.subscribe(name, priority, move |_, event, _| {
+                if event == PowerEvent::PreSleep {
+                    called.lock().unwrap().push(name);
+                    thread::sleep(Duration::from_millis(200));
+                }
+            });
+        }
+        PowerMonitor::register(&conn, monitor).unwrap();
+        process_until(&conn, TIMEOUT, || logind.held_inhibitors() == 1).unwrap();
+
+        logind.prepare_for_sleep(true);
+        process_until(&conn, TIMEOUT, || logind.held_inhibitors() == 0).unwrap();
+        assert_eq!(*called.lock().unwrap(), vec!["slow"]);
+    }
+}
@@ -0,0 +1,526 @@
+//! Configuration of the RTC wake alarm
+//!
+//! The [`Rtc`](trait.Rtc.html) trait abstracts over how the alarm is accessed. [`IoctlRtc`](struct.IoctlRtc.html)
+//! uses the `/dev/rtc0` ioctls, [`SysfsRtc`](struct.SysfsRtc.html) uses the `wakealarm` sysfs attribute for RTCs that
+//! don't support those ioctls, and, for tests and with the `testing` feature, [`FakeRtc`](struct.FakeRtc.html) keeps the
+//! alarm in memory.
+use std::fmt;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc};
+use libc::{c_int, c_uchar};
+
+use crate::config::{RtcInterface, SchedulerConfig};
+
+mod adjtime;
+#[cfg(any(test, feature = "testing"))]
+mod fake;
+mod ioctl;
+mod sysfs;
+
+pub use self::adjtime::{Adjtime, ClockMode};
+#[cfg(any(test, feature = "testing"))]
+pub use self::fake::FakeRtc;
+pub use self::ioctl::IoctlRtc;
+pub use self::sysfs::SysfsRtc;
+
+/// Access to an RTC's wake alarm
+pub trait Rtc {
+    /// Read the current RTC wake alarm configuration
+    fn alarm_configuration(&self) -> Result<RtcWakeAlarm>;
+
+    /// Configure the RTC wake alarm
+    fn set_alarm_configuration(&self, alarm: &RtcWakeAlarm) -> Result<()>;
+}
+
+impl<R: Rtc + ?Sized> Rtc for &R {
+    fn alarm_configuration(&self) -> Result<RtcWakeAlarm> {
+        (**self).alarm_configuration()
+    }
+
+    fn set_alarm_configuration(&self, alarm: &RtcWakeAlarm) -> Result<()> {
+        (**self).set_alarm_configuration(alarm)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct RtcTime {
+    /// Seconds (0-60)
+    tm_sec: c_int,
+    /// Minutes (0-59)
+    tm_min: c_int,
+    /// Hours (0-23)
+    tm_hour: c_int,
+    /// Day of the month (1-31)
+    tm_mday: c_int,
+    /// Month (0-11)
+    tm_mon: c_int,
+    /// Year - 1900
+    tm_year: c_int,
+    /// Day of the week (0-6, Sunday = 0)
+    /// This is unused
+    tm_wday: c_int,
+    /// Day in the year (0-365, January 1st = 0)
+    /// This is unused
+    tm_yday: c_int,
+    /// Daylight savings time
+    /// This is unused
+    tm_isdst: c_int,
+}
+
+impl RtcTime {
+    /// Converts a RTC time to a Chrono time. This does not include timezone information, because the RTC could be set to either UTC or
+    /// the local timezone.
+    pub fn to_chrono(self) -> NaiveDateTime {
+        // See https://en.wikipedia.org/wiki/ISO_8601#Dates and man:gmtime(3) for the conversion
+        let date = NaiveDate::from_ymd(
+            self.tm_year + 1900,
+            self.tm_mon as u32 + 1,
+            self.tm_mday as u32,
+        );
+        // Linux handles leap seconds by setting tm_sec to 60, but Chrono handles them with large fractional seconds.
+        let time = if self.tm_sec == 60 {
+            NaiveTime::from_hms_milli(self.tm_hour as u32, self.tm_min as u32, 59, 1999)
+        } else {
+            NaiveTime::from_hms(self.tm_hour as u32, self.tm_min as u32, self.tm_sec as u32)
+        };
+        NaiveDateTime::new(date, time)
+    }
+
+    /// Converts a Chrono time to a RTC time.
+    pub fn from_chrono(dt: &NaiveDateTime) -> RtcTime {
+        RtcTime {
+            tm_sec: if dt.timestamp_subsec_millis() > 999 {
+                60
+            } else {
+                dt.second() as c_int
+            },
+            tm_min: dt.minute() as c_int,
+            tm_hour: dt.hour() as c_int,
+            tm_mday: dt.day() as c_int,
+            tm_mon: dt.month0() as c_int,
+            tm_year: dt.year() - 1900,
+            tm_wday: 0,
+            tm_yday: 0,
+            tm_isdst: 0,
+        }
+    }
+}
+
+/// RTC wake alarm configuration
+#[repr(C)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct RtcWakeAlarm {
+    enabled: c_uchar,
+    pending: c_uchar,
+    time: RtcTime,
+}
+
+impl RtcWakeAlarm {
+    /// Creates a new wake alarm configuration
+    pub fn new(enabled: bool, time: &NaiveDateTime) -> RtcWakeAlarm {
+        let mut alarm = RtcWakeAlarm {
+            enabled: 0,
+            pending: 0,
+            time: RtcTime::from_chrono(time),
+        };
+        alarm.set_enabled(enabled);
+        alarm
+    }
+
+    /// Is the wake alarm enabled?
+    pub fn enabled(&self) -> bool {
+        self.enabled != 0
+    }
+
+    /// When will the alarm go off?
+    pub fn time(&self) -> NaiveDateTime {
+        self.time.to_chrono()
+    }
+
+    /// Set whether the alarm should be enabled or disabled
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = if enabled { 1 } else { 0 }
+    }
+
+    /// Sets the time at which the alarm should go off
+    pub fn set_time(&mut self, time: &NaiveDateTime) {
+        self.time = RtcTime::from_chrono(time)
+    }
+}
+
+impl Default for RtcWakeAlarm {
+    /// A disabled alarm at the UNIX epoch
+    fn default() -> RtcWakeAlarm {
+        RtcWakeAlarm::new(false, &NaiveDateTime::from_timestamp(0, 0))
+    }
+}
+
+impl fmt::Display for RtcWakeAlarm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RTC alarm at {} ({})",
+            self.time(),
+            if self.enabled() {
+                "enabled"
+            } else {
+                "disabled"
+            }
+        )
+    }
+}
+
+/// Opens `rtc0` using the given interface
+pub fn open(interface: RtcInterface) -> Result<Box<dyn Rtc>> {
+    match interface {
+        RtcInterface::Ioctl => Ok(Box::new(IoctlRtc::new()?)),
+        RtcInterface::Sysfs => Ok(Box::new(SysfsRtc::new())),
+    }
+}
+
+/// What [`arm_wake_alarm`](fn.arm_wake_alarm.html) did with the RTC wake alarm
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum AlarmDecision {
+    /// The alarm was set for `time`. If another alarm was enabled, `replaced` is when it would have gone off.
+    Set {
+        time: DateTime<Utc>,
+        replaced: Option<DateTime<Utc>>,
+    },
+    /// An earlier alarm set by another program was left in place
+    KeptEarlier(DateTime<Utc>),
+    /// No alarm was set, because the timer has already elapsed
+    Skipped,
+}
+
+/// Determines when the RTC alarm should go off for a timer that next elapses at `elapse_time`.
+///
+/// The alarm is moved `wake_ahead` earlier so that the system has finished booting when the timer elapses, but is never
+/// set less than `min_lead_time` after `now`, since it might go off before shutdown completes and be missed. If the
+/// timer has already elapsed, this returns `None`: there's nothing to wake up for, and `Persistent=` timers will catch
+/// up on the next boot anyway.
+pub fn wake_time(
+    config: &SchedulerConfig,
+    now: DateTime<Utc>,
+    elapse_time: &DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>> {
+    if *elapse_time <= now {
+        return Ok(None);
+    }
+
+    let wake_ahead =
+        Duration::from_std(config.wake_ahead).context("Wake-ahead margin is too large")?;
+    let min_lead_time =
+        Duration::from_std(config.min_lead_time).context("Minimum lead time is too large")?;
+
+    Ok(Some((*elapse_time - wake_ahead).max(now + min_lead_time)))
+}
+
+/// Sets the RTC wake alarm for a timer that next elapses at `elapse_time`, unless an earlier alarm set by another
+/// program is already pending.
+///
+/// `armed` is the hardware clock time of the alarm the caller last set, if any. That alarm is always replaced, so
+/// that it can be moved later. On success, `armed` is updated to the alarm now set by the caller.
+pub fn arm_wake_alarm<R: Rtc + ?Sized>(
+    rtc: &R,
+    adjtime: &Adjtime,
+    config: &SchedulerConfig,
+    now: DateTime<Utc>,
+    elapse_time: &DateTime<Utc>,
+    armed: &mut Option<NaiveDateTime>,
+) -> Result<AlarmDecision> {
+    let alarm_time = match wake_time(config, now, elapse_time)? {
+        Some(alarm_time) => alarm_time,
+        None => return Ok(AlarmDecision::Skipped),
+    };
+
+    let mut alarm_config = rtc.alarm_configuration()?;
+    let replaced = if alarm_config.enabled() {
+        let current_alarm = adjtime.to_datetime(&alarm_config.time());
+        // An earlier alarm that's too close to go off reliably (or already went off) doesn't count
+        let reliable_after = now + Duration::from_std(config.min_lead_time)?;
+        let ours = *armed == Some(alarm_config.time());
+        if !ours && current_alarm < alarm_time && current_alarm >= reliable_after {
+            *armed = None;
+            return Ok(AlarmDecision::KeptEarlier(current_alarm));
+        }
+        Some(current_alarm)
+    } else {
+        None
+    };
+
+    alarm_config.set_enabled(true);
+    alarm_config.set_time(&adjtime.to_hardware(&alarm_time));
+    rtc.set_alarm_configuration(&alarm_config)?;
+    *armed = Some(alarm_config.time());
+
+    Ok(AlarmDecision::Set {
+        time: alarm_time,
+        replaced,
+    })
+}
+
+/// Undoes [`arm_wake_alarm`](fn.arm_wake_alarm.html) setting the alarm for the hardware clock time `armed`, by putting
+/// back `previous`, the configuration it replaced. If the alarm has been changed again since, for example by another
+/// program, it's left alone. Returns whether the alarm was restored.
+pub fn restore_wake_alarm<R: Rtc + ?Sized>(
+    rtc: &R,
+    previous: &RtcWakeAlarm,
+    armed: NaiveDateTime,
+) -> Result<bool> {
+    let current = rtc.alarm_configuration()?;
+    if !current.enabled() || current.time() != armed {
+        return Ok(false);
+    }
+
+    rtc.set_alarm_configuration(previous)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration as StdDuration;
+
+    use chrono::{Duration, TimeZone, Utc};
+
+    use super::*;
+
+    fn config() -> SchedulerConfig {
+        SchedulerConfig {
+            wake_ahead: StdDuration::from_secs(300),
+            min_lead_time: StdDuration::from_secs(60),
+            ..SchedulerConfig::default()
+        }
+    }
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2020, 3, 1).and_hms(22, 0, 0)
+    }
+
+    #[test]
+    fn wakes_ahead_of_elapse() {
+        let elapse = now() + Duration::hours(2);
+        assert_eq!(
+            wake_time(&config(), now(), &elapse).unwrap(),
+            Some(elapse - Duration::minutes(5))
+        );
+    }
+
+    #[test]
+    fn pushes_alarm_back_to_min_lead_time() {
+        let elapse = now() + Duration::seconds(30);
+        assert_eq!(
+            wake_time(&config(), now(), &elapse).unwrap(),
+            Some(now() + Duration::minutes(1))
+        );
+    }
+
+    #[test]
+    fn skips_elapsed_timers() {
+        let rtc = FakeRtc::new();
+        let mut armed = None;
+        let decision = arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &(now() - Duration::minutes(1)),
+            &mut armed,
+        )
+        .unwrap();
+
+        assert_eq!(decision, AlarmDecision::Skipped);
+        assert!(!rtc.alarm().enabled());
+        assert_eq!(armed, None);
+    }
+
+    #[test]
+    fn sets_alarm_when_none_enabled() {
+        let rtc = FakeRtc::new();
+        let mut armed = None;
+        let elapse = now() + Duration::hours(2);
+        let decision = arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &elapse,
+            &mut armed,
+        )
+        .unwrap();
+
+        let expected = elapse - Duration::minutes(5);
+        assert_eq!(
+            decision,
+            AlarmDecision::Set {
+                time: expected,
+                replaced: None
+            }
+        );
+        assert!(rtc.alarm().enabled());
+        assert_eq!(rtc.alarm().time(), expected.naive_utc());
+        assert_eq!(armed, Some(expected.naive_utc()));
+    }
+
+    #[test]
+    fn keeps_earlier_foreign_alarm() {
+        let existing = now() + Duration::hours(1);
+        let rtc = FakeRtc::with_alarm(RtcWakeAlarm::new(true, &existing.naive_utc()));
+        let mut armed = None;
+        let decision = arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &(now() + Duration::hours(2)),
+            &mut armed,
+        )
+        .unwrap();
+
+        assert_eq!(decision, AlarmDecision::KeptEarlier(existing));
+        assert_eq!(rtc.alarm().time(), existing.naive_utc());
+        assert_eq!(armed, None);
+    }
+
+    #[test]
+    fn replaces_later_alarm() {
+        let existing = now() + Duration::hours(3);
+        let rtc = FakeRtc::with_alarm(RtcWakeAlarm::new(true, &existing.naive_utc()));
+        let mut armed = None;
+        let elapse = now() + Duration::hours(2);
+        let decision = arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &elapse,
+            &mut armed,
+        )
+        .unwrap();
+
+        let expected = elapse - Duration::minutes(5);
+        assert_eq!(
+            decision,
+            AlarmDecision::Set {
+                time: expected,
+                replaced: Some(existing)
+            }
+        );
+        assert_eq!(rtc.alarm().time(), expected.naive_utc());
+    }
+
+    #[test]
+    fn replaces_stale_alarm() {
+        let existing = now() - Duration::hours(1);
+        let rtc = FakeRtc::with_alarm(RtcWakeAlarm::new(true, &existing.naive_utc()));
+        let mut armed = None;
+        let elapse = now() + Duration::hours(2);
+        let decision = arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &elapse,
+            &mut armed,
+        )
+        .unwrap();
+
+        assert_eq!(
+            decision,
+            AlarmDecision::Set {
+                time: elapse - Duration::minutes(5),
+                replaced: Some(existing)
+            }
+        );
+    }
+
+    #[test]
+    fn replaces_own_earlier_alarm() {
+        let existing = now() + Duration::hours(1);
+        let rtc = FakeRtc::with_alarm(RtcWakeAlarm::new(true, &existing.naive_utc()));
+        let mut armed = Some(existing.naive_utc());
+        let elapse = now() + Duration::hours(2);
+        let decision = arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &elapse,
+            &mut armed,
+        )
+        .unwrap();
+
+        let expected = elapse - Duration::minutes(5);
+        assert_eq!(
+            decision,
+            AlarmDecision::Set {
+                time: expected,
+                replaced: Some(existing)
+            }
+        );
+        assert_eq!(armed, Some(expected.naive_utc()));
+    }
+
+    #[test]
+    fn compensates_for_drift() {
+        // Gains 10 seconds per day, last adjusted 6 days before now
+        let adjtime = Adjtime::parse(&format!(
+            "10.0 {} 0.0\n0\nUTC\n",
+            now().timestamp() - 6 * 86400
+        ))
+        .unwrap();
+        let rtc = FakeRtc::new();
+        let mut armed = None;
+        let elapse = now() + Duration::days(1) + Duration::minutes(5);
+        arm_wake_alarm(&rtc, &adjtime, &config(), now(), &elapse, &mut armed).unwrap();
+
+        let expected = now() + Duration::days(1) + Duration::seconds(70);
+        assert_eq!(rtc.alarm().time(), expected.naive_utc());
+        // Converting back isn't exact, since drift is estimated from the uncorrected time
+        let round_trip = adjtime.to_datetime(&rtc.alarm().time()) - (now() + Duration::days(1));
+        assert_eq!(round_trip.num_seconds(), 0);
+    }
+
+    #[test]
+    fn restores_replaced_alarm() {
+        let previous = RtcWakeAlarm::new(true, &(now() + Duration::days(2)).naive_utc());
+        let rtc = FakeRtc::with_alarm(previous);
+        let mut armed = None;
+        let elapse = now() + Duration::hours(2);
+        arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &elapse,
+            &mut armed,
+        )
+        .unwrap();
+        assert_ne!(rtc.alarm(), previous);
+
+        assert!(restore_wake_alarm(&rtc, &previous, armed.unwrap()).unwrap());
+        assert_eq!(rtc.alarm(), previous);
+    }
+
+    #[test]
+    fn leaves_changed_alarm() {
+        let rtc = FakeRtc::new();
+        let mut armed = None;
+        let elapse = now() + Duration::hours(2);
+        arm_wake_alarm(
+            &rtc,
+            &Adjtime::default(),
+            &config(),
+            now(),
+            &elapse,
+            &mut armed,
+        )
+        .unwrap();
+        let changed = RtcWakeAlarm::new(true, &(now() + Duration::hours(3)).naive_utc());
+        rtc.set_alarm_configuration(&changed).unwrap();
+
+        assert!(!restore_wake_alarm(&rtc, &RtcWakeAlarm::default(), armed.unwrap()).unwrap());
+        assert_eq!(rtc.alarm(), changed);
+    }
+}
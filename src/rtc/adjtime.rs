@@ -0,0 +1,240 @@
+//! Hardware clock settings and time conversions
+use std::fs;
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use chrono::{DateTime, Duration, Local, LocalResult, NaiveDateTime, TimeZone, Utc};
+
+use crate::time::{local_to_utc, utc_offset_before};
+
+const SECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0;
+
+/// Hardware clock settings from `/etc/adjtime`, which is maintained by `hwclock` and `timedatectl`.
+///
+/// See [`man:adjtime(5)`](http://man7.org/linux/man-pages/man5/adjtime.5.html).
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Adjtime {
+    /// How much the hardware clock gains per day, in seconds. Negative if it loses time.
+    pub drift_factor: f64,
+    /// When the hardware clock was last adjusted or calibrated, in seconds since the UNIX epoch. If this is 0, the
+    /// hardware clock has never been calibrated and the drift factor is meaningless.
+    pub last_adjust_time: i64,
+    /// Adjustment, in seconds, that was not applied the last time the hardware clock was adjusted
+    pub adjust_residue: f64,
+    /// When the hardware clock was last calibrated, in seconds since the UNIX epoch
+    pub last_calibration_time: i64,
+    /// Which timezone the hardware clock uses
+    pub clock_mode: ClockMode,
+}
+
+impl Default for Adjtime {
+    /// The settings `hwclock` assumes if `/etc/adjtime` does not exist
+    fn default() -> Adjtime {
+        Adjtime {
+            drift_factor: 0.0,
+            last_adjust_time: 0,
+            adjust_residue: 0.0,
+            last_calibration_time: 0,
+            clock_mode: ClockMode::Utc,
+        }
+    }
+}
+
+impl Adjtime {
+    /// Reads the hardware clock settings from `/etc/adjtime`, using the defaults if it does not exist.
+    pub fn read() -> Result<Adjtime> {
+        match fs::read_to_string("/etc/adjtime") {
+            Ok(contents) => Adjtime::parse(&contents).context("Invalid /etc/adjtime"),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Adjtime::default()),
+            Err(e) => Err(Error::from(e)).context("Could not access /etc/adjtime"),
+        }
+    }
+
+    /// Parses the contents of `/etc/adjtime`. Like `hwclock`, this uses the defaults for the calibration time and clock
+    /// mode if their lines are missing.
+    pub fn parse(contents: &str) -> Result<Adjtime> {
+        let mut lines = contents.lines();
+        let defaults = Adjtime::default();
+
+        let mut drift_line = lines
+            .next()
+            .ok_or_else(|| anyhow!("Missing drift line"))?
+            .split_whitespace();
+        let drift_factor = drift_line
+            .next()
+            .ok_or_else(|| anyhow!("Missing drift factor"))?
+            .parse()
+            .context("Invalid drift factor")?;
+        let last_adjust_time = drift_line
+            .next()
+            .ok_or_else(|| anyhow!("Missing last adjustment time"))?
+            .parse()
+            .context("Invalid last adjustment time")?;
+        let adjust_residue = drift_line
+            .next()
+            .ok_or_else(|| anyhow!("Missing adjustment residue"))?
+            .parse()
+            .context("Invalid adjustment residue")?;
+
+        let last_calibration_time = match lines.next() {
+            Some(line) => line
+                .trim()
+                .parse()
+                .context("Invalid last calibration time")?,
+            None => defaults.last_calibration_time,
+        };
+
+        // If the mode line is missing, the default is UTC
+        let clock_mode = match lines.next().map(str::trim) {
+            Some("UTC") | None => ClockMode::Utc,
+            Some("LOCAL") => ClockMode::Local,
+            Some(other) => bail!("Invalid clock mode: {}", other),
+        };
+
+        Ok(Adjtime {
+            drift_factor,
+            last_adjust_time,
+            adjust_residue,
+            last_calibration_time,
+            clock_mode,
+        })
+    }
+
+    /// Estimates how far ahead of the system clock the hardware clock will be at `time`, based on its recorded drift.
+    /// This is the same calculation `hwclock --adjust` uses.
+    pub fn drift_at(&self, time: &DateTime<Utc>) -> Duration {
+        if self.last_adjust_time == 0 {
+            return Duration::zero();
+        }
+        let days = (time.timestamp() - self.last_adjust_time) as f64 / SECONDS_PER_DAY;
+        let drift_secs = days * self.drift_factor + self.adjust_residue;
+        Duration::microseconds((drift_secs * 1e6).round() as i64)
+    }
+
+    /// Converts a time from the hardware clock to a UTC DateTime, correcting for drift
+    pub fn to_datetime(&self, hardware_time: &NaiveDateTime) -> DateTime<Utc> {
+        let uncorrected = self.clock_mode.to_datetime(hardware_time);
+        uncorrected - self.drift_at(&uncorrected)
+    }
+
+    /// Converts a UTC DateTime to the time the hardware clock will show at that point, accounting for drift
+    pub fn to_hardware(&self, dt: &DateTime<Utc>) -> NaiveDateTime {
+        self.clock_mode.to_hardware(&(*dt + self.drift_at(dt)))
+    }
+}
+
+/// Hardware clock mode (which timezone the clock uses)
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ClockMode {
+    /// The clock is in UTC
+    Utc,
+    /// The clock is in the local timezone
+    Local,
+}
+
+impl ClockMode {
+    /// Converts a NaiveDateTime from the hardware clock to a UTC DateTime
+    ///
+    /// In `Local` mode, the hardware time might not map to exactly one instant because of a DST transition. When the
+    /// clocks go back, it's interpreted as the earlier of the two instants, since waking up an hour early is better
+    /// than an hour late. When the clocks go forward, the hardware time is in the skipped hour, which happens if the
+    /// hardware clock hasn't been adjusted yet, so it's interpreted using the UTC offset from before the transition.
+    pub fn to_datetime(self, hardware_time: &NaiveDateTime) -> DateTime<Utc> {
+        match self {
+            ClockMode::Utc => Utc.from_utc_datetime(hardware_time),
+            ClockMode::Local => match local_to_utc(hardware_time) {
+                LocalResult::Single(dt) => dt,
+                LocalResult::Ambiguous(earliest, _) => earliest,
+                LocalResult::None => {
+                    Utc.from_utc_datetime(&(*hardware_time - utc_offset_before(hardware_time)))
+                }
+            },
+        }
+    }
+
+    /// Converts a UTC DateTime to a NaiveDateTime in the hardware clock timezone
+    pub fn to_hardware(self, dt: &DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            ClockMode::Utc => dt.naive_utc(),
+            ClockMode::Local => dt.with_timezone(&Local).naive_local(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::testing::{with_timezone, CENTRAL_EUROPEAN_TIME};
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    fn local(time: &str) -> NaiveDateTime {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_all_lines() {
+        let adjtime = Adjtime::parse("-1.5 1583020800 0.25\n1582934400\nLOCAL\n").unwrap();
+        assert_eq!(
+            adjtime,
+            Adjtime {
+                drift_factor: -1.5,
+                last_adjust_time: 1583020800,
+                adjust_residue: 0.25,
+                last_calibration_time: 1582934400,
+                clock_mode: ClockMode::Local,
+            }
+        );
+    }
+
+    #[test]
+    fn defaults_missing_lines() {
+        let adjtime = Adjtime::parse("0.0 0 0.0\n").unwrap();
+        assert_eq!(adjtime, Adjtime::default());
+
+        let adjtime = Adjtime::parse("0.0 0 0.0\n1582934400\n").unwrap();
+        assert_eq!(adjtime.last_calibration_time, 1582934400);
+        assert_eq!(adjtime.clock_mode, ClockMode::Utc);
+
+        assert!(Adjtime::parse("").is_err());
+        assert!(Adjtime::parse("0.0 0\n0\nUTC\n").is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_mode() {
+        assert!(Adjtime::parse("0.0 0 0.0\n0\nlocal\n").is_err());
+        assert!(Adjtime::parse("0.0 0 0.0\n0\nGMT\n").is_err());
+    }
+
+    #[test]
+    fn converts_local_time_across_dst() {
+        // In 2020, DST started at 2am on 29 March and ended at 3am on 25 October
+        with_timezone(CENTRAL_EUROPEAN_TIME, || {
+            let mode = ClockMode::Local;
+            assert_eq!(
+                mode.to_datetime(&local("2020-01-15T04:00:00")),
+                utc("2020-01-15T03:00:00Z")
+            );
+            assert_eq!(
+                mode.to_hardware(&utc("2020-01-15T03:00:00Z")),
+                local("2020-01-15T04:00:00")
+            );
+            // 2:30 happened twice when the clocks went back, and the earlier one is used
+            assert_eq!(
+                mode.to_datetime(&local("2020-10-25T02:30:00")),
+                utc("2020-10-25T00:30:00Z")
+            );
+            // 2:30 didn't happen when the clocks went forward, so the offset from before then is used
+            assert_eq!(
+                mode.to_datetime(&local("2020-03-29T02:30:00")),
+                utc("2020-03-29T01:30:00Z")
+            );
+            assert_eq!(
+                mode.to_hardware(&utc("2020-03-29T01:30:00Z")),
+                local("2020-03-29T03:30:00")
+            );
+        });
+    }
+}
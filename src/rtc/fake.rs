@@ -0,0 +1,51 @@
+//! In-memory RTC for testing
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+
+use super::{Rtc, RtcWakeAlarm};
+
+/// An RTC that only stores its wake alarm in memory
+#[derive(Debug, Default)]
+pub struct FakeRtc {
+    alarm: Mutex<RtcWakeAlarm>,
+}
+
+impl FakeRtc {
+    /// Creates a fake RTC with its wake alarm disabled
+    pub fn new() -> FakeRtc {
+        FakeRtc::default()
+    }
+
+    /// Creates a fake RTC with the given wake alarm configuration
+    pub fn with_alarm(alarm: RtcWakeAlarm) -> FakeRtc {
+        FakeRtc {
+            alarm: Mutex::new(alarm),
+        }
+    }
+
+    /// Gets the current wake alarm configuration
+    pub fn alarm(&self) -> RtcWakeAlarm {
+        *self
+            .alarm
+            .lock()
+            .expect("Mutex containing fake RTC alarm was poisoned")
+    }
+}
+
+impl Rtc for FakeRtc {
+    fn alarm_configuration(&self) -> Result<RtcWakeAlarm> {
+        self.alarm
+            .lock()
+            .map(|alarm| *alarm)
+            .map_err(|_| anyhow!("Mutex containing fake RTC alarm was poisoned"))
+    }
+
+    fn set_alarm_configuration(&self, alarm: &RtcWakeAlarm) -> Result<()> {
+        *self
+            .alarm
+            .lock()
+            .map_err(|_| anyhow!("Mutex containing fake RTC alarm was poisoned"))? = *alarm;
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! RTC access through the ioctls on `/dev/rtc0`
+use std::fs::File;
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+
+use anyhow::{Context, Result};
+
+use super::{Rtc, RtcWakeAlarm};
+
+// These constants are based on <linux/rtc.h>
+const RTC_IOCTL_IDENTIFIER: u8 = b'p';
+const RTC_WKALRM_SET: u8 = 0x0f;
+const RTC_WKALRM_RD: u8 = 0x10;
+
+ioctl_read! {
+    /// Read the RTC's wake alarm time. Note that not all RTCs support this interface, some use the less-powerful
+    /// `RTC_ALM_READ` ioctl instead.
+    ///
+    /// See [`man:rtc(4)`](http://man7.org/linux/man-pages/man4/rtc.4.html) for more information.
+    rtc_read_wake_alarm, RTC_IOCTL_IDENTIFIER, RTC_WKALRM_RD, RtcWakeAlarm
+}
+
+ioctl_write_ptr! {
+    /// Configure the RTC's wake alarm. Note that not all RTCs support this interface, some use `RTC_ALM_SET`
+    /// and `RTC_AIE_ON/OFF` instead.
+    /// See [`man:rtc(4)`](http://man7.org/linux/man-pages/man4/rtc.4.html) for more information.
+    rtc_set_wake_alarm, RTC_IOCTL_IDENTIFIER, RTC_WKALRM_SET, RtcWakeAlarm
+}
+
+/// Linux RTC driver, using the RTC device file's ioctls
+///
+/// See [`man:rtc(4)`](http://man7.org/linux/man-pages/man4/rtc.4.html) for details
+pub struct IoctlRtc {
+    device_file: File,
+}
+
+impl IoctlRtc {
+    /// Opens the RTC device file `/dev/rtc0`
+    pub fn new() -> Result<IoctlRtc> {
+        let file = File::open("/dev/rtc0").context("Could not open RTC device file /dev/rtc0")?;
+        Ok(IoctlRtc { device_file: file })
+    }
+}
+
+impl Rtc for IoctlRtc {
+    fn alarm_configuration(&self) -> Result<RtcWakeAlarm> {
+        let mut alarm = MaybeUninit::<RtcWakeAlarm>::uninit();
+        unsafe {
+            rtc_read_wake_alarm(self.device_file.as_raw_fd(), alarm.as_mut_ptr())
+                .context("RTC_WKALRM_RD ioctl failed")?;
+            Ok(alarm.assume_init())
+        }
+    }
+
+    fn set_alarm_configuration(&self, alarm: &RtcWakeAlarm) -> Result<()> {
+        unsafe {
+            rtc_set_wake_alarm(self.device_file.as_raw_fd(), alarm as *const RtcWakeAlarm)
+                .context("RTC_WKALRM_SET ioctl failed")?;
+        }
+        Ok(())
+    }
+}
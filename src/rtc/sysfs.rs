@@ -0,0 +1,136 @@
+//! RTC access through the `wakealarm` sysfs attribute
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+
+use super::{Rtc, RtcWakeAlarm};
+
+/// RTC driver using `/sys/class/rtc/rtc0/wakealarm`. This works with RTCs that don't support the `RTC_WKALRM_RD` and
+/// `RTC_WKALRM_SET` ioctls, since the kernel falls back to other interfaces.
+///
+/// The attribute contains the alarm time as seconds since the UNIX epoch, but the kernel computes that directly from the
+/// hardware clock time. If the hardware clock is in local time, so is the "epoch".
+///
+/// See [the kernel documentation](https://www.kernel.org/doc/html/latest/admin-guide/rtc.html#sysfs-interface)
+pub struct SysfsRtc {
+    wakealarm: PathBuf,
+}
+
+impl SysfsRtc {
+    /// Creates a driver for `rtc0`
+    pub fn new() -> SysfsRtc {
+        SysfsRtc::with_path("/sys/class/rtc/rtc0/wakealarm")
+    }
+
+    /// Creates a driver using the given `wakealarm` attribute file
+    pub fn with_path<P: Into<PathBuf>>(wakealarm: P) -> SysfsRtc {
+        SysfsRtc {
+            wakealarm: wakealarm.into(),
+        }
+    }
+}
+
+impl Default for SysfsRtc {
+    fn default() -> SysfsRtc {
+        SysfsRtc::new()
+    }
+}
+
+impl Rtc for SysfsRtc {
+    fn alarm_configuration(&self) -> Result<RtcWakeAlarm> {
+        let contents = fs::read_to_string(&self.wakealarm)
+            .with_context(|| format!("Could not read {}", self.wakealarm.display()))?;
+        // The attribute is empty if the alarm is disabled
+        match contents.trim() {
+            "" => Ok(RtcWakeAlarm::default()),
+            timestamp => {
+                let timestamp = timestamp
+                    .parse()
+                    .with_context(|| format!("Invalid wake alarm time: {}", timestamp))?;
+                Ok(RtcWakeAlarm::new(
+                    true,
+                    &NaiveDateTime::from_timestamp(timestamp, 0),
+                ))
+            }
+        }
+    }
+
+    fn set_alarm_configuration(&self, alarm: &RtcWakeAlarm) -> Result<()> {
+        // The kernel refuses to change an enabled alarm, so it has to be cleared first
+        fs::write(&self.wakealarm, "0")
+            .with_context(|| format!("Could not clear {}", self.wakealarm.display()))?;
+        if alarm.enabled() {
+            fs::write(&self.wakealarm, alarm.time().timestamp().to_string())
+                .with_context(|| format!("Could not write {}", self.wakealarm.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use chrono::{TimeZone, Utc};
+    use nix::sys::stat::Mode;
+    use nix::unistd::mkfifo;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn reads_and_writes_wakealarm() {
+        let dir = TempDir::new().unwrap();
+        let wakealarm = dir.path().join("wakealarm");
+        let rtc = SysfsRtc::with_path(&wakealarm);
+
+        fs::write(&wakealarm, "\n").unwrap();
+        assert!(!rtc.alarm_configuration().unwrap().enabled());
+
+        let time = Utc.ymd(2020, 3, 2).and_hms(1, 55, 0).naive_utc();
+        fs::write(&wakealarm, format!("{}\n", time.timestamp())).unwrap();
+        let alarm = rtc.alarm_configuration().unwrap();
+        assert!(alarm.enabled());
+        assert_eq!(alarm.time(), time);
+
+        let later = time + chrono::Duration::days(1);
+        rtc.set_alarm_configuration(&RtcWakeAlarm::new(true, &later))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&wakealarm).unwrap(),
+            later.timestamp().to_string()
+        );
+
+        rtc.set_alarm_configuration(&RtcWakeAlarm::new(false, &later))
+            .unwrap();
+        assert_eq!(fs::read_to_string(&wakealarm).unwrap(), "0");
+    }
+
+    #[test]
+    fn clears_alarm_before_setting() {
+        let dir = TempDir::new().unwrap();
+        let wakealarm = dir.path().join("wakealarm");
+        // Each write opens and closes the attribute, so a FIFO shows them one at a time, like the kernel sees them
+        mkfifo(&wakealarm, Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+        let writes = {
+            let wakealarm = wakealarm.clone();
+            thread::spawn(move || {
+                (0..2)
+                    .map(|_| fs::read_to_string(&wakealarm).unwrap())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let time = Utc.ymd(2020, 3, 2).and_hms(1, 55, 0).naive_utc();
+        SysfsRtc::with_path(&wakealarm)
+            .set_alarm_configuration(&RtcWakeAlarm::new(true, &time))
+            .unwrap();
+
+        assert_eq!(
+            writes.join().unwrap(),
+            vec!["0".to_string(), time.timestamp().to_string()]
+        );
+    }
+}
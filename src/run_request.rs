@@ -0,0 +1,104 @@
+//! Requests for the runner to run a target by hand.
+//!
+//! `night-kitchen run-now` leaves a request in the [state directory](../fn.state_directory.html) and then starts the
+//! target's service, so that the run goes through the runner in the same environment as a timed one. The runner takes
+//! the request and, instead of working out whether it brought the system up, does what the request asks with the system
+//! afterwards.
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::history::PowerAction;
+
+const REQUEST_FILE: &str = "run-request.json";
+
+/// How long a request is good for, in seconds. If the service didn't start within this long, whoever made the request
+/// has most likely given up on it.
+pub const MAX_REQUEST_AGE_SECS: i64 = 5 * 60;
+
+/// A request to run a target by hand
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RunRequest {
+    /// The target to run, such as `night-kitchen-daily.target`
+    pub target: String,
+    /// What to do with the system after the run
+    pub power_action: PowerAction,
+    pub requested: DateTime<Utc>,
+}
+
+impl RunRequest {
+    /// Creates a request to run `target` now and then take `power_action`
+    pub fn new<S: Into<String>>(target: S, power_action: PowerAction) -> RunRequest {
+        RunRequest {
+            target: target.into(),
+            power_action,
+            requested: Utc::now(),
+        }
+    }
+
+    /// Leaves the request in `dir` for the runner, replacing any earlier one
+    pub fn submit(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(REQUEST_FILE);
+        let contents = serde_json::to_vec(self).context("Could not serialize run request")?;
+        fs::write(&path, contents).with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    /// Takes the request to run `target` out of `dir`, if there is one that's still current at `now`. A request for a
+    /// different target is left for its own runner.
+    pub fn take(dir: &Path, target: &str, now: DateTime<Utc>) -> Result<Option<RunRequest>> {
+        let path = dir.join(REQUEST_FILE);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+        let request: RunRequest = serde_json::from_slice(&contents)
+            .with_context(|| format!("Could not parse {}", path.display()))?;
+        if request.target != target {
+            return Ok(None);
+        }
+
+        fs::remove_file(&path).with_context(|| format!("Could not remove {}", path.display()))?;
+        if now - request.requested > Duration::seconds(MAX_REQUEST_AGE_SECS) {
+            return Ok(None);
+        }
+        Ok(Some(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const DAILY: &str = "night-kitchen-daily.target";
+
+    #[test]
+    fn takes_current_request_for_target() {
+        let dir = TempDir::new().unwrap();
+        let now = Utc::now();
+        assert_eq!(RunRequest::take(dir.path(), DAILY, now).unwrap(), None);
+
+        let request = RunRequest::new(DAILY, PowerAction::Suspend);
+        request.submit(dir.path()).unwrap();
+        assert_eq!(
+            RunRequest::take(dir.path(), "night-kitchen-weekly.target", now).unwrap(),
+            None
+        );
+        assert_eq!(
+            RunRequest::take(dir.path(), DAILY, now).unwrap(),
+            Some(request.clone())
+        );
+        assert_eq!(RunRequest::take(dir.path(), DAILY, now).unwrap(), None);
+
+        request.submit(dir.path()).unwrap();
+        let later = now + Duration::seconds(MAX_REQUEST_AGE_SECS + 1);
+        assert_eq!(RunRequest::take(dir.path(), DAILY, later).unwrap(), None);
+        assert!(!dir.path().join(REQUEST_FILE).exists());
+    }
+}
@@ -0,0 +1,329 @@
+//! What the runner does once it's started: works out why, runs the target unless its activation was skipped, records and
+//! reports the run, and then returns the system to the state it was in before Night Kitchen booted or woke it.
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use dbus::blocking::Connection;
+use slog::{debug, error, info, Logger};
+
+use crate::activation::calendar_elapse_after;
+use crate::boot::caused_boot;
+use crate::config::{metrics_directory, HookConfig, NetworkConfig, NetworkFailurePolicy};
+use crate::dbus::scheduler::{IoGithubNightKitchenScheduler, WakeCause};
+use crate::dbus::{login_manager, scheduler_control, start_unit, LoginManager};
+use crate::history::{History, PowerAction, RunCause, RunRecord};
+use crate::hooks;
+use crate::metrics::{self, PowerActionCounts, RUNNER_METRICS_FILE};
+use crate::network;
+use crate::notifier;
+use crate::platform::Platform;
+use crate::power_monitor::Deadline;
+use crate::run_request::RunRequest;
+use crate::skips::Skips;
+use crate::time::from_timestamp_usecs;
+
+/// This is the shortest time since the system resumed for which night-kitchen will not hold itself responsible for
+/// waking the system up.
+const MIN_INNOCENT_WAKETIME: Duration = Duration::from_secs(60);
+
+/// The runner can start before the scheduler has handled the system resuming, so it keeps asking the scheduler about
+/// the last resume for this long.
+const RESUME_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const RESUME_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Runs `target`, which was started at `start_time` by `timer` or, if there's no timer, by hand. Afterwards the system
+/// is powered off or suspended again if Night Kitchen booted or woke it, or if the request to run the target by hand
+/// asked for that.
+///
+/// Returns the record of the run. Skipped activations of `timer` are recorded and reported as skipped runs. If the
+/// target couldn't be started, the run is still recorded and reported, but the system is left up so that someone can
+/// look into it.
+pub fn run(
+    logger: &Logger,
+    conn: &mut Connection,
+    platform: &dyn Platform,
+    state_dir: &Path,
+    target: &str,
+    timer: Option<&str>,
+    start_time: DateTime<Utc>,
+) -> Result<RunRecord> {
+    let request = match timer {
+        Some(_) => None,
+        None => run_request(logger, state_dir, target, start_time),
+    };
+
+    let cause = if request.is_some() {
+        RunCause::Manual
+    } else if caused_boot(logger, platform, state_dir) {
+        RunCause::Boot
+    } else if caused_wake(logger, conn, start_time) {
+        RunCause::Wake
+    } else {
+        RunCause::None
+    };
+    let planned_action = match &request {
+        Some(request) => request.power_action,
+        None => cause.power_action(),
+    };
+
+    let mut skipped =
+        timer.and_then(|timer| skip_reason(logger, conn, state_dir, timer, start_time));
+    let run = match &skipped {
+        Some(reason) => {
+            info!(&logger, "Not running skipped activation"; "unit" => target, "reason" => reason);
+            Ok(Vec::new())
+        }
+        None => match wait_for_network(logger, conn) {
+            Ok(None) => {
+                info!(&logger, "Running systemd unit {unit}", unit = target; "cause" => ?cause);
+                start_unit(logger, conn, target)
+            }
+            Ok(Some(reason)) => {
+                info!(&logger, "Not running {} without the network", target; "reason" => &reason);
+                skipped = Some(reason);
+                Ok(Vec::new())
+            }
+            Err(err) => Err(err),
+        },
+    };
+
+    // If the tasks couldn't even be started, leave the system up so that someone can look into it
+    let power_action = match &run {
+        Ok(_) => planned_action,
+        Err(_) => PowerAction::None,
+    };
+    let (units, error) = match &run {
+        Ok(units) => (units.clone(), None),
+        Err(err) => (Vec::new(), Some(format!("{:#}", err))),
+    };
+    let record = RunRecord {
+        target: target.to_string(),
+        timer: timer.map(str::to_string),
+        cause,
+        started: start_time,
+        finished: platform.now(),
+        units,
+        error,
+        skipped,
+        power_action,
+    };
+    record_run(logger, state_dir, &record);
+    export_metrics(logger, state_dir, power_action);
+    notify(logger, conn, state_dir, &record);
+    run?;
+
+    restore_power_state(logger, conn, power_action)?;
+    Ok(record)
+}
+
+/// Waits for the network, if the target's service is configured to. Returns why the target should be skipped if the
+/// network didn't come up and the target should be skipped, or an error if it should fail.
+fn wait_for_network(logger: &Logger, conn: &mut Connection) -> Result<Option<String>> {
+    let config = NetworkConfig::from_env().context("Invalid network configuration")?;
+    match network::wait(logger, conn, &config) {
+        Ok(()) => Ok(None),
+        Err(err) => match config.on_failure {
+            NetworkFailurePolicy::Skip => Ok(Some(format!("{:#}", err))),
+            NetworkFailurePolicy::Fail => Err(err),
+        },
+    }
+}
+
+/// Takes the request to run `target` by hand left by `night-kitchen run-now`, if there is one
+fn run_request(
+    logger: &Logger,
+    state_dir: &Path,
+    target: &str,
+    now: DateTime<Utc>,
+) -> Option<RunRequest> {
+    match RunRequest::take(state_dir, target, now) {
+        Ok(Some(request)) => {
+            info!(&logger, "Running by request"; "requested" => %request.requested, "power_action" => %request.power_action);
+            Some(request)
+        }
+        Ok(None) => None,
+        Err(err) => {
+            error!(&logger, "Could not read run request: {:?}", err);
+            None
+        }
+    }
+}
+
+fn restore_power_state(
+    logger: &Logger,
+    conn: &Connection,
+    power_action: PowerAction,
+) -> Result<()> {
+    match power_action {
+        PowerAction::PowerOff => {
+            info!(&logger, "Shutting system down...");
+            shutdown(&login_manager(conn))?;
+        }
+        PowerAction::Suspend => {
+            info!(&logger, "Suspending system...");
+            suspend(&login_manager(conn))?;
+        }
+        PowerAction::None => info!(&logger, "Not responsible for booting/waking"),
+    }
+
+    Ok(())
+}
+
+/// Powers off the system
+fn shutdown<M: LoginManager>(manager: &M) -> Result<()> {
+    // Important: Both the systemd and logind D-Bus APIs have PowerOff methods. The logind method goes through a graceful shutdown, respecting inhibitor locks
+    // and stopping services, while the systemd one immediately shuts the system down. Calling the systemd one directly by mistake would be unfortunate.
+    // The boolean argument is whether PolicyKit should prompt the user for authentication if needed. Since night-kitchen-runner is activated by a timer,
+    // we want to fail-fast if we don't have sufficient privileges instead.
+    manager
+        .power_off(false)
+        .context("Could not power off the system")?;
+    Ok(())
+}
+
+/// Puts the system to sleep
+fn suspend<M: LoginManager>(manager: &M) -> Result<()> {
+    // Boolean is the same PolicyKit flag as in shutdown()
+    manager
+        .suspend(false)
+        .context("Could not suspend the system")?;
+    Ok(())
+}
+
+/// Returns why the activation of `timer` that started the runner shouldn't run, if Night Kitchen is paused or the
+/// activation was skipped. Skipped activations that have elapsed are used up either way.
+fn skip_reason(
+    logger: &Logger,
+    conn: &Connection,
+    state_dir: &Path,
+    timer: &str,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let result = Skips::update(state_dir, |skips| {
+        if let Some(until) = skips.paused_until.filter(|until| now < *until) {
+            return Ok(Some(format!("Night Kitchen is paused until {}", until)));
+        }
+        let latest = match skips.take_elapsed(timer, &now).pop() {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+
+        // An earlier skipped activation may have been missed entirely while the system was off, so this run is only
+        // for a skipped one if the timer hasn't elapsed since the latest
+        match calendar_elapse_after(logger, conn, timer, &latest, &Deadline::none())? {
+            Some(next) if next <= now => {
+                debug!(&logger, "Skipped activation already passed"; "elapse" => %latest, "next_elapse" => %next);
+                Ok(None)
+            }
+            _ => Ok(Some(format!("The activation at {} was skipped", latest))),
+        }
+    });
+    result.unwrap_or_else(|err| {
+        error!(
+            &logger,
+            "Could not check for skipped activations: {:?}", err
+        );
+        None
+    })
+}
+
+/// Adds a run to the run history. Failing to do so shouldn't stop the runner from returning the system to the state
+/// it was in, so errors are only logged.
+fn record_run(logger: &Logger, state_dir: &Path, record: &RunRecord) {
+    debug!(&logger, "Recording run"; "target" => &record.target, "succeeded" => record.succeeded());
+    if let Err(err) = History::open(state_dir).append(record) {
+        error!(&logger, "Could not record run: {:?}", err);
+    }
+}
+
+/// Runs the notification hooks and sends reports for how the run went, if any are configured. Like recording the run,
+/// none of this can stop the runner from returning the system to the state it was in, so failures are only logged.
+fn notify(logger: &Logger, conn: &mut Connection, state_dir: &Path, record: &RunRecord) {
+    match HookConfig::from_env() {
+        Ok(config) => {
+            hooks::notify(logger, conn, &config, record);
+            notifier::notify(logger, state_dir, &config, record);
+        }
+        Err(err) => error!(&logger, "Invalid hook configuration: {:?}", err),
+    }
+}
+
+/// Writes the runner's metrics for node_exporter, if a metrics directory is configured. The power action is counted
+/// before it's taken, since the system may go down before the runner gets another chance.
+fn export_metrics(logger: &Logger, state_dir: &Path, power_action: PowerAction) {
+    let result = metrics_directory().and_then(|dir| match dir {
+        Some(dir) => {
+            let counts = PowerActionCounts::record(state_dir, power_action)?;
+            let runs = History::open(state_dir).runs()?;
+            metrics::write_textfile(
+                &dir,
+                RUNNER_METRICS_FILE,
+                &metrics::runner_metrics(&runs, counts),
+            )
+        }
+        None => Ok(()),
+    });
+    if let Err(err) = result {
+        error!(&logger, "Could not export metrics: {:?}", err);
+    }
+}
+
+/// Returns `true` if night kitchen most likely woke the system from suspend, according to the scheduler. The system
+/// must have resumed shortly before the runner started, once a Night Kitchen timer was due.
+fn caused_wake(logger: &Logger, conn: &Connection, start_time: DateTime<Utc>) -> bool {
+    let scheduler = scheduler_control(conn);
+    let give_up = Instant::now() + RESUME_GRACE_PERIOD;
+    loop {
+        let last_resume = scheduler
+            .last_resume()
+            .and_then(|resume| scheduler.last_wake_cause().map(|cause| (resume, cause)));
+        let (resume_usecs, cause) = match last_resume {
+            Ok(last_resume) => last_resume,
+            Err(err) => {
+                error!(&logger, "Could not ask scheduler about last resume"; "error" => ?err);
+                return false;
+            }
+        };
+
+        if resume_usecs > 0 {
+            let resume_time = from_timestamp_usecs(resume_usecs);
+            debug!(&logger, "Resumed from suspend at {}", resume_time; "cause" => &cause);
+            let recent = match (start_time - resume_time).to_std() {
+                Ok(delta) => delta < MIN_INNOCENT_WAKETIME,
+                // The scheduler noticed the resume after the runner started
+                Err(_) => true,
+            };
+            if recent {
+                return matches!(cause.parse(), Ok(WakeCause::Timer));
+            }
+        }
+
+        if Instant::now() >= give_up {
+            return false;
+        }
+        thread::sleep(RESUME_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dbus::testing::{FakeLogind, TestBus};
+
+    use super::*;
+
+    #[test]
+    fn shutdown_and_suspend_use_logind() {
+        let bus = TestBus::start().unwrap();
+        let logind = FakeLogind::start(&bus).unwrap();
+        let conn = bus.connect().unwrap();
+
+        shutdown(&login_manager(&conn)).unwrap();
+        suspend(&login_manager(&conn)).unwrap();
+
+        assert_eq!(logind.power_off_calls(), 1);
+        assert_eq!(logind.suspend_calls(), 1);
+    }
+}
@@ -0,0 +1,158 @@
+//! Timer activations Night Kitchen has been told to skip, and pauses of all of its wakeups.
+//!
+//! These are kept in `skips.json` in the [state directory](../fn.state_directory.html), so that they survive the
+//! shutdowns they're meant to stretch out. The scheduler doesn't wake the system for a skipped activation, or for any
+//! before a pause ends. If the system is up for one anyway, such as when another timer woke it, the runner skips the
+//! tasks and returns the system to sleep straight away.
+//!
+//! Skipped activations are stored as the times they elapse, rather than as a count, so that the scheduler and runner
+//! agree on exactly which ones are skipped however many times the system sleeps and wakes in between.
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{load_json, update_json};
+
+const SKIPS_FILE: &str = "skips.json";
+
+/// The skipped activations and pause in effect
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Skips {
+    /// Night Kitchen won't wake the system or run timed tasks before this time
+    pub paused_until: Option<DateTime<Utc>>,
+    /// When each skipped activation elapses, earliest first, by timer unit
+    #[serde(default)]
+    pub activations: BTreeMap<String, Vec<DateTime<Utc>>>,
+}
+
+impl Skips {
+    /// Reads the skips kept in `dir`. There are none if nothing was ever skipped.
+    pub fn load(dir: &Path) -> Result<Skips> {
+        load_json(&dir.join(SKIPS_FILE))
+    }
+
+    /// Changes the skips kept in `dir` with `change`, which runs while they're locked. They're only saved if `change`
+    /// succeeds.
+    pub fn update<T, F: FnOnce(&mut Skips) -> Result<T>>(dir: &Path, change: F) -> Result<T> {
+        update_json(&dir.join(SKIPS_FILE), change)
+    }
+
+    /// Whether Night Kitchen is paused at `time`
+    pub fn is_paused(&self, time: &DateTime<Utc>) -> bool {
+        matches!(self.paused_until, Some(until) if *time < until)
+    }
+
+    /// Whether the activation of `timer` that elapses at `elapse` is skipped, either on its own or by a pause
+    pub fn skips(&self, timer: &str, elapse: &DateTime<Utc>) -> bool {
+        self.is_paused(elapse) || self.is_skipped(timer, elapse)
+    }
+
+    /// Whether the activation of `timer` that elapses at `elapse` was skipped on its own
+    pub fn is_skipped(&self, timer: &str, elapse: &DateTime<Utc>) -> bool {
+        self.activations
+            .get(timer)
+            .map(|elapses| elapses.contains(elapse))
+            .unwrap_or(false)
+    }
+
+    /// Skips the activation of `timer` that elapses at `elapse`
+    pub fn skip(&mut self, timer: &str, elapse: DateTime<Utc>) {
+        let elapses = self.activations.entry(timer.to_string()).or_default();
+        if let Err(index) = elapses.binary_search(&elapse) {
+            elapses.insert(index, elapse);
+        }
+    }
+
+    /// Removes the skipped activations of `timer` that elapsed at or before `now`, returning them earliest first
+    pub fn take_elapsed(&mut self, timer: &str, now: &DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let elapses = match self.activations.get_mut(timer) {
+            Some(elapses) => elapses,
+            None => return Vec::new(),
+        };
+        let elapsed_count = elapses
+            .iter()
+            .position(|elapse| elapse > now)
+            .unwrap_or(elapses.len());
+        let elapsed = elapses.drain(..elapsed_count).collect();
+        if elapses.is_empty() {
+            self.activations.remove(timer);
+        }
+        elapsed
+    }
+
+    /// Stops skipping activations of `timer`, or of every timer if it's `None`
+    pub fn clear(&mut self, timer: Option<&str>) {
+        match timer {
+            Some(timer) => {
+                self.activations.remove(timer);
+            }
+            None => self.activations.clear(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const DAILY: &str = "night-kitchen-daily.timer";
+
+    #[test]
+    fn persists_skips() {
+        let dir = TempDir::new().unwrap();
+        assert_eq!(Skips::load(dir.path()).unwrap(), Skips::default());
+
+        let elapse = Utc.ymd(2020, 3, 2).and_hms(2, 0, 0);
+        Skips::update(dir.path(), |skips| {
+            skips.skip(DAILY, elapse + Duration::days(1));
+            skips.skip(DAILY, elapse);
+            skips.skip(DAILY, elapse);
+            skips.paused_until = Some(elapse + Duration::days(7));
+            Ok(())
+        })
+        .unwrap();
+        let failed: Result<()> = Skips::update(dir.path(), |skips| {
+            skips.clear(None);
+            Err(anyhow::anyhow!("Changed my mind"))
+        });
+        assert!(failed.is_err());
+
+        let skips = Skips::load(dir.path()).unwrap();
+        assert_eq!(
+            skips.activations[DAILY],
+            vec![elapse, elapse + Duration::days(1)]
+        );
+        assert!(skips.is_skipped(DAILY, &elapse));
+        assert!(!skips.is_skipped("night-kitchen-weekly.timer", &elapse));
+        assert!(skips.skips("night-kitchen-weekly.timer", &elapse));
+        assert!(!skips.skips(DAILY, &(elapse + Duration::days(8))));
+    }
+
+    #[test]
+    fn takes_elapsed_activations() {
+        let elapse = Utc.ymd(2020, 3, 2).and_hms(2, 0, 0);
+        let mut skips = Skips::default();
+        for days in 0..3 {
+            skips.skip(DAILY, elapse + Duration::days(days));
+        }
+
+        assert!(skips
+            .take_elapsed(DAILY, &(elapse - Duration::hours(1)))
+            .is_empty());
+        assert_eq!(
+            skips.take_elapsed(DAILY, &(elapse + Duration::days(1))),
+            vec![elapse, elapse + Duration::days(1)]
+        );
+        assert_eq!(
+            skips.take_elapsed(DAILY, &(elapse + Duration::days(5))),
+            vec![elapse + Duration::days(2)]
+        );
+        assert!(skips.activations.is_empty());
+    }
+}
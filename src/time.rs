@@ -0,0 +1,166 @@
+//! Helpers for dealing with time, especially in relation to systemd
+//!
+//! systemd reports timestamps as microseconds on one of several clocks. `CLOCK_REALTIME` timestamps can be converted
+//! with [`from_timestamp_usecs`](fn.from_timestamp_usecs.html) and back with [`to_timestamp_usecs`](fn.to_timestamp_usecs.html),
+//! while [`monotonic_to_realtime`](fn.monotonic_to_realtime.html) and [`boottime_to_realtime`](fn.boottime_to_realtime.html) first convert
+//! timestamps on the clocks that start counting at boot. [`local_to_utc`](fn.local_to_utc.html) converts local times
+//! around DST transitions.
+use std::convert::TryInto;
+
+use chrono::{DateTime, Duration, Local, LocalResult, NaiveDateTime, TimeZone, Utc};
+use libc;
+
+/// How far either side of a local time to look for a DST transition. This only needs to be longer than the gap or
+/// overlap a transition creates, which is normally an hour.
+const DST_TRANSITION_HOURS: i64 = 3;
+
+/// Converts a timestamp represented as microseconds since the UTC UNIX epoch to a `DateTime`.
+pub fn from_timestamp_usecs(usecs: u64) -> DateTime<Utc> {
+    Utc.timestamp_nanos((usecs * 1000) as i64)
+}
+
+/// Converts a `DateTime` to microseconds since the UTC UNIX epoch, the inverse of
+/// [`from_timestamp_usecs`](fn.from_timestamp_usecs.html). Times before the epoch become 0.
+pub fn to_timestamp_usecs(time: &DateTime<Utc>) -> u64 {
+    (time.timestamp_nanos() / 1000).try_into().unwrap_or(0)
+}
+
+// Use the same approach as systemd for converting between CLOCK_MONOTONIC and CLOCK_REALTIME timestamps.
+// The basic idea is to get the current time with both clocks, and then use the difference as an offset for conversion
+// See dual_clock_get in https://github.com/systemd/systemd/blob/master/src/basic/time-util.c#L66 and
+// calc_next_elapse in https://github.com/systemd/systemd/blob/master/src/systemctl/systemctl.c#L1295
+
+/// Converts a `CLOCK_MONOTONIC` timestamp to `CLOCK_REALTIME`. `CLOCK_MONOTONIC` does not advance while the system is
+/// suspended, so the result is only valid until the next suspend.
+///
+/// # Panics
+///
+/// Panics if the current time can't be read from either clock, which the kernel only reports for invalid clocks.
+pub fn monotonic_to_realtime(monotonic: DateTime<Utc>) -> DateTime<Utc> {
+    clock_to_realtime(libc::CLOCK_MONOTONIC, monotonic)
+}
+
+/// Converts a `CLOCK_BOOTTIME` timestamp to `CLOCK_REALTIME`. Unlike `CLOCK_MONOTONIC`, `CLOCK_BOOTTIME` keeps advancing
+/// while the system is suspended, so the result stays valid across suspends (but not reboots).
+///
+/// # Panics
+///
+/// Panics under the same conditions as [`monotonic_to_realtime`](fn.monotonic_to_realtime.html).
+pub fn boottime_to_realtime(boottime: DateTime<Utc>) -> DateTime<Utc> {
+    clock_to_realtime(libc::CLOCK_BOOTTIME, boottime)
+}
+
+fn clock_to_realtime(clock: libc::clockid_t, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+    // Could be off by a tiny amount because the two calls don't happen at the same time, but it's probably not enough to notice.
+    // These don't need to be recalculated every time but also can't be stored forever because of clock skew / NTP, so it's easier not to cache them
+    let clock_now = clock_gettime(clock);
+    let realtime_now = clock_gettime(libc::CLOCK_REALTIME);
+
+    let clock_now = Utc.timestamp(clock_now.tv_sec, clock_now.tv_nsec.try_into().unwrap());
+    let realtime_now = Utc.timestamp(
+        realtime_now.tv_sec,
+        realtime_now.tv_nsec.try_into().unwrap(),
+    );
+    timestamp + (realtime_now - clock_now)
+}
+
+extern "C" {
+    // Not exposed by the libc crate
+    fn tzset();
+}
+
+/// Converts a local time to UTC, reporting times that happen twice or not at all because of a DST transition like
+/// `Local.from_local_datetime` does.
+///
+/// chrono converts local times with `mktime()`, which picks one of two ambiguous times depending on earlier calls and
+/// moves skipped times past the transition, so this only converts the other way and checks which candidates map back.
+pub fn local_to_utc(local: &NaiveDateTime) -> LocalResult<DateTime<Utc>> {
+    let mut instants: Vec<DateTime<Utc>> = [-DST_TRANSITION_HOURS, DST_TRANSITION_HOURS]
+        .iter()
+        .map(|hours| utc_offset_near(&(*local + Duration::hours(*hours))))
+        .map(|offset| Utc.from_utc_datetime(&(*local - offset)))
+        .filter(|instant| instant.with_timezone(&Local).naive_local() == *local)
+        .collect();
+    instants.sort();
+    instants.dedup();
+    match instants[..] {
+        [] => LocalResult::None,
+        [instant] => LocalResult::Single(instant),
+        [earliest, latest, ..] => LocalResult::Ambiguous(earliest, latest),
+    }
+}
+
+/// The local timezone's UTC offset a few hours before `local`, which is the one in effect before any DST transition at
+/// `local`
+pub fn utc_offset_before(local: &NaiveDateTime) -> Duration {
+    utc_offset_near(&(*local - Duration::hours(DST_TRANSITION_HOURS)))
+}
+
+/// The local timezone's UTC offset around `local`, treating it as UTC. That's off by the offset itself, which is fine
+/// away from DST transitions.
+fn utc_offset_near(local: &NaiveDateTime) -> Duration {
+    Duration::seconds(
+        Local
+            .offset_from_utc_datetime(local)
+            .local_minus_utc()
+            .into(),
+    )
+}
+
+/// Re-reads the local timezone. The C library caches it, so this must be called after the timezone changes for local
+/// time conversions to pick up the new one.
+pub fn reload_timezone() {
+    unsafe { tzset() }
+}
+
+fn clock_gettime(clock: libc::clockid_t) -> libc::timespec {
+    // nix doesn't have clock_gettime bindings yet (see https://github.com/nix-rust/nix/pull/1100)
+    let mut timespec = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let status = unsafe { libc::clock_gettime(clock, &mut timespec as *mut _) };
+    if status != 0 {
+        panic!("clock_gettime failed!");
+    }
+    timespec
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::env;
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+
+    use super::reload_timezone;
+
+    /// Central European Time as a POSIX `TZ` rule, so that tests don't rely on tzdata being installed
+    pub const CENTRAL_EUROPEAN_TIME: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+
+    /// Tests that change the local timezone hold this, since it's shared by the whole process
+    static TIMEZONE: Mutex<()> = Mutex::new(());
+
+    /// Puts back the timezone a test started with, even if it panics
+    struct RestoreTimezone(Option<OsString>);
+
+    impl Drop for RestoreTimezone {
+        fn drop(&mut self) {
+            match self.0.take() {
+                Some(tz) => env::set_var("TZ", tz),
+                None => env::remove_var("TZ"),
+            }
+            reload_timezone();
+        }
+    }
+
+    /// Runs `f` with the local timezone set to `tz`, one test at a time
+    pub fn with_timezone<T>(tz: &str, f: impl FnOnce() -> T) -> T {
+        let _lock = TIMEZONE
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _restore = RestoreTimezone(env::var_os("TZ"));
+        env::set_var("TZ", tz);
+        reload_timezone();
+        f()
+    }
+}